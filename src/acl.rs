@@ -0,0 +1,177 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+
+use crate::Scope;
+
+/// Credentials of the process on the other end of a client connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerCred {
+    pub pid: u32,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl PeerCred {
+    #[cfg(target_os = "linux")]
+    pub fn from_stream(stream: &UnixStream) -> Option<PeerCred> {
+        use std::os::fd::AsRawFd;
+        let mut cred = libc::ucred {
+            pid: 0,
+            uid: 0,
+            gid: 0,
+        };
+        let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+        // SAFETY: `cred` and `len` are valid for writes and sized for SO_PEERCRED.
+        let rc = unsafe {
+            libc::getsockopt(
+                stream.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_PEERCRED,
+                &mut cred as *mut libc::ucred as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if rc != 0 {
+            return None;
+        }
+        Some(PeerCred {
+            pid: cred.pid as u32,
+            uid: cred.uid,
+            gid: cred.gid,
+        })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn from_stream(_stream: &UnixStream) -> Option<PeerCred> {
+        None
+    }
+}
+
+/// One access rule from the daemon config. A rule applies to a mutation when
+/// every selector it has (`keys`, `dir`) matches; only `writers` may then
+/// set or unset. Keys outside every rule stay writable by anyone.
+///
+/// ```json
+/// { "acl": [
+///     { "keys": "PROD_*", "writers": [1000] },
+///     { "dir": "/srv/shared", "writers": [1000, 1001] }
+/// ] }
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AclRule {
+    /// Key pattern; `*` matches any run of characters.
+    #[serde(default)]
+    pub keys: Option<String>,
+    /// Directory scope this rule covers, including scopes below it.
+    #[serde(default)]
+    pub dir: Option<PathBuf>,
+    /// Uids allowed to mutate matching keys.
+    #[serde(default)]
+    pub writers: Vec<u32>,
+}
+
+impl AclRule {
+    pub fn validate(&self) -> Result<()> {
+        if self.keys.is_none() && self.dir.is_none() {
+            return Err(anyhow!("rule needs `keys` or `dir`"));
+        }
+        Ok(())
+    }
+
+    fn matches(&self, key: &str, scope: &Scope) -> bool {
+        if let Some(pattern) = &self.keys {
+            if !glob_match(pattern, key) {
+                return false;
+            }
+        }
+        if let Some(dir) = &self.dir {
+            match scope {
+                Scope::Dir(path) => {
+                    if !crate::is_ancestor(dir, path) {
+                        return false;
+                    }
+                }
+                Scope::Global => return false,
+            }
+        }
+        true
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Acl {
+    rules: Vec<AclRule>,
+    provider_writers: Vec<u32>,
+}
+
+impl Acl {
+    pub fn new(rules: Vec<AclRule>, provider_writers: Vec<u32>) -> Self {
+        Acl {
+            rules,
+            provider_writers,
+        }
+    }
+
+    /// Checks whether `peer` may point `key` at a provider. envd runs
+    /// providers as its own uid, so only `provider_writers` may add them,
+    /// and connections without credentials never can.
+    pub fn check_provider(&self, peer: Option<&PeerCred>, key: &str) -> Result<()> {
+        match peer {
+            Some(p) if self.provider_writers.contains(&p.uid) => Ok(()),
+            _ => Err(anyhow!(
+                "permission denied: uid {} may not point {} at a provider",
+                peer.map(|p| p.uid.to_string())
+                    .unwrap_or_else(|| "unknown".to_string()),
+                key
+            )),
+        }
+    }
+
+    /// Checks whether `peer` may set or unset `key` in `scope`. Connections
+    /// without credentials are denied anything a rule covers.
+    pub fn check_write(&self, peer: Option<&PeerCred>, key: &str, scope: &Scope) -> Result<()> {
+        for rule in self.rules.iter().filter(|r| r.matches(key, scope)) {
+            let allowed = peer.map(|p| rule.writers.contains(&p.uid)).unwrap_or(false);
+            if !allowed {
+                return Err(anyhow!(
+                    "permission denied: {} is not writable by uid {}{}",
+                    key,
+                    peer.map(|p| p.uid.to_string())
+                        .unwrap_or_else(|| "unknown".to_string()),
+                    describe_scope(scope)
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn describe_scope(scope: &Scope) -> String {
+    match scope {
+        Scope::Global => String::new(),
+        Scope::Dir(p) => format!(" in {}", p.display()),
+    }
+}
+
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let parts: Vec<&str> = pattern.split('*').collect();
+    if parts.len() == 1 {
+        return pattern == text;
+    }
+    let (first, rest) = parts.split_first().expect("split yields one part");
+    let (last, middle) = rest.split_last().expect("pattern has a star");
+    if !text.starts_with(first) {
+        return false;
+    }
+    let mut remaining = &text[first.len()..];
+    for part in middle {
+        match remaining.find(part) {
+            Some(idx) => remaining = &remaining[idx + part.len()..],
+            None => return false,
+        }
+    }
+    remaining.len() >= last.len() && remaining.ends_with(last)
+}
@@ -0,0 +1,195 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::{self, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{PeerCred, Scope, StoredValue};
+
+const DEFAULT_MAX_BYTES: u64 = 1024 * 1024;
+const DEFAULT_KEEP: usize = 5;
+
+/// `audit` section of the daemon config.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AuditConfig {
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+    /// Rotate once the live file grows past this many bytes.
+    #[serde(default)]
+    pub max_bytes: Option<u64>,
+    /// Number of rotated files (`audit.log.1` ..) to keep.
+    #[serde(default)]
+    pub keep: Option<usize>,
+}
+
+/// One recorded mutation, stored as a JSON line.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    /// Seconds since the Unix epoch.
+    pub timestamp: u64,
+    pub generation: u64,
+    pub op: String,
+    pub key: String,
+    pub scope: Scope,
+    pub pid: Option<u32>,
+    pub uid: Option<u32>,
+    pub exe: Option<PathBuf>,
+    pub parent_exe: Option<PathBuf>,
+    pub cwd: Option<PathBuf>,
+    pub old_hash: Option<String>,
+    pub new_hash: Option<String>,
+}
+
+/// Who made a request, looked up from /proc while the client is still connected.
+#[derive(Debug, Clone, Default)]
+pub struct Caller {
+    pub pid: Option<u32>,
+    pub uid: Option<u32>,
+    pub exe: Option<PathBuf>,
+    pub parent_exe: Option<PathBuf>,
+    pub cwd: Option<PathBuf>,
+}
+
+impl Caller {
+    pub fn lookup(peer: Option<&PeerCred>) -> Caller {
+        let Some(peer) = peer else {
+            return Caller::default();
+        };
+        let proc_dir = PathBuf::from(format!("/proc/{}", peer.pid));
+        let parent_exe = parent_pid(&proc_dir)
+            .and_then(|ppid| fs::read_link(format!("/proc/{}/exe", ppid)).ok());
+        Caller {
+            pid: Some(peer.pid),
+            uid: Some(peer.uid),
+            exe: fs::read_link(proc_dir.join("exe")).ok(),
+            parent_exe,
+            cwd: fs::read_link(proc_dir.join("cwd")).ok(),
+        }
+    }
+}
+
+fn parent_pid(proc_dir: &Path) -> Option<u32> {
+    // Format: "pid (comm) state ppid ..."; comm may itself contain spaces or ')'.
+    let stat = fs::read_to_string(proc_dir.join("stat")).ok()?;
+    let after_comm = &stat[stat.rfind(')')? + 1..];
+    after_comm.split_whitespace().nth(1)?.parse().ok()
+}
+
+/// Hash recorded for a value. Secrets hash their ciphertext so the log never
+/// helps anyone guess their plaintext.
+pub fn value_hash(value: &StoredValue) -> String {
+    let mut hasher = Sha256::new();
+    match value {
+        StoredValue::Plain(v) => hasher.update(v.as_bytes()),
+        other => hasher.update(serde_json::to_vec(other).unwrap_or_default()),
+    }
+    hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+pub fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Append-only JSON-lines log, rotated by size.
+#[derive(Debug)]
+pub struct AuditLog {
+    path: PathBuf,
+    max_bytes: u64,
+    keep: usize,
+}
+
+impl AuditLog {
+    pub fn new(config: &AuditConfig) -> Self {
+        AuditLog {
+            path: config.path.clone().unwrap_or_else(audit_log_path),
+            max_bytes: config.max_bytes.unwrap_or(DEFAULT_MAX_BYTES),
+            keep: config.keep.unwrap_or(DEFAULT_KEEP).max(1),
+        }
+    }
+
+    pub fn record(&self, entry: &AuditEntry) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("creating dir {}", parent.display()))?;
+        }
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+        let mut f = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .mode(0o600)
+            .open(&self.path)
+            .with_context(|| format!("opening {}", self.path.display()))?;
+        f.write_all(line.as_bytes())?;
+        if f.metadata()?.len() > self.max_bytes {
+            self.rotate()?;
+        }
+        Ok(())
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&self) -> Result<()> {
+        let _ = fs::remove_file(self.rotated(self.keep));
+        for n in (1..self.keep).rev() {
+            let from = self.rotated(n);
+            if from.exists() {
+                fs::rename(&from, self.rotated(n + 1))?;
+            }
+        }
+        fs::rename(&self.path, self.rotated(1))
+            .with_context(|| format!("rotating {}", self.path.display()))?;
+        Ok(())
+    }
+
+    /// Returns entries oldest first, across rotated files, optionally for one key.
+    pub fn read(&self, key: Option<&str>) -> Result<Vec<AuditEntry>> {
+        let mut files: Vec<PathBuf> = (1..=self.keep).rev().map(|n| self.rotated(n)).collect();
+        files.push(self.path.clone());
+        let mut out = Vec::new();
+        for file in files {
+            let f = match fs::File::open(&file) {
+                Ok(f) => f,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => {
+                    return Err(e).with_context(|| format!("opening {}", file.display()));
+                }
+            };
+            for line in BufReader::new(f).lines() {
+                let line = line?;
+                // Skip lines a crash may have left half-written
+                let Ok(entry) = serde_json::from_str::<AuditEntry>(&line) else {
+                    continue;
+                };
+                if key.map(|k| k == entry.key).unwrap_or(true) {
+                    out.push(entry);
+                }
+            }
+        }
+        Ok(out)
+    }
+}
+
+pub fn audit_log_path() -> PathBuf {
+    if let Some(p) = std::env::var_os("ENVD_AUDIT_LOG") {
+        if !p.is_empty() {
+            return PathBuf::from(p);
+        }
+    }
+    crate::state_dir().join("audit.log")
+}
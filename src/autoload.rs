@@ -0,0 +1,221 @@
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Files envd loads into their directory's scope, later ones overriding
+/// earlier ones.
+pub const AUTOLOAD_FILES: [&str; 2] = [".env", ".envrc"];
+
+/// How many allowed versions of one file are remembered.
+const MAX_ALLOWED_VERSIONS: usize = 16;
+
+/// What the user decided about one autoload file. Versions are SHA-256
+/// hashes of its contents, so switching back to an allowed version (say, on
+/// another git branch) loads it again without asking.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TrustEntry {
+    /// Allowed versions, oldest first.
+    #[serde(default)]
+    pub allowed: Vec<String>,
+    /// The version that was denied, which is skipped without a notice.
+    #[serde(default)]
+    pub denied: Option<String>,
+}
+
+/// Whether a file's current contents may be loaded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trust {
+    Allowed,
+    Denied,
+    /// Never approved, or changed since it was.
+    Unknown,
+}
+
+/// `envctl allow`/`deny` decisions, keyed by file path and persisted as JSON.
+#[derive(Debug, Default)]
+pub struct TrustStore {
+    path: Option<PathBuf>,
+    files: BTreeMap<PathBuf, TrustEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct TrustFile {
+    files: BTreeMap<PathBuf, TrustEntry>,
+}
+
+impl TrustStore {
+    /// Loads decisions from `path`; a missing file means none yet.
+    pub fn load(path: PathBuf) -> Result<Self> {
+        let files = match fs::read_to_string(&path) {
+            Ok(text) => {
+                serde_json::from_str::<TrustFile>(&text)
+                    .with_context(|| format!("parsing {}", path.display()))?
+                    .files
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e).with_context(|| format!("reading {}", path.display())),
+        };
+        Ok(TrustStore {
+            path: Some(path),
+            files,
+        })
+    }
+
+    pub fn check(&self, file: &Path, hash: &str) -> Trust {
+        match self.files.get(file) {
+            Some(entry) if entry.allowed.iter().any(|h| h == hash) => Trust::Allowed,
+            Some(entry) if entry.denied.as_deref() == Some(hash) => Trust::Denied,
+            _ => Trust::Unknown,
+        }
+    }
+
+    /// Records a decision about `file`'s version `hash` and saves the store.
+    /// Denying revokes every allowed version of the file.
+    pub fn decide(&mut self, file: PathBuf, hash: String, allowed: bool) -> Result<()> {
+        let entry = self.files.entry(file).or_default();
+        if allowed {
+            entry.allowed.retain(|h| *h != hash);
+            entry.allowed.push(hash);
+            let excess = entry.allowed.len().saturating_sub(MAX_ALLOWED_VERSIONS);
+            entry.allowed.drain(..excess);
+            entry.denied = None;
+        } else {
+            entry.allowed.clear();
+            entry.denied = Some(hash);
+        }
+        self.save()
+    }
+
+    fn save(&self) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("creating dir {}", parent.display()))?;
+        }
+        let mut text = serde_json::to_string_pretty(&TrustFile {
+            files: self.files.clone(),
+        })?;
+        text.push('\n');
+        // Write then rename, so a crash never leaves a truncated store
+        let tmp = path.with_extension("json.tmp");
+        let mut f = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&tmp)
+            .with_context(|| format!("creating {}", tmp.display()))?;
+        f.write_all(text.as_bytes())?;
+        f.sync_all()?;
+        fs::rename(&tmp, path).with_context(|| format!("replacing {}", path.display()))?;
+        Ok(())
+    }
+}
+
+pub fn trust_path() -> PathBuf {
+    crate::state_dir().join("trust.json")
+}
+
+/// A file's contents as last seen, so unchanged files aren't re-read.
+#[derive(Debug, Clone)]
+pub struct FileSnapshot {
+    modified: Option<SystemTime>,
+    len: u64,
+    pub hash: String,
+    pub contents: String,
+}
+
+impl FileSnapshot {
+    /// Reads `path`, reusing `previous` if the file's mtime and size match it.
+    /// `Ok(None)` if there is no such file.
+    pub fn read(path: &Path, previous: Option<&FileSnapshot>) -> Result<Option<FileSnapshot>> {
+        let meta = match fs::metadata(path) {
+            Ok(meta) if meta.is_file() => meta,
+            Ok(_) => return Ok(None),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("stat {}", path.display())),
+        };
+        let modified = meta.modified().ok();
+        if let Some(prev) = previous {
+            if prev.modified == modified && prev.len == meta.len() && modified.is_some() {
+                return Ok(Some(prev.clone()));
+            }
+        }
+        let contents =
+            fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+        Ok(Some(FileSnapshot {
+            modified,
+            len: meta.len(),
+            hash: content_hash(&contents),
+            contents,
+        }))
+    }
+}
+
+pub fn content_hash(contents: &str) -> String {
+    Sha256::digest(contents.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// What envd loaded into one directory's scope from its autoload files.
+#[derive(Debug, Clone, Default)]
+pub struct Autoloaded {
+    pub files: HashMap<PathBuf, FileSnapshot>,
+    /// Values last loaded from the files, which a reload compares against.
+    pub values: BTreeMap<String, String>,
+}
+
+/// The autoload files `envctl allow`/`deny` refer to: both of a directory's
+/// files, or one named file. Returns the directory and the files that exist.
+pub fn autoload_targets(path: &Path) -> Result<(PathBuf, Vec<PathBuf>)> {
+    if path.is_dir() {
+        let path = path
+            .canonicalize()
+            .with_context(|| format!("resolving {}", path.display()))?;
+        let files: Vec<PathBuf> = AUTOLOAD_FILES
+            .iter()
+            .map(|name| path.join(name))
+            .filter(|f| f.is_file())
+            .collect();
+        if files.is_empty() {
+            return Err(anyhow!(
+                "no {} in {}",
+                AUTOLOAD_FILES.join(" or "),
+                path.display()
+            ));
+        }
+        return Ok((path, files));
+    }
+    let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+    if !AUTOLOAD_FILES.contains(&name) {
+        return Err(anyhow!(
+            "{} is not loaded automatically; only {} files are",
+            path.display(),
+            AUTOLOAD_FILES.join(" and ")
+        ));
+    }
+    if !path.is_file() {
+        return Err(anyhow!("{} does not exist", path.display()));
+    }
+    // Resolve the directory but not the file itself: a `.env` symlink is
+    // still found, and trusted, under its own name
+    let parent = match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+    let dir = parent
+        .canonicalize()
+        .with_context(|| format!("resolving {}", parent.display()))?;
+    let file = dir.join(name);
+    Ok((dir, vec![file]))
+}
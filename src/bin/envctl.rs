@@ -1,12 +1,25 @@
-use std::fs::{self, File};
-use std::io::{self, Read};
-use std::path::PathBuf;
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::OnceLock;
 
 use anyhow::{anyhow, Context, Result};
+use base64::engine::general_purpose::STANDARD as BASE64_STANDARD;
+use base64::Engine;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::OsRng;
+use clap::builder::PossibleValue;
 use clap::{Parser, Subcommand, ValueEnum};
 use cmux_env::{
-    client_send, client_send_autostart, parse_dotenv, parse_dotenv_base64, Request, Response,
-    Scope, ShellKind,
+    check_key, client_send, client_send_autostart, decode_base64_payload, expand_dotenv,
+    is_valid_key, now_secs, parse_dotenv_entries, parse_dotenv_str, profile_path, read_passphrase,
+    AuditEntry, ChangeEvent, ErrorCode, ProviderRef, Request, Response, Scope, ShellKind,
+    StoredValue,
 };
 
 #[derive(Parser, Debug)]
@@ -18,11 +31,25 @@ struct Cli {
 
 #[derive(Subcommand, Debug)]
 enum Commands {
-    /// Set KEY=VAL. Optional --dir to scope to directory.
+    /// Set KEY=VAL, or KEY with --from-file/--from-cmd. Optional --dir to scope to directory.
     Set {
         kv: String,
         #[arg(long)]
         dir: Option<PathBuf>,
+        #[arg(long, help = "Store the value encrypted as a secret")]
+        secret: bool,
+        #[arg(
+            long,
+            conflicts_with_all = ["secret", "from_cmd"],
+            help = "Read the value from FILE whenever it is exported"
+        )]
+        from_file: Option<PathBuf>,
+        #[arg(
+            long,
+            conflicts_with = "secret",
+            help = "Run CMD with sh -c and use its stdout whenever the value is exported"
+        )]
+        from_cmd: Option<String>,
     },
     /// Unset KEY. Optional --dir to scope to directory.
     Unset {
@@ -30,16 +57,72 @@ enum Commands {
         #[arg(long)]
         dir: Option<PathBuf>,
     },
-    /// Get effective value for KEY at PWD
+    /// Get effective value for KEY at PWD (exits 3 if KEY is not set)
     Get {
         key: String,
         #[arg(long)]
         pwd: Option<PathBuf>,
+        #[arg(long, value_enum, help = "Print KEY and value in this format")]
+        format: Option<OutputFormat>,
+        #[arg(
+            short = '0',
+            long = "null",
+            conflicts_with = "format",
+            help = "End the value with NUL"
+        )]
+        null: bool,
     },
-    /// List effective variables at PWD
+    /// List effective variables at PWD, sorted by key
     List {
         #[arg(long)]
         pwd: Option<PathBuf>,
+        #[arg(long, value_enum, default_value_t = OutputFormat::Vars(VarsFormat::Dotenv))]
+        format: OutputFormat,
+        #[arg(
+            short = '0',
+            long = "null",
+            conflicts_with = "format",
+            help = "Print raw KEY=VALUE records ended by NUL, like `env -0`"
+        )]
+        null: bool,
+    },
+    /// List directory scopes with their variable counts
+    Scopes {
+        #[arg(long, value_enum, default_value_t = OutputFormat::Vars(VarsFormat::Table))]
+        format: OutputFormat,
+        #[arg(
+            short = '0',
+            long = "null",
+            conflicts_with = "format",
+            help = "Print paths ended by NUL"
+        )]
+        null: bool,
+    },
+    /// Run CMD with the effective variables for PWD applied to its environment
+    Exec {
+        #[arg(
+            long,
+            help = "Directory whose scopes apply (default: current directory)"
+        )]
+        pwd: Option<PathBuf>,
+        #[arg(
+            long,
+            value_name = "NAME",
+            help = "Also apply the variables in profiles/NAME.env under envd's config directory"
+        )]
+        profile: Option<String>,
+        #[arg(
+            long,
+            help = "Start from an empty environment instead of the current one"
+        )]
+        clean: bool,
+        #[arg(
+            required = true,
+            trailing_var_arg = true,
+            allow_hyphen_values = true,
+            value_name = "CMD"
+        )]
+        command: Vec<OsString>,
     },
     /// Load .env from file or stdin (-). Optional --dir to scope to directory.
     Load {
@@ -49,25 +132,141 @@ enum Commands {
         dir: Option<PathBuf>,
         #[arg(long, help = "Treat INPUT (or stdin) as base64-encoded content")]
         base64: bool,
+        #[arg(long, help = "Store every loaded value encrypted as a secret")]
+        secret: bool,
+        #[arg(
+            long,
+            help = "Expand $VAR and ${VAR} references from earlier keys and envd's values"
+        )]
+        expand: bool,
+        #[arg(
+            long,
+            requires = "expand",
+            help = "Also expand references from envctl's own environment"
+        )]
+        expand_env: bool,
+    },
+    /// Let exports load the .env/.envrc files in DIR (or just FILE) as they are now
+    Allow {
+        #[arg(value_name = "DIR|FILE", default_value = ".")]
+        path: PathBuf,
+    },
+    /// Stop loading the .env/.envrc files in DIR (or just FILE)
+    Deny {
+        #[arg(value_name = "DIR|FILE", default_value = ".")]
+        path: PathBuf,
+    },
+    /// Make FILE the source of truth for DIR's scope, reapplied whenever it changes
+    Link {
+        file: PathBuf,
+        #[arg(long)]
+        dir: PathBuf,
     },
-    /// Print export/unset script diff since GEN and bump gen
+    /// Stop following the file linked to DIR; its values stay set
+    Unlink {
+        #[arg(long)]
+        dir: PathBuf,
+    },
+    /// Print export/unset script diff since GEN (or the diff as JSON), or the
+    /// full effective set for PWD with --format
     Export {
-        shell: ShellType,
+        #[arg(required_unless_present_any = ["format", "null"])]
+        shell: Option<ExportTarget>,
         #[arg(long, default_value_t = 0)]
         since: u64,
         #[arg(long)]
         pwd: Option<PathBuf>,
+        #[arg(long, value_enum, conflicts_with_all = ["shell", "since"])]
+        format: Option<ExportFormat>,
+        #[arg(
+            short = '0',
+            long = "null",
+            conflicts_with_all = ["shell", "format"],
+            help = "Print the diff as KEY=VALUE (set) and KEY (unset) records ended by NUL"
+        )]
+        null: bool,
+        #[arg(
+            long,
+            requires = "format",
+            help = "Leave out (with a warning) values the format cannot represent"
+        )]
+        skip_invalid: bool,
+        #[arg(
+            long,
+            requires = "format",
+            help = "metadata.name of the k8s-configmap or k8s-secret manifest"
+        )]
+        name: Option<String>,
     },
     /// Print hook for bash/zsh/fish
-    Hook { shell: ShellType },
+    Hook {
+        shell: ShellType,
+        #[arg(long, value_enum, default_value_t = HookMode::Debug)]
+        mode: HookMode,
+    },
     /// Install hook into the user's shell rc file
     InstallHook {
         shell: ShellType,
         #[arg(long, help = "Override rc file path")]
         rcfile: Option<PathBuf>,
+        #[arg(long, value_enum, default_value_t = HookMode::Debug)]
+        mode: HookMode,
+        #[arg(
+            long,
+            help = "Print the change as a unified diff instead of writing it"
+        )]
+        dry_run: bool,
+    },
+    /// Remove the hook block from the user's shell rc file
+    UninstallHook {
+        shell: ShellType,
+        #[arg(long, help = "Override rc file path")]
+        rcfile: Option<PathBuf>,
+    },
+    /// Report whether each shell's rc file has an up-to-date hook
+    HookStatus {
+        /// Shells to check (default: all)
+        shells: Vec<ShellType>,
+        #[arg(long, help = "Check this rc file instead (with a single SHELL)")]
+        rcfile: Option<PathBuf>,
+    },
+    /// Hand envd's variables to systemd user units
+    Systemd {
+        #[command(subcommand)]
+        command: SystemdCommand,
+    },
+    /// Drop the daemon's in-memory secret key
+    Lock,
+    /// Restore the daemon's secret key (from its keyfile, or a passphrase)
+    Unlock {
+        #[arg(long, help = "Prompt for the passphrase")]
+        passphrase: bool,
+    },
+    /// Show recorded mutations, optionally only for KEY
+    Log {
+        key: Option<String>,
+        #[arg(long, help = "Only show the most recent N entries")]
+        limit: Option<usize>,
+    },
+    /// Show past values of KEY with their generations
+    History {
+        key: String,
+        #[arg(long, help = "Only show changes in this directory scope")]
+        dir: Option<PathBuf>,
+    },
+    /// Restore values as of generation N (all keys, or only KEY)
+    Rollback {
+        key: Option<String>,
+        #[arg(long, value_name = "N")]
+        to_gen: u64,
+        #[arg(long, help = "Only roll back this directory scope")]
+        dir: Option<PathBuf>,
     },
     /// Show daemon status
-    Status,
+    Status {
+        #[arg(long, value_enum, default_value_t = OutputFormat::Vars(VarsFormat::Table))]
+        format: OutputFormat,
+    },
     /// Ping daemon
     Ping,
 }
@@ -77,6 +276,131 @@ enum ShellType {
     Bash,
     Zsh,
     Fish,
+    Nu,
+    #[value(name = "pwsh")]
+    PowerShell,
+    #[value(name = "sh")]
+    Posix,
+    #[value(alias = "csh")]
+    Tcsh,
+    Elvish,
+    Xonsh,
+}
+
+/// Output format for list/get/status/scopes: JSON, or one of the `VarsFormat`s.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum OutputFormat {
+    Json,
+    Vars(VarsFormat),
+}
+
+impl ValueEnum for OutputFormat {
+    fn value_variants<'a>() -> &'a [Self] {
+        static VARIANTS: OnceLock<Vec<OutputFormat>> = OnceLock::new();
+        VARIANTS.get_or_init(|| {
+            [OutputFormat::Json]
+                .into_iter()
+                .chain(
+                    VarsFormat::value_variants()
+                        .iter()
+                        .copied()
+                        .map(OutputFormat::Vars),
+                )
+                .collect()
+        })
+    }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        match self {
+            OutputFormat::Json => Some(PossibleValue::new("json")),
+            OutputFormat::Vars(format) => format.to_possible_value(),
+        }
+    }
+}
+
+/// Formats `print_vars` writes variables in, one per line.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+enum VarsFormat {
+    /// KEY=value, double-quoted with escapes when needed
+    Dotenv,
+    /// export KEY='value', for eval in sh-compatible shells
+    Shell,
+    /// Aligned columns for reading, with control characters escaped
+    Table,
+}
+
+#[derive(Subcommand, Debug)]
+enum SystemdCommand {
+    /// Write a drop-in that sets UNIT's Environment= to the effective variables
+    Sync {
+        /// User unit name; `.service` is added if it has no suffix
+        unit: String,
+        #[arg(long)]
+        pwd: Option<PathBuf>,
+        #[arg(long, help = "Run `systemctl --user daemon-reload` afterwards")]
+        reload: bool,
+    },
+}
+
+/// File formats `envctl export --format` writes from the effective variables.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+enum ExportFormat {
+    /// `docker run --env-file`: unquoted KEY=VALUE lines
+    DockerEnv,
+    /// An `environment:` mapping for a docker compose service
+    Compose,
+    /// systemd `EnvironmentFile=`: KEY="VALUE" with shell-style escapes
+    Systemd,
+    /// Kubernetes ConfigMap manifest holding the non-secret variables
+    K8sConfigmap,
+    /// Kubernetes Secret manifest holding the secret variables, base64-encoded
+    K8sSecret,
+    /// GitHub Actions `$GITHUB_ENV`: KEY<<DELIMITER heredoc blocks
+    Github,
+    /// GitLab `artifacts:reports:dotenv`: unquoted KEY=VALUE lines
+    Gitlab,
+    /// Buildkite `environment` hook: a bash script of `export` lines
+    Buildkite,
+}
+
+/// When the bash hook applies changes.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+enum HookMode {
+    /// Before every command, from a DEBUG trap
+    Debug,
+    /// Before every prompt, from PROMPT_COMMAND; plays well with bash-preexec
+    Prompt,
+}
+
+/// What `envctl export` prints the diff for: a shell, or `json`.
+#[derive(Copy, Clone, Debug)]
+enum ExportTarget {
+    Shell(ShellType),
+    Json,
+}
+
+impl ValueEnum for ExportTarget {
+    fn value_variants<'a>() -> &'a [Self] {
+        // Every shell, so a new ShellType is exportable without a second list
+        static VARIANTS: OnceLock<Vec<ExportTarget>> = OnceLock::new();
+        VARIANTS.get_or_init(|| {
+            ShellType::value_variants()
+                .iter()
+                .copied()
+                .map(ExportTarget::Shell)
+                .chain([ExportTarget::Json])
+                .collect()
+        })
+    }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        match self {
+            ExportTarget::Shell(shell) => shell.to_possible_value(),
+            ExportTarget::Json => Some(
+                PossibleValue::new("json").help("{set, unset, errors, generation} for programs"),
+            ),
+        }
+    }
 }
 
 impl From<ShellType> for ShellKind {
@@ -85,6 +409,12 @@ impl From<ShellType> for ShellKind {
             ShellType::Bash => ShellKind::Bash,
             ShellType::Zsh => ShellKind::Zsh,
             ShellType::Fish => ShellKind::Fish,
+            ShellType::Nu => ShellKind::Nu,
+            ShellType::PowerShell => ShellKind::PowerShell,
+            ShellType::Posix => ShellKind::Posix,
+            ShellType::Tcsh => ShellKind::Tcsh,
+            ShellType::Elvish => ShellKind::Elvish,
+            ShellType::Xonsh => ShellKind::Xonsh,
         }
     }
 }
@@ -95,12 +425,63 @@ impl ShellType {
             ShellType::Bash => "bash",
             ShellType::Zsh => "zsh",
             ShellType::Fish => "fish",
+            ShellType::Nu => "nu",
+            ShellType::PowerShell => "pwsh",
+            ShellType::Posix => "sh",
+            ShellType::Tcsh => "tcsh",
+            ShellType::Elvish => "elvish",
+            ShellType::Xonsh => "xonsh",
         }
     }
 }
 
-fn main() -> Result<()> {
+/// Exit status for requests rejected by the daemon's ACL (sysexits EX_NOPERM).
+const EXIT_PERMISSION_DENIED: u8 = 77;
+
+#[derive(Debug, thiserror::Error)]
+#[error("{0}")]
+struct PermissionDenied(String);
+
+/// Exit status for `envctl get` when the key has no value, so scripts can tell
+/// it apart from failures (1) and usage errors (2).
+const EXIT_KEY_MISSING: u8 = 3;
+
+#[derive(Debug, thiserror::Error)]
+#[error("{0} is not set")]
+struct KeyMissing(String);
+
+/// `envctl exec` could not start its command; exits like a shell would.
+#[derive(Debug, thiserror::Error)]
+#[error("cannot run {program}: {source}")]
+struct ExecFailed {
+    program: String,
+    source: io::Error,
+}
+
+fn main() -> ExitCode {
     let cli = Cli::parse();
+    match run(cli) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            if e.downcast_ref::<PermissionDenied>().is_some() {
+                ExitCode::from(EXIT_PERMISSION_DENIED)
+            } else if e.downcast_ref::<KeyMissing>().is_some() {
+                ExitCode::from(EXIT_KEY_MISSING)
+            } else if let Some(exec) = e.downcast_ref::<ExecFailed>() {
+                if exec.source.kind() == io::ErrorKind::NotFound {
+                    ExitCode::from(127)
+                } else {
+                    ExitCode::from(126)
+                }
+            } else {
+                ExitCode::FAILURE
+            }
+        }
+    }
+}
+
+fn run(cli: Cli) -> Result<()> {
     match cli.command {
         Commands::Ping => {
             let resp = client_send(&Request::Ping)?;
@@ -109,67 +490,257 @@ fn main() -> Result<()> {
                     println!("pong");
                     Ok(())
                 }
-                _ => Err(anyhow!("unexpected response")),
+                other => Err(unexpected(other)),
             }
         }
-        Commands::Status => {
+        Commands::Status { format } => {
             let resp = client_send(&Request::Status)?;
             match resp {
                 Response::Status {
                     generation,
                     globals,
                     scopes,
+                    secrets,
+                    locked,
                 } => {
-                    println!("generation: {}", generation);
-                    println!("globals: {}", globals);
-                    println!("scopes: {}", scopes);
+                    if format == OutputFormat::Vars(VarsFormat::Table) {
+                        println!("generation: {}", generation);
+                        println!("globals: {}", globals);
+                        println!("scopes: {}", scopes);
+                        println!(
+                            "secrets: {}{}",
+                            secrets,
+                            if locked { " (locked)" } else { "" }
+                        );
+                        return Ok(());
+                    }
+                    let fields = [
+                        ("generation", generation.to_string()),
+                        ("globals", globals.to_string()),
+                        ("scopes", scopes.to_string()),
+                        ("secrets", secrets.to_string()),
+                        ("locked", locked.to_string()),
+                    ];
+                    match format {
+                        OutputFormat::Json => println!(
+                            "{}",
+                            serde_json::json!({
+                                "generation": generation,
+                                "globals": globals,
+                                "scopes": scopes,
+                                "secrets": secrets,
+                                "locked": locked,
+                            })
+                        ),
+                        OutputFormat::Vars(format) => {
+                            print_vars(fields.iter().map(|(k, v)| (*k, v.as_str())), format)
+                        }
+                    }
                     Ok(())
                 }
-                _ => Err(anyhow!("unexpected response")),
+                other => Err(unexpected(other)),
             }
         }
-        Commands::Set { kv, dir } => {
-            let (key, val) = parse_kv(&kv)?;
+        Commands::Scopes { format, null } => {
+            let scopes = match client_send_autostart(&Request::Scopes)? {
+                Response::Scopes { scopes } => scopes,
+                other => return Err(unexpected(other)),
+            };
+            if null {
+                let mut out = io::stdout().lock();
+                for scope in &scopes {
+                    out.write_all(scope.path.as_os_str().as_encoded_bytes())?;
+                    out.write_all(b"\0")?;
+                }
+                return Ok(());
+            }
+            match format {
+                OutputFormat::Json => println!("{}", serde_json::to_string(&scopes)?),
+                // One line of assignments per scope, quoted as `list` quotes
+                OutputFormat::Vars(VarsFormat::Dotenv) => {
+                    for s in &scopes {
+                        println!(
+                            "path={} keys={} secrets={}",
+                            dotenv_quote(&s.path.display().to_string()),
+                            s.keys,
+                            s.secrets
+                        );
+                    }
+                }
+                OutputFormat::Vars(VarsFormat::Shell) => {
+                    for s in &scopes {
+                        println!(
+                            "path={} keys={} secrets={}",
+                            sh_quote(&s.path.display().to_string()),
+                            s.keys,
+                            s.secrets
+                        );
+                    }
+                }
+                OutputFormat::Vars(VarsFormat::Table) => {
+                    let rows: Vec<[String; 3]> = scopes
+                        .iter()
+                        .map(|s| {
+                            [
+                                escape_control(&s.path.display().to_string()),
+                                s.keys.to_string(),
+                                s.secrets.to_string(),
+                            ]
+                        })
+                        .collect();
+                    print_table(["PATH", "KEYS", "SECRETS"], &rows);
+                }
+            }
+            Ok(())
+        }
+        Commands::Set {
+            kv,
+            dir,
+            secret,
+            from_file,
+            from_cmd,
+        } => {
             let scope = dir.map(Scope::Dir).unwrap_or(Scope::Global);
-            let _ = client_send_autostart(&Request::Set {
+            let provider = match (from_file, from_cmd) {
+                (Some(path), _) => Some(ProviderRef::File {
+                    path: std::path::absolute(&path)
+                        .with_context(|| format!("resolve {}", path.display()))?,
+                }),
+                (_, Some(command)) => Some(ProviderRef::Cmd { command }),
+                _ => None,
+            };
+            if let Some(provider) = provider {
+                if kv.contains('=') {
+                    return Err(anyhow!(
+                        "expected KEY without a value when using a provider"
+                    ));
+                }
+                check_key(&kv)?;
+                return expect_ok(client_send_autostart(&Request::SetRef {
+                    key: kv,
+                    provider,
+                    scope,
+                })?);
+            }
+            let (key, val) = parse_kv(&kv)?;
+            expect_ok(client_send_autostart(&Request::Set {
                 key,
                 value: val,
                 scope,
-            })?;
-            Ok(())
+                secret,
+            })?)
         }
         Commands::Unset { key, dir } => {
             let scope = dir.map(Scope::Dir).unwrap_or(Scope::Global);
-            let _ = client_send_autostart(&Request::Unset { key, scope })?;
-            Ok(())
+            expect_ok(client_send_autostart(&Request::Unset { key, scope })?)
         }
-        Commands::Get { key, pwd } => {
-            let resp = client_send_autostart(&Request::Get { key, pwd })?;
-            match resp {
-                Response::Value { value } => {
-                    if let Some(v) = value {
-                        println!("{}", v);
-                    }
-                    Ok(())
+        Commands::Get {
+            key,
+            pwd,
+            format,
+            null,
+        } => {
+            // Sent even when not given: envd's own directory means nothing here
+            let pwd = Some(pwd.map_or_else(std::env::current_dir, Ok)?);
+            let resp = client_send_autostart(&Request::Get {
+                key: key.clone(),
+                pwd,
+            })?;
+            let value = match resp {
+                Response::Value { value: Some(v) } => v,
+                Response::Value { value: None } => return Err(KeyMissing(key).into()),
+                other => return Err(unexpected(other)),
+            };
+            match format {
+                None if null => {
+                    let mut out = io::stdout().lock();
+                    out.write_all(value.as_bytes())?;
+                    out.write_all(b"\0")?;
+                }
+                None => println!("{}", value),
+                Some(OutputFormat::Json) => println!("{}", serde_json::to_string(&value)?),
+                Some(OutputFormat::Vars(format)) => {
+                    print_vars([(key.as_str(), value.as_str())], format)
                 }
-                _ => Err(anyhow!("unexpected response")),
             }
+            Ok(())
         }
-        Commands::List { pwd } => {
+        Commands::List { pwd, format, null } => {
+            let pwd = Some(pwd.map_or_else(std::env::current_dir, Ok)?);
             let resp = client_send_autostart(&Request::List { pwd })?;
-            match resp {
-                Response::Map { entries } => {
-                    for (k, v) in entries {
-                        println!("{}={}", k, v);
-                    }
-                    Ok(())
+            let entries: BTreeMap<String, String> = match resp {
+                Response::Map { entries, .. } => entries.into_iter().collect(),
+                other => return Err(unexpected(other)),
+            };
+            if null {
+                let mut out = io::stdout().lock();
+                for (k, v) in &entries {
+                    write!(out, "{}={}\0", k, v)?;
+                }
+            } else {
+                match format {
+                    OutputFormat::Json => println!("{}", serde_json::to_string(&entries)?),
+                    OutputFormat::Vars(format) => print_vars(
+                        entries.iter().map(|(k, v)| (k.as_str(), v.as_str())),
+                        format,
+                    ),
                 }
-                _ => Err(anyhow!("unexpected response")),
             }
+            Ok(())
         }
-        Commands::Load { input, dir, base64 } => {
-            let scope = dir.map(Scope::Dir).unwrap_or(Scope::Global);
-            let entries = if base64 {
+        Commands::Exec {
+            pwd,
+            profile,
+            clean,
+            command,
+        } => {
+            let pwd = match pwd {
+                Some(p) => {
+                    std::path::absolute(&p).with_context(|| format!("resolving {}", p.display()))?
+                }
+                None => std::env::current_dir().context("reading current directory")?,
+            };
+            // Read first, so a missing profile doesn't autostart envd for nothing
+            let profile = match profile {
+                Some(name) => {
+                    let path = profile_path(&name)?;
+                    let text = fs::read_to_string(&path)
+                        .with_context(|| format!("reading profile {}", path.display()))?;
+                    parse_dotenv_str(&text)
+                        .with_context(|| format!("parsing profile {}", path.display()))?
+                }
+                None => Vec::new(),
+            };
+            let mut entries = match client_send_autostart(&Request::List { pwd: Some(pwd) })? {
+                Response::Map { entries, .. } => entries,
+                other => return Err(unexpected(other)),
+            };
+            entries.extend(profile);
+            let (program, args) = command.split_first().context("no command given")?;
+            let mut cmd = std::process::Command::new(program);
+            cmd.args(args);
+            if clean {
+                cmd.env_clear();
+            }
+            cmd.envs(entries);
+            // Replaces this process, so the command's exit status and signals
+            // reach our caller directly. Only returns on failure.
+            let source = cmd.exec();
+            Err(ExecFailed {
+                program: program.to_string_lossy().into_owned(),
+                source,
+            }
+            .into())
+        }
+        Commands::Load {
+            input,
+            dir,
+            base64,
+            secret,
+            expand,
+            expand_env,
+        } => {
+            let text = if base64 {
                 let payload = if input == "-" {
                     let mut buf = String::new();
                     io::stdin().read_to_string(&mut buf)?;
@@ -177,21 +748,176 @@ fn main() -> Result<()> {
                 } else {
                     input.clone()
                 };
-                parse_dotenv_base64(payload)?
+                decode_base64_payload(&payload)?
             } else if input == "-" {
                 let mut buf = String::new();
                 io::stdin().read_to_string(&mut buf)?;
-                parse_dotenv(buf.as_bytes())?
+                buf
             } else {
-                let f = File::open(&input).with_context(|| format!("open {}", input))?;
-                parse_dotenv(f)?
+                fs::read_to_string(&input).with_context(|| format!("open {}", input))?
             };
-            let _ = client_send_autostart(&Request::Load { entries, scope })?;
-            Ok(())
+            let source = match (input.as_str(), base64) {
+                ("-", _) => "stdin",
+                (_, true) => "base64 payload",
+                (path, false) => path,
+            };
+            let parsed =
+                parse_dotenv_entries(&text).with_context(|| format!("parsing {}", source))?;
+            let entries = if expand {
+                let pwd = match &dir {
+                    Some(dir) => dir.clone(),
+                    None => std::env::current_dir()?,
+                };
+                // Values, secret keys and provider keys, fetched only once a
+                // reference isn't defined in the file
+                type Listed = (HashMap<String, String>, Vec<String>, Vec<String>);
+                let mut daemon: Option<Listed> = None;
+                expand_dotenv(parsed, |name| {
+                    if daemon.is_none() {
+                        daemon = Some(
+                            match client_send_autostart(&Request::List {
+                                pwd: Some(pwd.clone()),
+                            })? {
+                                Response::Map {
+                                    entries,
+                                    secrets,
+                                    refs,
+                                } => (entries, secrets, refs),
+                                other => return Err(unexpected(other)),
+                            },
+                        );
+                    }
+                    let (values, secrets, refs) = daemon.as_ref().expect("fetched above");
+                    if let Some(v) = values.get(name) {
+                        // Don't copy a secret, or a provider's output (as
+                        // likely a credential), into values stored in plain text
+                        if !secret && secrets.iter().any(|s| s == name) {
+                            return Err(anyhow!(
+                                "{} is a secret; pass --secret to store values expanding it",
+                                name
+                            ));
+                        }
+                        if !secret && refs.iter().any(|r| r == name) {
+                            return Err(anyhow!(
+                                "{} comes from a provider; pass --secret to store values expanding it",
+                                name
+                            ));
+                        }
+                        return Ok(Some(v.clone()));
+                    }
+                    Ok(if expand_env {
+                        std::env::var(name).ok()
+                    } else {
+                        None
+                    })
+                })
+                .with_context(|| format!("expanding {}", source))?
+            } else {
+                parsed
+                    .into_iter()
+                    .map(|e| {
+                        let value = e.value();
+                        (e.key, value)
+                    })
+                    .collect()
+            };
+            let scope = dir.map(Scope::Dir).unwrap_or(Scope::Global);
+            expect_ok(client_send_autostart(&Request::Load {
+                entries,
+                scope,
+                secret,
+            })?)
+        }
+        Commands::Allow { path } => decide_autoload(path, true),
+        Commands::Deny { path } => decide_autoload(path, false),
+        Commands::Link { file, dir } => {
+            // envd resolves paths from its own working directory
+            let cwd = std::env::current_dir()?;
+            let req = Request::Link {
+                file: cwd.join(file),
+                dir: cwd.join(dir),
+            };
+            match client_send_autostart(&req)? {
+                Response::Linked {
+                    file,
+                    dir,
+                    changes,
+                    new_generation,
+                } => {
+                    println!(
+                        "Linked {} to {} ({} change(s), generation {})",
+                        file.display(),
+                        dir.display(),
+                        changes,
+                        new_generation
+                    );
+                    Ok(())
+                }
+                other => Err(unexpected(other)),
+            }
+        }
+        Commands::Unlink { dir } => {
+            let dir = std::env::current_dir()?.join(dir);
+            match client_send_autostart(&Request::Unlink { dir })? {
+                Response::Unlinked { file, dir } => {
+                    println!("Unlinked {} from {}", file.display(), dir.display());
+                    Ok(())
+                }
+                other => Err(unexpected(other)),
+            }
         }
-        Commands::Export { shell, since, pwd } => {
-            let shell: ShellKind = shell.into();
+        Commands::Export {
+            shell,
+            since,
+            pwd,
+            format,
+            skip_invalid,
+            name,
+            null,
+        } => {
             let pwd = pwd.unwrap_or(std::env::current_dir()?);
+            if let Some(format) = format {
+                let (entries, secrets, refs) =
+                    match client_send_autostart(&Request::List { pwd: Some(pwd) })? {
+                        Response::Map {
+                            entries,
+                            secrets,
+                            refs,
+                        } => (entries, secrets, refs),
+                        other => return Err(unexpected(other)),
+                    };
+                let mut entries: BTreeMap<String, String> = entries.into_iter().collect();
+                let is_k8s = matches!(format, ExportFormat::K8sConfigmap | ExportFormat::K8sSecret);
+                if is_k8s {
+                    // Secrets and provider values (tokens, more often than not)
+                    // go to the Secret manifest and everything else to the
+                    // ConfigMap, so neither leaks into the other
+                    let want_secrets = format == ExportFormat::K8sSecret;
+                    let left_out = entries.len();
+                    entries
+                        .retain(|k, _| (secrets.contains(k) || refs.contains(k)) == want_secrets);
+                    let left_out = left_out - entries.len();
+                    if left_out > 0 && !want_secrets {
+                        eprintln!(
+                            "envctl: leaving {} secret or provider value(s) out of the ConfigMap; export them with --format k8s-secret",
+                            left_out
+                        );
+                    }
+                }
+                match &name {
+                    Some(name) if is_k8s => check_k8s_name(name)?,
+                    Some(_) => return Err(anyhow!("--name only applies to the k8s-* formats")),
+                    None if is_k8s => {
+                        return Err(anyhow!("--name is required for {}", format_name(format)));
+                    }
+                    None => {}
+                }
+                print!(
+                    "{}",
+                    render_export_format(format, &entries, name.as_deref(), skip_invalid)?
+                );
+                return Ok(());
+            }
             // If --since not specified (0), try ENVCTL_GEN to provide a smoother UX
             let since = if since == 0 {
                 std::env::var("ENVCTL_GEN")
@@ -201,7 +927,37 @@ fn main() -> Result<()> {
             } else {
                 since
             };
-            let resp = client_send_autostart(&Request::Export { shell, since, pwd })?;
+            let shell = match shell {
+                Some(ExportTarget::Shell(shell)) => shell,
+                Some(ExportTarget::Json) | None => {
+                    let diff = match client_send_autostart(&Request::ExportDiff { since, pwd })? {
+                        Response::ExportDiff { diff } => diff,
+                        other => return Err(unexpected(other)),
+                    };
+                    if null {
+                        for msg in &diff.errors {
+                            eprintln!("envctl: {}", msg);
+                        }
+                        let mut out = io::stdout().lock();
+                        for (k, v) in &diff.set {
+                            write!(out, "{}={}\0", k, v)?;
+                        }
+                        for k in &diff.unset {
+                            write!(out, "{}\0", k)?;
+                        }
+                        write!(out, "ENVCTL_GEN={}\0", diff.generation)?;
+                        out.flush()?;
+                    } else {
+                        println!("{}", serde_json::to_string(&diff)?);
+                    }
+                    return Ok(());
+                }
+            };
+            let resp = client_send_autostart(&Request::Export {
+                shell: shell.into(),
+                since,
+                pwd,
+            })?;
             match resp {
                 Response::Export {
                     script,
@@ -210,56 +966,624 @@ fn main() -> Result<()> {
                     print!("{}", script);
                     Ok(())
                 }
-                _ => Err(anyhow!("unexpected response")),
+                other => Err(unexpected(other)),
             }
         }
-        Commands::Hook { shell } => {
-            match shell {
-                ShellType::Bash => print!("{}", hook_bash()),
-                ShellType::Zsh => print!("{}", hook_zsh()),
-                ShellType::Fish => print!("{}", hook_fish()),
-            }
+        Commands::Hook { shell, mode } => {
+            print!("{}", hook_script(shell, mode)?);
             Ok(())
         }
-        Commands::InstallHook { shell, rcfile } => {
-            install_hook(shell, rcfile)?;
+        Commands::InstallHook {
+            shell,
+            rcfile,
+            mode,
+            dry_run,
+        } => {
+            install_hook(shell, rcfile, mode, dry_run)?;
             Ok(())
         }
+        Commands::UninstallHook { shell, rcfile } => uninstall_hook(shell, rcfile),
+        Commands::HookStatus { shells, rcfile } => hook_status(shells, rcfile),
+        Commands::Log { key, limit } => {
+            let resp = client_send_autostart(&Request::Log { key, limit })?;
+            match resp {
+                Response::AuditLog { entries } => {
+                    for entry in entries {
+                        println!("{}", format_audit_entry(&entry));
+                    }
+                    Ok(())
+                }
+                other => Err(unexpected(other)),
+            }
+        }
+        Commands::History { key, dir } => {
+            let scope = dir.map(Scope::Dir);
+            let resp = client_send_autostart(&Request::History { key, scope })?;
+            match resp {
+                Response::History { events } => {
+                    for ev in events {
+                        println!("{}", format_history_event(&ev));
+                    }
+                    Ok(())
+                }
+                other => Err(unexpected(other)),
+            }
+        }
+        Commands::Rollback { key, to_gen, dir } => {
+            let scope = dir.map(Scope::Dir);
+            let resp = client_send_autostart(&Request::Rollback { to_gen, key, scope })?;
+            match resp {
+                Response::RolledBack {
+                    changes,
+                    new_generation,
+                } => {
+                    println!(
+                        "rolled back {} key(s) to generation {} (now at generation {})",
+                        changes, to_gen, new_generation
+                    );
+                    Ok(())
+                }
+                other => Err(unexpected(other)),
+            }
+        }
+        Commands::Systemd {
+            command: SystemdCommand::Sync { unit, pwd, reload },
+        } => systemd_sync(&unit, pwd, reload),
+        Commands::Lock => expect_ok(client_send(&Request::Lock)?),
+        Commands::Unlock { passphrase } => {
+            let passphrase = if passphrase {
+                Some(read_passphrase("Passphrase: ")?)
+            } else {
+                None
+            };
+            expect_ok(client_send(&Request::Unlock { passphrase })?)
+        }
+    }
+}
+
+/// Renders `entries` as `format`. `name` is the manifest name for the k8s
+/// formats and is ignored by the others.
+fn render_export_format(
+    format: ExportFormat,
+    entries: &BTreeMap<String, String>,
+    name: Option<&str>,
+    skip_invalid: bool,
+) -> Result<String> {
+    // Why a variable can't be written in `format`, if it can't
+    let unrepresentable = |k: &str, v: &str| -> Option<&'static str> {
+        match format {
+            // Keys are written unquoted, so anything else could run as code
+            ExportFormat::DockerEnv
+            | ExportFormat::Compose
+            | ExportFormat::Systemd
+            | ExportFormat::Github
+            | ExportFormat::Gitlab
+            | ExportFormat::Buildkite
+                if !is_valid_key(k) =>
+            {
+                Some("is not a valid variable name")
+            }
+            // Each line is taken literally up to the newline; there is no quoting
+            ExportFormat::DockerEnv | ExportFormat::Gitlab if v.contains(['\n', '\r']) => {
+                Some("contains a newline")
+            }
+            ExportFormat::K8sConfigmap | ExportFormat::K8sSecret if !is_k8s_data_key(k) => {
+                Some("is not a valid ConfigMap/Secret key")
+            }
+            // Secret data is base64, so any bytes survive
+            ExportFormat::K8sSecret => None,
+            _ if v.contains('\0') => Some("contains a NUL byte"),
+            _ => None,
+        }
+    };
+    let mut invalid = Vec::new();
+    let mut vars = Vec::new();
+    for (k, v) in entries {
+        match unrepresentable(k, v) {
+            // Quote keys that could carry control characters to the terminal
+            Some(why) if is_valid_key(k) => invalid.push(format!("{} ({})", k, why)),
+            Some(why) => invalid.push(format!("{:?} ({})", k, why)),
+            None => vars.push((k.as_str(), v.as_str())),
+        }
+    }
+    if !invalid.is_empty() {
+        if !skip_invalid {
+            return Err(anyhow!(
+                "cannot write these values as {}: {}; use --skip-invalid to leave them out",
+                format_name(format),
+                invalid.join(", ")
+            ));
+        }
+        for key in &invalid {
+            eprintln!("envctl: skipping {}", key);
+        }
     }
+
+    let mut out = String::new();
+    match format {
+        ExportFormat::DockerEnv => {
+            for (k, v) in vars {
+                out.push_str(&format!("{}={}\n", k, v));
+            }
+        }
+        ExportFormat::Compose => {
+            if vars.is_empty() {
+                out.push_str("environment: {}\n");
+            } else {
+                out.push_str("environment:\n");
+            }
+            for (k, v) in vars {
+                // JSON strings are valid YAML double-quoted scalars; `$$` stops
+                // compose from interpolating the value
+                out.push_str(&format!(
+                    "  {}: {}\n",
+                    serde_json::to_string(k)?,
+                    serde_json::to_string(&v.replace('$', "$$"))?
+                ));
+            }
+        }
+        ExportFormat::Systemd => {
+            for (k, v) in vars {
+                out.push_str(&format!("{}={}\n", k, systemd_env_file_quote(v)));
+            }
+        }
+        ExportFormat::Github => {
+            for (k, v) in vars {
+                // A fresh unguessable delimiter per value, so no value can end
+                // its block early and inject variables of its own
+                let delim = loop {
+                    let delim = github_delimiter()?;
+                    if !v.contains(&delim) {
+                        break delim;
+                    }
+                };
+                out.push_str(&format!("{}<<{}\n{}\n{}\n", k, delim, v, delim));
+            }
+        }
+        ExportFormat::Gitlab => {
+            for (k, v) in vars {
+                out.push_str(&format!("{}={}\n", k, v));
+            }
+        }
+        ExportFormat::Buildkite => {
+            out.push_str("#!/bin/bash\n# Written by `envctl export --format buildkite`\n");
+            for (k, v) in vars {
+                out.push_str(&format!("export {}={}\n", k, sh_quote(v)));
+            }
+        }
+        ExportFormat::K8sConfigmap | ExportFormat::K8sSecret => {
+            let secret = format == ExportFormat::K8sSecret;
+            out.push_str("apiVersion: v1\n");
+            out.push_str(if secret {
+                "kind: Secret\n"
+            } else {
+                "kind: ConfigMap\n"
+            });
+            out.push_str("metadata:\n");
+            out.push_str(&format!(
+                "  name: {}\n",
+                serde_json::to_string(name.unwrap_or_default())?
+            ));
+            if secret {
+                out.push_str("type: Opaque\n");
+            }
+            out.push_str(if vars.is_empty() {
+                "data: {}\n"
+            } else {
+                "data:\n"
+            });
+            for (k, v) in vars {
+                let v = if secret {
+                    BASE64_STANDARD.encode(v)
+                } else {
+                    v.to_string()
+                };
+                out.push_str(&format!(
+                    "  {}: {}\n",
+                    serde_json::to_string(k)?,
+                    serde_json::to_string(&v)?
+                ));
+            }
+        }
+    }
+    Ok(out)
 }
 
-fn install_hook(shell: ShellType, rcfile: Option<PathBuf>) -> Result<()> {
-    const START_MARKER: &str = "# >>> envctl hook >>>";
-    const END_MARKER: &str = "# <<< envctl hook <<<";
+fn github_delimiter() -> Result<String> {
+    let mut bytes = [0u8; 16];
+    OsRng
+        .try_fill_bytes(&mut bytes)
+        .map_err(|e| anyhow!("generating a delimiter: {}", e))?;
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    Ok(format!("ghadelimiter_{}", hex))
+}
 
-    let rc_path = rcfile.unwrap_or(default_rc_path(shell)?);
-    if let Some(parent) = rc_path.parent() {
-        fs::create_dir_all(parent)
-            .with_context(|| format!("creating rcfile directory {}", parent.display()))?;
+// ConfigMap and Secret keys: alphanumerics, `-`, `_` and `.`, at most 253 bytes
+fn is_k8s_data_key(key: &str) -> bool {
+    !key.is_empty()
+        && key.len() <= 253
+        && key
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.'))
+}
+
+// Object names are DNS subdomains (RFC 1123): lowercase alphanumerics, `-`
+// and `.`, starting and ending with an alphanumeric
+fn check_k8s_name(name: &str) -> Result<()> {
+    let alnum = |b: u8| b.is_ascii_lowercase() || b.is_ascii_digit();
+    let valid = !name.is_empty()
+        && name.len() <= 253
+        && name.bytes().all(|b| alnum(b) || matches!(b, b'-' | b'.'))
+        && name.bytes().next().is_some_and(alnum)
+        && name.bytes().last().is_some_and(alnum);
+    if valid {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "invalid name {:?}: use lowercase letters, digits, '-' and '.', starting and ending with a letter or digit",
+            name
+        ))
+    }
+}
+
+// EnvironmentFile= values may be double-quoted; inside the quotes newlines are
+// kept and a backslash escapes `"`, `\`, `` ` `` and `$`.
+fn systemd_env_file_quote(val: &str) -> String {
+    let mut out = String::with_capacity(val.len() + 2);
+    out.push('"');
+    for ch in val.chars() {
+        if matches!(ch, '"' | '\\' | '`' | '$') {
+            out.push('\\');
+        }
+        out.push(ch);
+    }
+    out.push('"');
+    out
+}
+
+// Unit file settings are C-unescaped and `%` starts a specifier, so one
+// Environment= word is quoted with C escapes and `%%`.
+fn systemd_unit_env_word(key: &str, val: &str) -> String {
+    let mut out = String::from("\"");
+    out.push_str(key);
+    out.push('=');
+    for ch in val.chars() {
+        match ch {
+            '\\' => out.push_str("\\\\"),
+            '"' => out.push_str("\\\""),
+            '%' => out.push_str("%%"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_ascii_control() => out.push_str(&format!("\\x{:02x}", c as u32)),
+            _ => out.push(ch),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn decide_autoload(path: PathBuf, allow: bool) -> Result<()> {
+    // envd resolves the path from its own working directory
+    let path = std::env::current_dir()?.join(path);
+    let req = if allow {
+        Request::Allow { path }
+    } else {
+        Request::Deny { path }
+    };
+    let (files, notices) = match client_send_autostart(&req)? {
+        Response::Autoload { files, notices } => (files, notices),
+        other => return Err(unexpected(other)),
+    };
+    for file in &files {
+        println!(
+            "{} {}",
+            if allow { "Allowed" } else { "Denied" },
+            file.display()
+        );
     }
+    for msg in &notices {
+        eprintln!("envctl: {}", msg);
+    }
+    Ok(())
+}
 
-    let mut contents = if rc_path.exists() {
-        fs::read_to_string(&rc_path)
-            .with_context(|| format!("reading rcfile {}", rc_path.display()))?
+fn systemd_sync(unit: &str, pwd: Option<PathBuf>, reload: bool) -> Result<()> {
+    if unit.is_empty() || unit.contains('/') {
+        return Err(anyhow!("invalid unit name {:?}", unit));
+    }
+    let unit = if unit.contains('.') {
+        unit.to_string()
     } else {
-        String::new()
+        format!("{}.service", unit)
     };
+    let pwd = match pwd {
+        Some(p) => p,
+        None => std::env::current_dir()?,
+    };
+    let entries: BTreeMap<String, String> =
+        match client_send_autostart(&Request::List { pwd: Some(pwd) })? {
+            Response::Map { entries, .. } => entries.into_iter().collect(),
+            other => return Err(unexpected(other)),
+        };
+    if let Some(key) = entries.keys().find(|k| !is_valid_key(k)) {
+        return Err(anyhow!(
+            "invalid key {:?}, which the drop-in would write unquoted",
+            key
+        ));
+    }
+    if let Some(key) = entries
+        .iter()
+        .find(|(_, v)| v.contains('\0'))
+        .map(|(k, _)| k)
+    {
+        return Err(anyhow!(
+            "{} contains a NUL byte, which systemd cannot hold",
+            key
+        ));
+    }
 
-    if let Some(start_idx) = contents.find(START_MARKER) {
-        let end_search = &contents[start_idx..];
-        if let Some(end_rel) = end_search.find(END_MARKER) {
-            let mut end_idx = start_idx + end_rel + END_MARKER.len();
-            while let Some(&byte) = contents.as_bytes().get(end_idx) {
-                if byte == b'\n' || byte == b'\r' {
-                    end_idx += 1;
-                } else {
-                    break;
-                }
+    let mut conf =
+        String::from("# Written by `envctl systemd sync`; rerun it instead of editing.\n");
+    conf.push_str("[Service]\n");
+    for (k, v) in &entries {
+        conf.push_str(&format!("Environment={}\n", systemd_unit_env_word(k, v)));
+    }
+
+    let home = std::env::var("HOME").context("HOME not set")?;
+    let dir = xdg_config_home(Path::new(&home))
+        .join("systemd")
+        .join("user")
+        .join(format!("{}.d", unit));
+    fs::create_dir_all(&dir).with_context(|| format!("creating {}", dir.display()))?;
+    let path = dir.join("envctl.conf");
+    // Values include decrypted secrets, so only the owner may read them
+    replace_file(&path, &conf, Some(fs::Permissions::from_mode(0o600)))
+        .with_context(|| format!("writing {}", path.display()))?;
+    println!(
+        "Wrote {} variable(s) for {} to {}",
+        entries.len(),
+        unit,
+        path.display()
+    );
+
+    if reload {
+        let status = std::process::Command::new("systemctl")
+            .args(["--user", "daemon-reload"])
+            .status()
+            .context("running systemctl --user daemon-reload")?;
+        if !status.success() {
+            return Err(anyhow!("systemctl --user daemon-reload failed: {}", status));
+        }
+        println!("Reloaded; restart {} to apply", unit);
+    } else {
+        println!(
+            "Apply with: systemctl --user daemon-reload && systemctl --user restart {}",
+            unit
+        );
+    }
+    Ok(())
+}
+
+fn format_name(format: ExportFormat) -> String {
+    format
+        .to_possible_value()
+        .map(|v| v.get_name().to_string())
+        .unwrap_or_default()
+}
+
+/// Prints variables one per line.
+fn print_vars<'a>(vars: impl IntoIterator<Item = (&'a str, &'a str)>, format: VarsFormat) {
+    let vars: Vec<(&str, &str)> = vars.into_iter().collect();
+    // Keys are written unquoted, so one stored before keys were validated
+    // could inject code into `eval "$(envctl list --format shell)"`
+    let writable = || {
+        vars.iter().filter(|(k, _)| {
+            let valid = is_valid_key(k);
+            if !valid {
+                eprintln!("envctl: skipping invalid key {:?}", k);
+            }
+            valid
+        })
+    };
+    match format {
+        VarsFormat::Dotenv => {
+            for (k, v) in writable() {
+                println!("{}={}", k, dotenv_quote(v));
+            }
+        }
+        VarsFormat::Shell => {
+            for (k, v) in writable() {
+                println!("export {}={}", k, sh_quote(v));
+            }
+        }
+        VarsFormat::Table => {
+            let rows: Vec<[String; 2]> = vars
+                .iter()
+                .map(|(k, v)| [escape_control(k), escape_control(v)])
+                .collect();
+            print_table(["KEY", "VALUE"], &rows);
+        }
+    }
+}
+
+fn print_table<const N: usize>(header: [&str; N], rows: &[[String; N]]) {
+    let mut widths = header.map(str::len);
+    for row in rows {
+        for (w, cell) in widths.iter_mut().zip(row) {
+            *w = (*w).max(cell.chars().count());
+        }
+    }
+    let line = |cells: Vec<&str>| {
+        let mut out = String::new();
+        for (i, cell) in cells.iter().enumerate() {
+            if i + 1 == N {
+                out.push_str(cell);
+            } else {
+                out.push_str(&format!("{:<width$}  ", cell, width = widths[i]));
             }
-            contents.replace_range(start_idx..end_idx, "");
-        } else {
-            contents.truncate(start_idx);
         }
+        println!("{}", out);
+    };
+    line(header.to_vec());
+    for row in rows {
+        line(row.iter().map(String::as_str).collect());
+    }
+}
+
+// Unquoted when that is unambiguous; otherwise double-quoted with the escapes
+// dotenv loaders understand (`$` too, for loaders that expand variables).
+fn dotenv_quote(val: &str) -> String {
+    let plain = !val.is_empty()
+        && val
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_-.,:/@%+=".contains(c));
+    if plain {
+        return val.to_string();
+    }
+    let mut out = String::with_capacity(val.len() + 2);
+    out.push('"');
+    for ch in val.chars() {
+        match ch {
+            '\\' => out.push_str("\\\\"),
+            '"' => out.push_str("\\\""),
+            '$' => out.push_str("\\$"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            _ => out.push(ch),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn sh_quote(val: &str) -> String {
+    format!("'{}'", val.replace('\'', "'\\''"))
+}
+
+fn escape_control(val: &str) -> String {
+    let mut out = String::with_capacity(val.len());
+    for ch in val.chars() {
+        match ch {
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => out.push_str(&c.escape_unicode().to_string()),
+            _ => out.push(ch),
+        }
+    }
+    out
+}
+
+fn expect_ok(resp: Response) -> Result<()> {
+    match resp {
+        Response::Ok => Ok(()),
+        other => Err(unexpected(other)),
+    }
+}
+
+fn unexpected(resp: Response) -> anyhow::Error {
+    match resp {
+        Response::Error {
+            message,
+            code: Some(ErrorCode::PermissionDenied),
+        } => PermissionDenied(message).into(),
+        Response::Error { message, .. } => anyhow!(message),
+        _ => anyhow!("unexpected response"),
+    }
+}
+
+fn format_history_event(ev: &ChangeEvent) -> String {
+    let value = match &ev.value {
+        None => " (unset)".to_string(),
+        Some(StoredValue::Plain(v)) => format!("={}", v),
+        Some(StoredValue::Secret(_)) => "=(secret)".to_string(),
+        Some(StoredValue::Ref(r)) => format!("=(from {})", r.describe()),
+    };
+    format!(
+        "gen={} {} [{}] {}{}",
+        ev.generation,
+        format_utc(ev.timestamp),
+        format_scope(&ev.scope),
+        ev.key,
+        value
+    )
+}
+
+fn format_scope(scope: &Scope) -> String {
+    match scope {
+        Scope::Global => "global".to_string(),
+        Scope::Dir(p) => p.display().to_string(),
+    }
+}
+
+fn format_audit_entry(e: &AuditEntry) -> String {
+    fn short(hash: &Option<String>) -> &str {
+        hash.as_deref()
+            .map(|h| &h[..h.len().min(12)])
+            .unwrap_or("-")
+    }
+    fn opt<T: std::fmt::Display>(v: Option<T>) -> String {
+        v.map(|v| v.to_string()).unwrap_or_else(|| "?".to_string())
+    }
+    format!(
+        "{} gen={} {} {} [{}] uid={} pid={} exe={} parent={} cwd={} old={} new={}",
+        format_utc(e.timestamp),
+        e.generation,
+        e.op,
+        e.key,
+        format_scope(&e.scope),
+        opt(e.uid),
+        opt(e.pid),
+        opt(e.exe.as_ref().map(|p| p.display())),
+        opt(e.parent_exe.as_ref().map(|p| p.display())),
+        opt(e.cwd.as_ref().map(|p| p.display())),
+        short(&e.old_hash),
+        short(&e.new_hash),
+    )
+}
+
+// RFC 3339 UTC timestamp from Unix seconds (civil-from-days, no tz database needed).
+fn format_utc(secs: u64) -> String {
+    let days = (secs / 86_400) as i64;
+    let rem = secs % 86_400;
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        rem / 3_600,
+        rem % 3_600 / 60,
+        rem % 60
+    )
+}
+
+const START_MARKER: &str = "# >>> envctl hook >>>";
+const END_MARKER: &str = "# <<< envctl hook <<<";
+
+fn install_hook(
+    shell: ShellType,
+    rcfile: Option<PathBuf>,
+    mode: HookMode,
+    dry_run: bool,
+) -> Result<()> {
+    let hook_body = hook_script(shell, mode)?;
+
+    let rc_path = rcfile.unwrap_or(default_rc_path(shell)?);
+    let original = read_rcfile(&rc_path)?.unwrap_or_default();
+
+    let mut contents = original.clone();
+    if let Some(range) = find_hook_block(&contents, &rc_path)? {
+        contents.replace_range(range, "");
         contents = contents.trim_end_matches('\n').to_string();
     }
 
@@ -267,33 +1591,277 @@ fn install_hook(shell: ShellType, rcfile: Option<PathBuf>) -> Result<()> {
         contents.push('\n');
     }
 
-    let hook_body = match shell {
-        ShellType::Bash => hook_bash(),
-        ShellType::Zsh => hook_zsh(),
-        ShellType::Fish => hook_fish(),
-    };
+    contents.push_str(&hook_block(&hook_body));
 
+    if dry_run {
+        print_unified_diff(&rc_path, &original, &contents);
+        return Ok(());
+    }
+    if contents == original {
+        println!(
+            "envctl hook for {} is already up to date in {}",
+            shell.as_str(),
+            rc_path.display()
+        );
+        return Ok(());
+    }
+    if let Some(backup) = write_rcfile(&rc_path, &contents)? {
+        println!("Backed up {} to {}", rc_path.display(), backup.display());
+    }
+
+    println!(
+        "Installed envctl hook for {} at {}",
+        shell.as_str(),
+        rc_path.display()
+    );
+    if matches!(shell, ShellType::Posix)
+        && std::env::var_os("ENV").map(PathBuf::from) != Some(rc_path.clone())
+    {
+        println!(
+            "Add `export ENV={}` to your ~/.profile so interactive shells load it",
+            rc_path.display()
+        );
+    }
+
+    Ok(())
+}
+
+fn hook_block(hook_body: &str) -> String {
     let mut block = String::new();
     block.push_str(START_MARKER);
     block.push('\n');
-    block.push_str(&hook_body);
+    block.push_str(hook_body);
     if !hook_body.ends_with('\n') {
         block.push('\n');
     }
     block.push_str(END_MARKER);
     block.push('\n');
+    block
+}
 
-    contents.push_str(&block);
+/// Byte range of the marker block in `contents`, through the end marker's
+/// line break. `Ok(None)` if there is no block.
+fn find_hook_block(contents: &str, rc_path: &Path) -> Result<Option<std::ops::Range<usize>>> {
+    let Some(start) = contents.find(START_MARKER) else {
+        return Ok(None);
+    };
+    let end_rel = contents[start..].find(END_MARKER).ok_or_else(|| {
+        anyhow!(
+            "{} has `{}` without a matching `{}`; fix the block by hand",
+            rc_path.display(),
+            START_MARKER,
+            END_MARKER
+        )
+    })?;
+    let mut end = start + end_rel + END_MARKER.len();
+    if contents[end..].starts_with("\r\n") {
+        end += 2;
+    } else if contents[end..].starts_with('\n') {
+        end += 1;
+    }
+    Ok(Some(start..end))
+}
 
-    fs::write(&rc_path, contents)
-        .with_context(|| format!("writing rcfile {}", rc_path.display()))?;
+fn read_rcfile(rc_path: &Path) -> Result<Option<String>> {
+    match fs::read_to_string(rc_path) {
+        Ok(contents) => Ok(Some(contents)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("reading rcfile {}", rc_path.display())),
+    }
+}
 
+fn uninstall_hook(shell: ShellType, rcfile: Option<PathBuf>) -> Result<()> {
+    let rc_path = match rcfile {
+        Some(p) => p,
+        None => default_rc_path(shell)?,
+    };
+    let contents = read_rcfile(&rc_path)?.unwrap_or_default();
+    let Some(range) = find_hook_block(&contents, &rc_path)? else {
+        println!("No envctl hook found in {}", rc_path.display());
+        return Ok(());
+    };
+
+    let mut contents = contents;
+    contents.replace_range(range, "");
+    if let Some(backup) = write_rcfile(&rc_path, &contents)? {
+        println!("Backed up {} to {}", rc_path.display(), backup.display());
+    }
     println!(
-        "Installed envctl hook for {} at {}",
+        "Removed envctl hook for {} from {}",
         shell.as_str(),
         rc_path.display()
     );
+    Ok(())
+}
+
+/// Replaces an rc file without ever leaving it half-written: the new contents
+/// go to a temp file next to the real one, which is then renamed over it. A
+/// symlinked rc file (dotfile managers) is written through to its target, and
+/// the target keeps its permissions. Returns the timestamped backup made of
+/// the previous contents, if there were any.
+fn write_rcfile(rc_path: &Path, contents: &str) -> Result<Option<PathBuf>> {
+    let target = resolve_symlinks(rc_path)?;
+    let dir = match target.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    fs::create_dir_all(&dir)
+        .with_context(|| format!("creating rcfile directory {}", dir.display()))?;
+    let file_name = target
+        .file_name()
+        .ok_or_else(|| anyhow!("{} is not a file path", target.display()))?
+        .to_string_lossy()
+        .into_owned();
+
+    let existing = match fs::metadata(&target) {
+        Ok(meta) => Some(meta),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e).with_context(|| format!("reading {}", target.display())),
+    };
+    let backup = match &existing {
+        Some(_) => Some(backup_rcfile(&target, &dir, &file_name)?),
+        None => None,
+    };
+
+    replace_file(&target, contents, existing.map(|m| m.permissions()))
+        .with_context(|| format!("writing rcfile {}", target.display()))?;
+    Ok(backup)
+}
 
+/// Writes `contents` to a temp file in `target`'s directory and renames it
+/// over `target`, so readers see either the old or the new file.
+fn replace_file(target: &Path, contents: &str, perms: Option<fs::Permissions>) -> Result<()> {
+    let dir = match target.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let file_name = target
+        .file_name()
+        .ok_or_else(|| anyhow!("{} is not a file path", target.display()))?
+        .to_string_lossy();
+    let tmp = dir.join(format!(".{}.envctl-tmp.{}", file_name, std::process::id()));
+    let written = (|| -> Result<()> {
+        // Created with the final mode, so the contents are never readable
+        // by anyone the permissions exclude
+        let mode = perms.as_ref().map_or(0o666, |p| p.mode() & 0o777);
+        let mut f = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(mode)
+            .open(&tmp)?;
+        f.write_all(contents.as_bytes())?;
+        if let Some(perms) = perms {
+            f.set_permissions(perms)?;
+        }
+        f.sync_all()?;
+        fs::rename(&tmp, target)?;
+        Ok(())
+    })();
+    if written.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    written
+}
+
+fn backup_rcfile(target: &Path, dir: &Path, file_name: &str) -> Result<PathBuf> {
+    let stamp = format_utc(now_secs()).replace(['-', ':'], "");
+    let mut backup = dir.join(format!("{}.envctl-backup-{}", file_name, stamp));
+    let mut n = 1;
+    while backup.exists() {
+        backup = dir.join(format!("{}.envctl-backup-{}.{}", file_name, stamp, n));
+        n += 1;
+    }
+    fs::copy(target, &backup)
+        .with_context(|| format!("backing up {} to {}", target.display(), backup.display()))?;
+    Ok(backup)
+}
+
+/// Follows `path` through any symlinks (including dangling ones) to the
+/// file that should actually be written.
+fn resolve_symlinks(path: &Path) -> Result<PathBuf> {
+    let mut path = path.to_path_buf();
+    for _ in 0..40 {
+        match fs::symlink_metadata(&path) {
+            Ok(meta) if meta.file_type().is_symlink() => {
+                let link = fs::read_link(&path)
+                    .with_context(|| format!("reading symlink {}", path.display()))?;
+                path = match path.parent() {
+                    Some(parent) => parent.join(link),
+                    None => link,
+                };
+            }
+            _ => return Ok(path),
+        }
+    }
+    Err(anyhow!("too many levels of symlinks at {}", path.display()))
+}
+
+fn print_unified_diff(rc_path: &Path, old: &str, new: &str) {
+    let old: Vec<&str> = old.split_inclusive('\n').collect();
+    let new: Vec<&str> = new.split_inclusive('\n').collect();
+    let name = rc_path.display().to_string();
+    let diff = difflib::unified_diff(&old, &new, &name, &name, "", "", 3);
+    if diff.is_empty() {
+        println!("No changes to {}", name);
+        return;
+    }
+    for line in diff {
+        // Headers come with an empty tab-separated date we don't use
+        let line = line.replace("\t\n", "\n");
+        print!("{}", line);
+        if !line.ends_with('\n') {
+            println!();
+        }
+    }
+}
+
+/// Compares each rc file's block with what `install-hook` would write now.
+/// Fails if any installed hook is outdated.
+fn hook_status(shells: Vec<ShellType>, rcfile: Option<PathBuf>) -> Result<()> {
+    if rcfile.is_some() && shells.len() != 1 {
+        return Err(anyhow!("--rcfile needs exactly one SHELL"));
+    }
+    let shells = if shells.is_empty() {
+        ShellType::value_variants().to_vec()
+    } else {
+        shells
+    };
+
+    let mut outdated = 0;
+    for shell in shells {
+        let rc_path = match &rcfile {
+            Some(p) => p.clone(),
+            None => default_rc_path(shell)?,
+        };
+        let contents = read_rcfile(&rc_path)?.unwrap_or_default();
+        let status = match find_hook_block(&contents, &rc_path) {
+            Err(_) => "malformed",
+            Ok(None) => "missing",
+            Ok(Some(range)) => {
+                let found = &contents[range];
+                let current = [HookMode::Debug, HookMode::Prompt]
+                    .into_iter()
+                    .filter_map(|mode| hook_script(shell, mode).ok())
+                    .any(|body| hook_block(&body) == found.replace("\r\n", "\n"));
+                if current {
+                    "installed"
+                } else {
+                    "outdated"
+                }
+            }
+        };
+        if matches!(status, "outdated" | "malformed") {
+            outdated += 1;
+        }
+        println!("{:<7} {:<10} {}", shell.as_str(), status, rc_path.display());
+    }
+
+    if outdated > 0 {
+        return Err(anyhow!(
+            "{} hook(s) need attention; rerun `envctl install-hook <shell>`",
+            outdated
+        ));
+    }
     Ok(())
 }
 
@@ -304,29 +1872,77 @@ fn default_rc_path(shell: ShellType) -> Result<PathBuf> {
         ShellType::Bash => base.join(".bashrc"),
         ShellType::Zsh => base.join(".zshrc"),
         ShellType::Fish => base.join(".config").join("fish").join("config.fish"),
+        ShellType::Nu => xdg_config_home(&base).join("nushell").join("config.nu"),
+        // $PROFILE (CurrentUserCurrentHost) for pwsh on Linux
+        ShellType::PowerShell => xdg_config_home(&base)
+            .join("powershell")
+            .join("Microsoft.PowerShell_profile.ps1"),
+        // Interactive POSIX shells only read the file named by $ENV
+        ShellType::Posix => match std::env::var("ENV") {
+            Ok(env) if !env.is_empty() && !env.contains('$') => PathBuf::from(env),
+            _ => base.join(".shrc"),
+        },
+        ShellType::Tcsh => base.join(".tcshrc"),
+        ShellType::Elvish => xdg_config_home(&base).join("elvish").join("rc.elv"),
+        ShellType::Xonsh => base.join(".xonshrc"),
     };
     Ok(path)
 }
 
+fn xdg_config_home(home: &Path) -> PathBuf {
+    match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(p) if !p.is_empty() => PathBuf::from(p),
+        _ => home.join(".config"),
+    }
+}
+
 fn parse_kv(s: &str) -> Result<(String, String)> {
     if let Some(eq) = s.find('=') {
         let (k, v) = s.split_at(eq);
-        if k.is_empty() {
-            return Err(anyhow!("empty key"));
-        }
+        check_key(k)?;
         Ok((k.to_string(), v[1..].to_string()))
     } else {
         Err(anyhow!("expected KEY=VAL"))
     }
 }
 
+fn hook_script(shell: ShellType, mode: HookMode) -> Result<String> {
+    if mode == HookMode::Prompt && !matches!(shell, ShellType::Bash) {
+        return Err(anyhow!(
+            "--mode prompt is only supported for bash; {} hooks already run per prompt or command",
+            shell.as_str()
+        ));
+    }
+    Ok(match shell {
+        ShellType::Bash if mode == HookMode::Prompt => hook_bash_prompt(),
+        ShellType::Bash => hook_bash(),
+        ShellType::Zsh => hook_zsh(),
+        ShellType::Fish => hook_fish(),
+        ShellType::Nu => hook_nu(),
+        ShellType::PowerShell => hook_pwsh(),
+        ShellType::Posix => hook_sh(),
+        ShellType::Tcsh => hook_tcsh(),
+        ShellType::Elvish => hook_elvish(),
+        ShellType::Xonsh => hook_xonsh(),
+    })
+}
+
 fn hook_bash() -> String {
     r#"# envctl bash hook
-# Apply env diffs safely (idempotent, uses ENVCTL_GEN)
+# Apply env diffs safely (idempotent, uses ENVCTL_GEN). envd mirrors its
+# generation into a file, so when neither it nor $PWD changed we return
+# without forking.
 __envctl_apply() {
+  local gen=
+  if [[ "$PWD" == "${__envctl_last_pwd-}" ]] &&
+    read -r gen 2>/dev/null <"${XDG_RUNTIME_DIR:-/tmp}/cmux-envd/generation" &&
+    [[ "$gen" == "${ENVCTL_GEN-}" ]]; then
+    return
+  fi
   local out
   out="$(envctl export bash --since "${ENVCTL_GEN:-0}" --pwd "$PWD")" || return
   eval "$out"
+  __envctl_last_pwd=$PWD
 }
 
 # Capture existing DEBUG trap handler (if any) so we can chain it later
@@ -378,13 +1994,60 @@ __envctl_apply
     .to_string()
 }
 
+fn hook_bash_prompt() -> String {
+    r#"# envctl bash hook (prompt mode)
+# Applies changes before each prompt instead of from a DEBUG trap, which
+# leaves the trap to bash-preexec, starship, atuin and friends.
+__envctl_apply() {
+  local status=$? gen=
+  if [[ "$PWD" == "${__envctl_last_pwd-}" ]] &&
+    read -r gen 2>/dev/null <"${XDG_RUNTIME_DIR:-/tmp}/cmux-envd/generation" &&
+    [[ "$gen" == "${ENVCTL_GEN-}" ]]; then
+    return $status
+  fi
+  local out
+  if out="$(envctl export bash --since "${ENVCTL_GEN:-0}" --pwd "$PWD")"; then
+    eval "$out"
+    __envctl_last_pwd=$PWD
+  fi
+  # Later PROMPT_COMMAND entries (bash-preexec's precmd) still see $?
+  return $status
+}
+
+# Run first so the rest of the prompt sees fresh values. Bash 5.1+ allows
+# PROMPT_COMMAND to be an array; keep whichever form is already in use.
+if [[ "$(declare -p PROMPT_COMMAND 2>/dev/null)" == "declare -a"* ]]; then
+  if [[ " ${PROMPT_COMMAND[*]} " != *" __envctl_apply "* ]]; then
+    PROMPT_COMMAND=(__envctl_apply "${PROMPT_COMMAND[@]}")
+  fi
+else
+  case ";${PROMPT_COMMAND-};" in
+    *";__envctl_apply;"*) ;;
+    *) PROMPT_COMMAND="__envctl_apply${PROMPT_COMMAND:+;$PROMPT_COMMAND}" ;;
+  esac
+fi
+
+# Apply once at shell start
+__envctl_apply
+"#
+    .to_string()
+}
+
 fn hook_zsh() -> String {
     r#"# envctl zsh hook
 autoload -U add-zsh-hook
+# Skip the export when neither envd's generation file nor $PWD changed
 envctl_preexec() {
+  local gen=
+  if [[ "$PWD" == "${__envctl_last_pwd-}" ]] &&
+    read -r gen 2>/dev/null <"${XDG_RUNTIME_DIR:-/tmp}/cmux-envd/generation" &&
+    [[ "$gen" == "${ENVCTL_GEN-}" ]]; then
+    return
+  fi
   local out
   out="$(envctl export zsh --since "${ENVCTL_GEN:-0}" --pwd "$PWD")" || return
   eval "$out"
+  __envctl_last_pwd=$PWD
 }
 add-zsh-hook preexec envctl_preexec
 # Apply once at shell start
@@ -395,14 +2058,205 @@ envctl_preexec
 
 fn hook_fish() -> String {
     r#"# envctl fish hook
-function __envctl_preexec --on-event fish_preexec
+# Skip the export when neither envd's generation file nor $PWD changed
+function __envctl_apply
+  set -l gen_file /tmp/cmux-envd/generation
+  test -n "$XDG_RUNTIME_DIR"; and set gen_file $XDG_RUNTIME_DIR/cmux-envd/generation
+  if test "$PWD" = "$__envctl_last_pwd"; and test -r $gen_file
+    read -l gen <$gen_file
+    test "$gen" = "$ENVCTL_GEN"; and return
+  end
   envctl export fish --since "$ENVCTL_GEN" --pwd "$PWD" | source
+  and set -g __envctl_last_pwd $PWD
+end
+function __envctl_preexec --on-event fish_preexec
+  __envctl_apply
 end
 function __envctl_prompt --on-event fish_prompt
-  envctl export fish --since "$ENVCTL_GEN" --pwd "$PWD" | source
+  __envctl_apply
 end
 # Apply once at shell start
-envctl export fish --since "$ENVCTL_GEN" --pwd "$PWD" | source
+__envctl_apply
+"#
+    .to_string()
+}
+
+fn hook_nu() -> String {
+    r#"# envctl nu hook
+# `envctl export nu` prints a JSON record: {set: {..}, unset: [..], errors: [..]}
+# Skips the export when neither envd's generation file nor $PWD changed
+def --env __envctl_apply [] {
+  let gen_file = ($env.XDG_RUNTIME_DIR? | default "/tmp" | path join cmux-envd generation)
+  let gen = (try { open --raw $gen_file | str trim } catch { "" })
+  if $gen != "" and $gen == ($env.ENVCTL_GEN? | default "") and $env.PWD == ($env.__envctl_last_pwd? | default "") {
+    return
+  }
+  let diff = (^envctl export nu --since ($env.ENVCTL_GEN? | default "0") --pwd $env.PWD | from json)
+  for msg in $diff.errors { print -e $msg }
+  $diff.set | load-env
+  if ($diff.unset | length) > 0 {
+    hide-env --ignore-errors ...$diff.unset
+  }
+  $env.__envctl_last_pwd = $env.PWD
+}
+$env.config = ($env.config | upsert hooks.pre_execution (
+  ($env.config.hooks.pre_execution? | default []) | append {|| __envctl_apply }
+))
+$env.config = ($env.config | upsert hooks.env_change.PWD (
+  ($env.config.hooks.env_change.PWD? | default []) | append {|before, after| __envctl_apply }
+))
+# Apply once at shell start
+__envctl_apply
+"#
+    .to_string()
+}
+
+fn hook_pwsh() -> String {
+    r#"# envctl pwsh hook
+# Skips the export when neither envd's generation file nor $PWD changed
+function global:__envctl_apply {
+  $runtime = if ($env:XDG_RUNTIME_DIR) { $env:XDG_RUNTIME_DIR } else { '/tmp' }
+  $gen = Get-Content -Raw -ErrorAction SilentlyContinue (Join-Path $runtime 'cmux-envd/generation')
+  if ($gen -and $gen.Trim() -eq $env:ENVCTL_GEN -and $PWD.ProviderPath -eq $global:__envctl_last_pwd) {
+    return
+  }
+  $since = if ($env:ENVCTL_GEN) { $env:ENVCTL_GEN } else { '0' }
+  $out = & envctl export pwsh --since $since --pwd $PWD.ProviderPath
+  if ($LASTEXITCODE -eq 0 -and $out) {
+    Invoke-Expression ($out -join "`n")
+    $global:__envctl_last_pwd = $PWD.ProviderPath
+  }
+}
+# Wrap the existing prompt once; re-sourcing the profile must not nest it
+if (-not $global:__envctl_prev_prompt) {
+  $global:__envctl_prev_prompt = $function:prompt
+}
+function global:prompt {
+  __envctl_apply
+  & $global:__envctl_prev_prompt
+}
+# Apply once at shell start
+__envctl_apply
+"#
+    .to_string()
+}
+
+fn hook_sh() -> String {
+    r#"# envctl sh hook (POSIX sh, dash, ash); load it through $ENV
+# POSIX sh has no precmd hook, but PS1 is expanded before every prompt. Its
+# command substitution runs in a subshell, so it signals this shell instead
+# and the USR1 trap applies the diff here.
+# Skip the export when neither envd's generation file nor $PWD changed
+__envctl_apply() {
+  if [ "$PWD" = "${__envctl_last_pwd-}" ] &&
+    read -r __envctl_gen 2>/dev/null <"${XDG_RUNTIME_DIR:-/tmp}/cmux-envd/generation" &&
+    [ "$__envctl_gen" = "${ENVCTL_GEN-}" ]; then
+    return 0
+  fi
+  __envctl_out=$(envctl export sh --since "${ENVCTL_GEN:-0}" --pwd "$PWD") &&
+    eval "$__envctl_out" &&
+    __envctl_last_pwd=$PWD
+  unset __envctl_out
+}
+trap '__envctl_apply' USR1
+case $PS1 in
+  *'kill -USR1 $$'*) ;;
+  *) PS1='$(kill -USR1 $$ 2>/dev/null)'"${PS1-\$ }" ;;
+esac
+# Apply once at shell start
+__envctl_apply
+"#
+    .to_string()
+}
+
+fn hook_tcsh() -> String {
+    r#"# envctl tcsh hook
+# Backquote substitution would turn newlines in values into blanks, so each
+# export goes through a private temp file that is sourced, then removed.
+if ( ! $?ENVCTL_GEN ) setenv ENVCTL_GEN 0
+set __envctl_tmpdir = /tmp
+if ( $?TMPDIR ) set __envctl_tmpdir = $TMPDIR:q
+if ( $?XDG_RUNTIME_DIR ) set __envctl_tmpdir = $XDG_RUNTIME_DIR:q
+set __envctl_template = $__envctl_tmpdir:q/envctl-tcsh.XXXXXX
+unset __envctl_tmpdir
+# tcsh can't read a file without forking, but envd replaces its generation
+# file on every change, so its mtime and inode (file tests are builtins)
+# stand in for the generation. The export is skipped while they and $cwd
+# are as they were at the last one.
+set __envctl_gen_file = /tmp/cmux-envd/generation
+if ( $?XDG_RUNTIME_DIR ) set __envctl_gen_file = $XDG_RUNTIME_DIR:q/cmux-envd/generation
+set __envctl_last = ""
+alias __envctl_export 'set __envctl_tmp = "`mktemp $__envctl_template:q`" && envctl export tcsh --since "$ENVCTL_GEN" --pwd "$cwd" >! "$__envctl_tmp" && set __envctl_last = "$__envctl_stamp" && source "$__envctl_tmp"; rm -f "$__envctl_tmp"; unset __envctl_tmp'
+alias __envctl_apply '@ __envctl_m = 0; @ __envctl_i = 0; if ( -e $__envctl_gen_file:q ) @ __envctl_m = -M $__envctl_gen_file:q; if ( -e $__envctl_gen_file:q ) @ __envctl_i = -I $__envctl_gen_file:q; set __envctl_stamp = "$__envctl_m $__envctl_i $cwd"; if ( "$__envctl_stamp" != "$__envctl_last" ) eval __envctl_export'
+# Chain onto an existing precmd alias instead of replacing it. The old
+# alias is copied as a word, never pasted into quotes of our own.
+if ( ! $?__envctl_hooked ) then
+  set __envctl_hooked
+  set __envctl_prev_precmd = "`alias precmd`"
+  if ( $%__envctl_prev_precmd == 0 ) then
+    alias precmd '__envctl_apply'
+  else
+    alias __envctl_prev_precmd $__envctl_prev_precmd:q
+    alias precmd '__envctl_apply; __envctl_prev_precmd'
+  endif
+  unset __envctl_prev_precmd
+endif
+# Apply once at shell start
+__envctl_apply
+"#
+    .to_string()
+}
+
+fn hook_elvish() -> String {
+    r#"# envctl elvish hook
+var __envctl_last_pwd = ''
+# Skips the export when neither envd's generation file nor $pwd changed
+fn __envctl_apply {
+  var since = 0
+  if (has-env ENVCTL_GEN) { set since = $E:ENVCTL_GEN }
+  var runtime = /tmp
+  if (has-env XDG_RUNTIME_DIR) { set runtime = $E:XDG_RUNTIME_DIR }
+  var gen = ''
+  try { set gen = (slurp < $runtime/cmux-envd/generation) } catch { }
+  if (and (eq $pwd $__envctl_last_pwd) (eq $gen $since"\n")) {
+    return
+  }
+  # envctl reports its own errors on stderr; don't break the prompt
+  try {
+    eval (envctl export elvish --since $since --pwd $pwd | slurp)
+    set __envctl_last_pwd = $pwd
+  } catch { }
+}
+set edit:before-readline = [$@edit:before-readline $__envctl_apply~]
+# Apply once at shell start
+__envctl_apply
+"#
+    .to_string()
+}
+
+fn hook_xonsh() -> String {
+    r#"# envctl xonsh hook
+__envctl_last_pwd = [None]
+
+# Skips the export when neither envd's generation file nor $PWD changed
+@events.on_pre_prompt
+def __envctl_apply(**_):
+    __envctl_gen = None
+    __envctl_runtime = ${...}.get('XDG_RUNTIME_DIR') or '/tmp'
+    try:
+        with open(__envctl_runtime + '/cmux-envd/generation') as f:
+            __envctl_gen = f.read().strip()
+    except OSError:
+        pass
+    if __envctl_gen is not None and __envctl_gen == ${...}.get('ENVCTL_GEN') and $PWD == __envctl_last_pwd[0]:
+        return
+    __envctl_out = $(envctl export xonsh --since @(${...}.get('ENVCTL_GEN', '0')) --pwd @($PWD))
+    if __envctl_out:
+        execx(__envctl_out)
+        __envctl_last_pwd[0] = $PWD
+
+# Apply once at shell start
+__envctl_apply()
 "#
     .to_string()
 }
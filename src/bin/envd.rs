@@ -1,7 +1,23 @@
 use anyhow::Result;
-use cmux_env::run_server;
+use clap::Parser;
+use cmux_env::{read_passphrase, run_server, run_server_with, salt_path, Vault};
+
+#[derive(Parser, Debug)]
+#[command(name = "envd", version, about = "cmux environment daemon")]
+struct Cli {
+    /// Derive the secret key from a passphrase prompted at startup instead of
+    /// the keyfile (read from stdin if it is not a tty)
+    #[arg(long)]
+    passphrase: bool,
+}
 
 fn main() -> Result<()> {
+    let cli = Cli::parse();
+    if cli.passphrase {
+        let passphrase = read_passphrase("envd passphrase: ")?;
+        let vault = Vault::with_passphrase(salt_path(), &passphrase)?;
+        return run_server_with(vault);
+    }
     // Simple foreground server
     run_server()
 }
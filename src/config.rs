@@ -0,0 +1,52 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::acl::AclRule;
+use crate::audit::AuditConfig;
+
+/// Daemon settings read once at startup from `config_path()`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DaemonConfig {
+    #[serde(default)]
+    pub acl: Vec<AclRule>,
+    #[serde(default)]
+    pub audit: AuditConfig,
+    /// Uids that may set `--from-cmd`/`--from-file` references, which envd
+    /// runs as itself. Defaults to envd's own uid.
+    #[serde(default)]
+    pub provider_writers: Option<Vec<u32>>,
+}
+
+impl DaemonConfig {
+    /// Loads the config file; a missing file means defaults.
+    pub fn load() -> Result<Self> {
+        Self::load_from(&config_path())
+    }
+
+    pub fn load_from(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(DaemonConfig::default());
+        }
+        let text =
+            fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+        let config: DaemonConfig =
+            serde_json::from_str(&text).with_context(|| format!("parsing {}", path.display()))?;
+        for rule in &config.acl {
+            rule.validate()
+                .with_context(|| format!("invalid acl rule in {}", path.display()))?;
+        }
+        Ok(config)
+    }
+}
+
+pub fn config_path() -> PathBuf {
+    if let Some(p) = std::env::var_os("ENVD_CONFIG") {
+        if !p.is_empty() {
+            return PathBuf::from(p);
+        }
+    }
+    crate::config_dir().join("config.json")
+}
@@ -0,0 +1,510 @@
+use std::io::Read;
+
+use anyhow::{anyhow, Result};
+
+use crate::is_valid_key;
+
+/// Where and why a `.env` file failed to parse. Lines and columns count from
+/// 1, columns in characters.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("line {line}, column {column}: {message}")]
+pub struct DotenvError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+/// Parses `.env` content into `(key, value)` pairs in file order.
+///
+/// Follows what the common dotenv implementations agree on:
+/// - blank lines and lines starting with `#` are skipped, as is an `export `
+///   prefix; whitespace around `=` is ignored
+/// - single-quoted (and backtick-quoted) values are taken literally
+/// - double-quoted values understand `\n`, `\r`, `\t`, `\"`, `\'`, `\\` and
+///   `\$`; other backslashes are kept as written
+/// - quoted values may span several lines
+/// - unquoted values end at the line end or at a `#` preceded by whitespace,
+///   and have surrounding whitespace trimmed
+pub fn parse_dotenv<R: Read>(mut r: R) -> Result<Vec<(String, String)>> {
+    let mut s = String::new();
+    r.read_to_string(&mut s)?;
+    Ok(parse_dotenv_str(&s)?)
+}
+
+pub fn parse_dotenv_str(s: &str) -> Result<Vec<(String, String)>, DotenvError> {
+    Ok(parse_dotenv_entries(s)?
+        .into_iter()
+        .map(|e| {
+            let value = e.value();
+            (e.key, value)
+        })
+        .collect())
+}
+
+/// Parses a direnv `.envrc` the way [`parse_dotenv_str`] parses `.env`, but
+/// only accepts the part of shell syntax both read alike: `export KEY=VALUE`
+/// lines whose values are single-quoted, or double-quoted or unquoted without
+/// anything a shell would expand or unescape. Anything else is an error
+/// rather than a value that differs from what direnv would export.
+pub fn parse_envrc_str(s: &str) -> Result<Vec<(String, String)>, DotenvError> {
+    Ok(parse_entries(s, true)?
+        .into_iter()
+        .map(|e| {
+            let value = e.value();
+            (e.key, value)
+        })
+        .collect())
+}
+
+/// One `KEY=VALUE` line (or several, for a multi-line quoted value).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DotenvEntry {
+    pub key: String,
+    /// Line the entry starts on.
+    pub line: usize,
+    pieces: Vec<Piece>,
+}
+
+// Parts of a value, split by whether `$` references in them may be expanded:
+// single-quoted text and escaped characters never are.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Piece {
+    Literal(String),
+    Expandable(String),
+}
+
+impl DotenvEntry {
+    /// The value as written, with no references expanded.
+    pub fn value(&self) -> String {
+        self.pieces
+            .iter()
+            .map(|p| match p {
+                Piece::Literal(s) | Piece::Expandable(s) => s.as_str(),
+            })
+            .collect()
+    }
+}
+
+/// Parses `.env` content, keeping enough of how each value was written to
+/// expand references in it later with [`expand_dotenv`].
+pub fn parse_dotenv_entries(s: &str) -> Result<Vec<DotenvEntry>, DotenvError> {
+    parse_entries(s, false)
+}
+
+fn parse_entries(s: &str, envrc: bool) -> Result<Vec<DotenvEntry>, DotenvError> {
+    let s = s
+        .strip_prefix('\u{feff}')
+        .unwrap_or(s)
+        .replace("\r\n", "\n");
+    let mut p = Parser {
+        chars: s.chars().collect(),
+        pos: 0,
+        line: 1,
+        column: 1,
+        envrc,
+    };
+    let mut out = Vec::new();
+    loop {
+        p.skip_blanks();
+        match p.peek() {
+            None => break,
+            Some('\n') => {
+                p.bump();
+                continue;
+            }
+            Some('#') => {
+                p.skip_line();
+                continue;
+            }
+            Some(_) => {}
+        }
+        out.push(p.entry()?);
+    }
+    Ok(out)
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    line: usize,
+    column: usize,
+    /// Only accept what a shell would read the same way.
+    envrc: bool,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += 1;
+        if c == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+        Some(c)
+    }
+
+    fn error(&self, message: impl Into<String>) -> DotenvError {
+        self.error_at((self.line, self.column), message)
+    }
+
+    fn error_at(&self, (line, column): (usize, usize), message: impl Into<String>) -> DotenvError {
+        DotenvError {
+            line,
+            column,
+            message: message.into(),
+        }
+    }
+
+    fn skip_blanks(&mut self) -> bool {
+        let start = self.pos;
+        while matches!(self.peek(), Some(' ' | '\t')) {
+            self.bump();
+        }
+        self.pos > start
+    }
+
+    // Skips the rest of the line, including its newline
+    fn skip_line(&mut self) {
+        while let Some(c) = self.bump() {
+            if c == '\n' {
+                break;
+            }
+        }
+    }
+
+    fn word(&mut self) -> String {
+        let mut word = String::new();
+        while let Some(c) = self.peek() {
+            if matches!(c, ' ' | '\t' | '\n' | '=') {
+                break;
+            }
+            word.push(c);
+            self.bump();
+        }
+        word
+    }
+
+    // One `KEY=VALUE` entry, starting at the key and consuming its line end
+    fn entry(&mut self) -> Result<DotenvEntry, DotenvError> {
+        let line = self.line;
+        let mut at = (self.line, self.column);
+        let mut key = self.word();
+        let mut exported = false;
+        if key == "export" && self.skip_blanks() && self.peek() != Some('=') {
+            at = (self.line, self.column);
+            key = self.word();
+            exported = true;
+        }
+        if self.envrc && !exported {
+            return Err(self.error_at(at, "only `export KEY=VALUE` lines are supported"));
+        }
+        if !is_valid_key(&key) {
+            return Err(self.error_at(at, format!("invalid key {:?}", key)));
+        }
+        if self.skip_blanks() && self.envrc {
+            return Err(self.error("no spaces allowed around '='"));
+        }
+        if self.peek() != Some('=') {
+            return Err(self.error(format!("expected '=' after {}", key)));
+        }
+        self.bump();
+        let spaced = self.skip_blanks();
+        if self.envrc && spaced {
+            return Err(self.error("no spaces allowed around '='"));
+        }
+        let value_at = (self.line, self.column);
+        let start = self.pos;
+        let pieces = match self.peek() {
+            Some('`') if self.envrc => {
+                return Err(self.error("command substitution is not supported"))
+            }
+            Some(q @ ('\'' | '`')) => vec![Piece::Literal(self.literal(q)?)],
+            Some('"') => {
+                let pieces = self.double_quoted()?;
+                if self.envrc
+                    && self.chars[start..self.pos]
+                        .iter()
+                        .any(|c| "$`\\".contains(*c))
+                {
+                    return Err(
+                        self.error_at(value_at, "$, ` and \\ are not supported in .envrc values")
+                    );
+                }
+                pieces
+            }
+            _ => {
+                let value = self.unquoted(spaced);
+                if self.envrc && !value.chars().all(is_plain_word_char) {
+                    return Err(self.error_at(
+                        value_at,
+                        "quote values containing spaces or shell special characters",
+                    ));
+                }
+                return Ok(DotenvEntry {
+                    key,
+                    line,
+                    pieces: vec![Piece::Expandable(value)],
+                });
+            }
+        };
+        self.skip_blanks();
+        match self.peek() {
+            None | Some('\n') => {
+                self.bump();
+            }
+            Some('#') => self.skip_line(),
+            Some(c) => {
+                return Err(self.error(format!("unexpected {:?} after closing quote", c)));
+            }
+        }
+        Ok(DotenvEntry { key, line, pieces })
+    }
+
+    fn literal(&mut self, quote: char) -> Result<String, DotenvError> {
+        let open = (self.line, self.column);
+        self.bump();
+        let mut value = String::new();
+        loop {
+            match self.bump() {
+                Some(c) if c == quote => return Ok(value),
+                Some(c) => value.push(c),
+                None => return Err(self.error_at(open, format!("unterminated {} quote", quote))),
+            }
+        }
+    }
+
+    fn double_quoted(&mut self) -> Result<Vec<Piece>, DotenvError> {
+        let open = (self.line, self.column);
+        self.bump();
+        let mut pieces = Vec::new();
+        let mut text = String::new();
+        loop {
+            match self.bump() {
+                Some('"') => break,
+                // An escaped `$` or `\` must not start or escape a reference
+                Some('\\') if matches!(self.peek(), Some('$' | '\\')) => {
+                    if !text.is_empty() {
+                        pieces.push(Piece::Expandable(std::mem::take(&mut text)));
+                    }
+                    pieces.push(Piece::Literal(self.bump().unwrap_or_default().to_string()));
+                }
+                Some('\\') => match self.peek() {
+                    Some(c @ ('n' | 'r' | 't' | '"' | '\'')) => {
+                        self.bump();
+                        text.push(match c {
+                            'n' => '\n',
+                            'r' => '\r',
+                            't' => '\t',
+                            other => other,
+                        });
+                    }
+                    _ => text.push('\\'),
+                },
+                Some(c) => text.push(c),
+                None => return Err(self.error_at(open, "unterminated \" quote")),
+            }
+        }
+        if !text.is_empty() || pieces.is_empty() {
+            pieces.push(Piece::Expandable(text));
+        }
+        Ok(pieces)
+    }
+
+    // Rest of the line, up to a comment. `spaced` says whether whitespace
+    // came right before the value, which makes a leading `#` a comment.
+    fn unquoted(&mut self, spaced: bool) -> String {
+        let mut value = String::new();
+        let mut after_blank = spaced;
+        while let Some(c) = self.bump() {
+            match c {
+                '\n' => break,
+                '#' if after_blank => {
+                    self.skip_line();
+                    break;
+                }
+                _ => {
+                    after_blank = matches!(c, ' ' | '\t');
+                    value.push(c);
+                }
+            }
+        }
+        value.truncate(value.trim_end().len());
+        value
+    }
+}
+
+// Characters a shell reads literally in an unquoted word
+fn is_plain_word_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "_./:,@%+=-#".contains(c)
+}
+
+/// Expands `$VAR`, `${VAR}`, `${VAR:-default}` and `${VAR:?message}` (and
+/// the `-`/`?` forms, which only test for unset) in the values of `entries`.
+///
+/// A reference resolves to the value of an earlier key in the same file, or
+/// else whatever `lookup` returns. Unresolved references and failed `?` tests
+/// are collected and reported together in the error.
+pub fn expand_dotenv(
+    entries: Vec<DotenvEntry>,
+    mut lookup: impl FnMut(&str) -> Result<Option<String>>,
+) -> Result<Vec<(String, String)>> {
+    let mut out: Vec<(String, String)> = Vec::with_capacity(entries.len());
+    let mut unresolved = Vec::new();
+    let mut failed = Vec::new();
+    for entry in entries {
+        let mut resolve = |name: &str| match out.iter().rev().find(|(k, _)| k == name) {
+            Some((_, v)) => Ok(Some(v.clone())),
+            None => lookup(name),
+        };
+        let mut ex = Expander {
+            line: entry.line,
+            resolve: &mut resolve,
+            unresolved: &mut unresolved,
+            failed: &mut failed,
+        };
+        let mut value = String::new();
+        for piece in &entry.pieces {
+            match piece {
+                Piece::Literal(s) => value.push_str(s),
+                Piece::Expandable(s) => value.push_str(&ex.expand(s)?),
+            }
+        }
+        out.push((entry.key, value));
+    }
+    if !unresolved.is_empty() {
+        failed.push(format!("unresolved references: {}", unresolved.join(", ")));
+    }
+    if !failed.is_empty() {
+        return Err(anyhow!("{}", failed.join("; ")));
+    }
+    Ok(out)
+}
+
+struct Expander<'a> {
+    line: usize,
+    resolve: &'a mut dyn FnMut(&str) -> Result<Option<String>>,
+    unresolved: &'a mut Vec<String>,
+    failed: &'a mut Vec<String>,
+}
+
+impl Expander<'_> {
+    fn expand(&mut self, text: &str) -> Result<String> {
+        let chars: Vec<char> = text.chars().collect();
+        let mut out = String::new();
+        let mut i = 0;
+        while i < chars.len() {
+            match (chars[i], chars.get(i + 1)) {
+                ('\\', Some('$')) => {
+                    out.push('$');
+                    i += 2;
+                }
+                ('$', Some('{')) => {
+                    let end = matching_brace(&chars, i + 2)
+                        .ok_or_else(|| anyhow!("line {}: missing '}}' after '${{'", self.line))?;
+                    let inner: String = chars[i + 2..end].iter().collect();
+                    out.push_str(&self.braced(&inner)?);
+                    i = end + 1;
+                }
+                ('$', Some(&c)) if c == '_' || c.is_ascii_alphabetic() => {
+                    let len = chars[i + 1..]
+                        .iter()
+                        .take_while(|c| **c == '_' || c.is_ascii_alphanumeric())
+                        .count();
+                    let name: String = chars[i + 1..i + 1 + len].iter().collect();
+                    out.push_str(&self.lookup(&name)?);
+                    i += 1 + len;
+                }
+                (c, _) => {
+                    out.push(c);
+                    i += 1;
+                }
+            }
+        }
+        Ok(out)
+    }
+
+    // The inside of `${...}`
+    fn braced(&mut self, inner: &str) -> Result<String> {
+        let name_len = inner
+            .find(|c: char| c != '_' && !c.is_ascii_alphanumeric())
+            .unwrap_or(inner.len());
+        let (name, rest) = inner.split_at(name_len);
+        if !is_valid_key(name) {
+            return Err(anyhow!(
+                "line {}: bad substitution '${{{}}}'",
+                self.line,
+                inner
+            ));
+        }
+        let (colon, rest) = match rest.strip_prefix(':') {
+            Some(rest) => (true, rest),
+            None => (false, rest),
+        };
+        let (op, word) = match rest.chars().next() {
+            None if !colon => return self.lookup(name),
+            Some(op @ ('-' | '?')) => (op, &rest[1..]),
+            _ => {
+                return Err(anyhow!(
+                    "line {}: bad substitution '${{{}}}'",
+                    self.line,
+                    inner
+                ))
+            }
+        };
+        let value = (self.resolve)(name)?.filter(|v| !(colon && v.is_empty()));
+        match (value, op) {
+            (Some(v), _) => Ok(v),
+            (None, '-') => self.expand(word),
+            (None, _) => {
+                let message = self.expand(word)?;
+                self.failed.push(if message.is_empty() {
+                    format!("line {}: {} is not set", self.line, name)
+                } else {
+                    format!("line {}: {}: {}", self.line, name, message)
+                });
+                Ok(String::new())
+            }
+        }
+    }
+
+    fn lookup(&mut self, name: &str) -> Result<String> {
+        match (self.resolve)(name)? {
+            Some(v) => Ok(v),
+            None => {
+                self.unresolved
+                    .push(format!("{} (line {})", name, self.line));
+                Ok(String::new())
+            }
+        }
+    }
+}
+
+// Index of the `}` closing a `${` whose contents start at `start`, allowing
+// nested `${...}` in default values
+fn matching_brace(chars: &[char], start: usize) -> Option<usize> {
+    let mut depth = 1;
+    let mut i = start;
+    while i < chars.len() {
+        match chars[i] {
+            '$' if chars.get(i + 1) == Some(&'{') => {
+                depth += 1;
+                i += 1;
+            }
+            '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    None
+}
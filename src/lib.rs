@@ -3,16 +3,39 @@ use base64::engine::general_purpose::STANDARD as BASE64_STANDARD;
 use base64::Engine;
 use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs;
-use std::io::{BufRead, BufReader, Cursor, Read, Write};
+use std::io::{BufRead, BufReader, Cursor, IsTerminal, Write};
 use std::os::unix::net::{UnixListener, UnixStream};
 use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::thread;
 use std::time::{Duration, Instant};
 
+mod acl;
+mod audit;
+mod autoload;
+mod config;
+mod dotenv;
+mod providers;
+mod secrets;
+mod watch;
+
+pub use acl::{Acl, AclRule, PeerCred};
+pub use audit::{audit_log_path, now_secs, AuditConfig, AuditEntry, AuditLog, Caller};
+pub use autoload::{trust_path, Trust, TrustStore, AUTOLOAD_FILES};
+pub use config::{config_path, DaemonConfig};
+pub use dotenv::{
+    expand_dotenv, parse_dotenv, parse_dotenv_entries, parse_dotenv_str, parse_envrc_str,
+    DotenvEntry, DotenvError,
+};
+pub use providers::{CommandProvider, FileProvider, Provider, ProviderCache, ProviderRef};
+pub use secrets::{KeySource, Sealed, Vault};
+
+use autoload::{Autoloaded, FileSnapshot};
+
 // ---------------- Path helpers ----------------
 
 pub fn runtime_dir() -> PathBuf {
@@ -29,6 +52,60 @@ pub fn socket_path() -> PathBuf {
     base.join("envd.sock")
 }
 
+/// Holds the daemon's current generation so shell hooks can skip calling
+/// `envctl export` when nothing changed, reading it with shell builtins.
+pub fn generation_path() -> PathBuf {
+    runtime_dir().join("cmux-envd").join("generation")
+}
+
+pub fn config_dir() -> PathBuf {
+    if let Ok(p) = std::env::var("XDG_CONFIG_HOME") {
+        if !p.is_empty() {
+            return PathBuf::from(p).join("cmux-envd");
+        }
+    }
+    if let Ok(home) = std::env::var("HOME") {
+        if !home.is_empty() {
+            return PathBuf::from(home).join(".config").join("cmux-envd");
+        }
+    }
+    runtime_dir().join("cmux-envd")
+}
+
+pub fn state_dir() -> PathBuf {
+    if let Ok(p) = std::env::var("XDG_STATE_HOME") {
+        if !p.is_empty() {
+            return PathBuf::from(p).join("cmux-envd");
+        }
+    }
+    if let Ok(home) = std::env::var("HOME") {
+        if !home.is_empty() {
+            return PathBuf::from(home)
+                .join(".local")
+                .join("state")
+                .join("cmux-envd");
+        }
+    }
+    runtime_dir().join("cmux-envd")
+}
+
+pub fn keyfile_path() -> PathBuf {
+    secrets::keyfile_path()
+}
+
+/// The dotenv file holding profile `name`, which `envctl exec --profile`
+/// applies on top of envd's values.
+pub fn profile_path(name: &str) -> Result<PathBuf> {
+    if name.is_empty() || name.starts_with('.') || name.contains('/') {
+        return Err(anyhow!("invalid profile name {:?}", name));
+    }
+    Ok(config_dir().join("profiles").join(format!("{}.env", name)))
+}
+
+pub fn salt_path() -> PathBuf {
+    secrets::salt_path()
+}
+
 fn ensure_socket_dir() -> Result<PathBuf> {
     let dir = runtime_dir().join("cmux-envd");
     fs::create_dir_all(&dir).with_context(|| format!("creating dir {}", dir.display()))?;
@@ -42,6 +119,16 @@ fn write_pid_file(dir: &Path) -> Result<()> {
     Ok(())
 }
 
+// Written to a temp file and renamed so a hook never reads a partial number.
+fn write_generation_file(generation: u64) -> Result<()> {
+    let path = generation_path();
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, format!("{}\n", generation))
+        .with_context(|| format!("writing {}", tmp.display()))?;
+    fs::rename(&tmp, &path).with_context(|| format!("renaming to {}", path.display()))?;
+    Ok(())
+}
+
 // ---------------- Protocol ----------------
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
@@ -50,6 +137,12 @@ pub enum ShellKind {
     Bash,
     Zsh,
     Fish,
+    Nu,
+    PowerShell,
+    Posix,
+    Tcsh,
+    Elvish,
+    Xonsh,
 }
 
 impl ShellKind {}
@@ -70,6 +163,13 @@ pub enum Request {
         key: String,
         value: String,
         scope: Scope,
+        #[serde(default)]
+        secret: bool,
+    },
+    SetRef {
+        key: String,
+        provider: ProviderRef,
+        scope: Scope,
     },
     Unset {
         key: String,
@@ -85,12 +185,57 @@ pub enum Request {
     Load {
         entries: Vec<(String, String)>,
         scope: Scope,
+        #[serde(default)]
+        secret: bool,
     },
     Export {
         shell: ShellKind,
         since: u64,
         pwd: PathBuf,
     },
+    /// Like `Export`, but answered with `Response::ExportDiff` instead of a
+    /// script, for programs that apply the changes themselves.
+    ExportDiff {
+        since: u64,
+        pwd: PathBuf,
+    },
+    Lock,
+    Unlock {
+        passphrase: Option<String>,
+    },
+    Log {
+        key: Option<String>,
+        limit: Option<usize>,
+    },
+    History {
+        key: String,
+        scope: Option<Scope>,
+    },
+    Rollback {
+        to_gen: u64,
+        key: Option<String>,
+        scope: Option<Scope>,
+    },
+    Scopes,
+    /// Approve the `.env`/`.envrc` files at `path` (a directory or one of the
+    /// files) as they are now, so exports from there load them.
+    Allow {
+        path: PathBuf,
+    },
+    /// Stop loading the files at `path` until they change or are allowed.
+    Deny {
+        path: PathBuf,
+    },
+    /// Make `file` the source of truth for `dir`'s scope: apply it now and
+    /// again whenever it changes.
+    Link {
+        file: PathBuf,
+        dir: PathBuf,
+    },
+    /// Stop following the file linked to `dir`, keeping the scope as it is.
+    Unlink {
+        dir: PathBuf,
+    },
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -101,6 +246,10 @@ pub enum Response {
         generation: u64,
         globals: usize,
         scopes: usize,
+        #[serde(default)]
+        secrets: usize,
+        #[serde(default)]
+        locked: bool,
     },
     Ok,
     Value {
@@ -108,16 +257,84 @@ pub enum Response {
     },
     Map {
         entries: HashMap<String, String>,
+        /// Keys in `entries` whose effective value is stored as a secret, sorted.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        secrets: Vec<String>,
+        /// Keys in `entries` whose effective value comes from a provider, sorted.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        refs: Vec<String>,
     },
     Export {
         script: String,
         new_generation: u64,
     },
+    ExportDiff {
+        #[serde(flatten)]
+        diff: ExportDiff,
+    },
+    AuditLog {
+        entries: Vec<AuditEntry>,
+    },
+    History {
+        events: Vec<ChangeEvent>,
+    },
+    RolledBack {
+        changes: usize,
+        new_generation: u64,
+    },
+    Scopes {
+        scopes: Vec<ScopeSummary>,
+    },
+    /// Result of `Allow`/`Deny`: the files decided on, and problems found
+    /// while reloading their directory.
+    Autoload {
+        files: Vec<PathBuf>,
+        notices: Vec<String>,
+    },
+    Linked {
+        file: PathBuf,
+        dir: PathBuf,
+        /// Keys set or unset by applying the file.
+        changes: usize,
+        new_generation: u64,
+    },
+    Unlinked {
+        file: PathBuf,
+        dir: PathBuf,
+    },
     Error {
         message: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        code: Option<ErrorCode>,
     },
 }
 
+/// Changes a client applies to catch up to `generation`: values to set, keys
+/// to unset, and provider failures (whose keys appear in neither).
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExportDiff {
+    pub set: BTreeMap<String, String>,
+    pub unset: Vec<String>,
+    #[serde(default)]
+    pub errors: Vec<String>,
+    pub generation: u64,
+}
+
+/// A directory scope and how many variables it holds, for `envctl scopes`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ScopeSummary {
+    pub path: PathBuf,
+    pub keys: usize,
+    pub secrets: usize,
+}
+
+/// Machine-readable error categories for clients that need to tell failures apart.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    PermissionDenied,
+}
+
 fn read_json(stream: &mut UnixStream) -> Result<Request> {
     let mut reader = BufReader::new(stream);
     let mut line = String::new();
@@ -143,24 +360,90 @@ pub struct ChangeEvent {
     pub generation: u64,
     pub key: String,
     pub scope: Scope,
+    /// Value after the change; `None` when the key was unset.
+    pub value: Option<StoredValue>,
+    /// Seconds since the Unix epoch.
+    pub timestamp: u64,
+}
+
+/// A stored variable value. Secrets only ever exist sealed, so anything that
+/// serializes state (or a core dump of it) never sees their plaintext.
+/// References are resolved through a provider each time they are read.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(tag = "type", content = "value")]
+pub enum StoredValue {
+    Plain(String),
+    Secret(Sealed),
+    Ref(ProviderRef),
+}
+
+impl StoredValue {
+    pub fn is_secret(&self) -> bool {
+        matches!(self, StoredValue::Secret(_))
+    }
+
+    pub fn is_ref(&self) -> bool {
+        matches!(self, StoredValue::Ref(_))
+    }
 }
 
 #[derive(Debug, Default)]
 pub struct State {
     pub generation: u64,
-    pub globals: HashMap<String, String>,
-    pub scoped: HashMap<PathBuf, HashMap<String, String>>, // Dir -> (key -> value)
+    pub globals: HashMap<String, StoredValue>,
+    pub scoped: HashMap<PathBuf, HashMap<String, StoredValue>>, // Dir -> (key -> value)
     pub history: Vec<ChangeEvent>,
+    pub vault: Vault,
+    pub providers: Arc<ProviderCache>,
+    pub trust: TrustStore,
+    autoloaded: HashMap<PathBuf, Autoloaded>,
+    /// Files `envctl link` made the source of truth, by the dir they apply to.
+    linked: HashMap<PathBuf, PathBuf>,
 }
 
 impl State {
+    pub fn with_vault(vault: Vault) -> Self {
+        State {
+            vault,
+            ..State::default()
+        }
+    }
+
     pub fn set(&mut self, scope: Scope, key: String, value: String) -> bool {
+        self.set_stored(scope, key, StoredValue::Plain(value))
+    }
+
+    /// Seals `value` with the vault key before storing it. Fails while locked.
+    pub fn set_secret(&mut self, scope: Scope, key: String, value: String) -> Result<bool> {
+        if let Some(StoredValue::Secret(existing)) = self.stored(&scope, &key) {
+            if self.vault.open(existing).ok().as_deref() == Some(value.as_str()) {
+                return Ok(false);
+            }
+        }
+        let sealed = self.vault.seal(&value)?;
+        Ok(self.set_stored(scope, key, StoredValue::Secret(sealed)))
+    }
+
+    /// Stores a reference that is resolved by its provider whenever it is read.
+    pub fn set_ref(&mut self, scope: Scope, key: String, provider: ProviderRef) -> bool {
+        self.providers.invalidate(&provider);
+        self.set_stored(scope, key, StoredValue::Ref(provider))
+    }
+
+    pub(crate) fn stored(&self, scope: &Scope, key: &str) -> Option<&StoredValue> {
+        match scope {
+            Scope::Global => self.globals.get(key),
+            Scope::Dir(path) => self.scoped.get(&canon(path)).and_then(|m| m.get(key)),
+        }
+    }
+
+    fn set_stored(&mut self, scope: Scope, key: String, value: StoredValue) -> bool {
         match scope {
             Scope::Global => {
                 let changed = self.globals.get(&key) != Some(&value);
                 if changed {
-                    self.globals.insert(key.clone(), value);
-                    self.bump(key, Scope::Global);
+                    self.globals.insert(key.clone(), value.clone());
+                    self.bump(key, Scope::Global, Some(value));
                 }
                 changed
             }
@@ -169,8 +452,8 @@ impl State {
                 let entry = self.scoped.entry(path_c.clone()).or_default();
                 let changed = entry.get(&key) != Some(&value);
                 if changed {
-                    entry.insert(key.clone(), value);
-                    self.bump(key, Scope::Dir(path_c));
+                    entry.insert(key.clone(), value.clone());
+                    self.bump(key, Scope::Dir(path_c), Some(value));
                 }
                 changed
             }
@@ -182,7 +465,7 @@ impl State {
             Scope::Global => {
                 let existed = self.globals.remove(&key).is_some();
                 if existed {
-                    self.bump(key, Scope::Global);
+                    self.bump(key, Scope::Global, None);
                 }
                 existed
             }
@@ -191,7 +474,7 @@ impl State {
                 if let Some(map) = self.scoped.get_mut(&path) {
                     let existed = map.remove(&key).is_some();
                     if existed {
-                        self.bump(key, Scope::Dir(path));
+                        self.bump(key, Scope::Dir(path), None);
                     }
                     existed
                 } else {
@@ -201,7 +484,7 @@ impl State {
         }
     }
 
-    fn bump(&mut self, key: String, scope: Scope) {
+    fn bump(&mut self, key: String, scope: Scope, value: Option<StoredValue>) {
         self.generation += 1;
         // normalize dir scope to canonical form
         let scope = match scope {
@@ -212,38 +495,469 @@ impl State {
             generation: self.generation,
             key,
             scope,
+            value,
+            timestamp: audit::now_secs(),
         });
     }
 
+    /// Past values of `key`, oldest first, optionally limited to one scope.
+    pub fn key_history(&self, key: &str, scope: Option<&Scope>) -> Vec<ChangeEvent> {
+        let scope = scope.map(canon_scope);
+        self.history
+            .iter()
+            .filter(|ev| ev.key == key && scope.as_ref().map(|s| *s == ev.scope).unwrap_or(true))
+            .cloned()
+            .collect()
+    }
+
+    /// Changes needed to bring keys back to their values as of generation
+    /// `to_gen`, optionally only for one key and/or scope. Each entry is the
+    /// target value, `None` meaning the key did not exist then.
+    pub fn rollback_plan(
+        &self,
+        to_gen: u64,
+        key: Option<&str>,
+        scope: Option<&Scope>,
+    ) -> Result<Vec<(Scope, String, Option<StoredValue>)>> {
+        if to_gen > self.generation {
+            return Err(anyhow!(
+                "generation {} is in the future (current is {})",
+                to_gen,
+                self.generation
+            ));
+        }
+        let scope = scope.map(canon_scope);
+        let mut targets: HashMap<(Scope, String), Option<StoredValue>> = HashMap::new();
+        for ev in &self.history {
+            if key.map(|k| k != ev.key).unwrap_or(false)
+                || scope.as_ref().map(|s| *s != ev.scope).unwrap_or(false)
+            {
+                continue;
+            }
+            let target = targets
+                .entry((ev.scope.clone(), ev.key.clone()))
+                .or_default();
+            if ev.generation <= to_gen {
+                *target = ev.value.clone();
+            }
+        }
+        let mut plan: Vec<(Scope, String, Option<StoredValue>)> = targets
+            .into_iter()
+            .filter(|((scope, key), target)| self.stored(scope, key) != target.as_ref())
+            .map(|((scope, key), target)| (scope, key, target))
+            .collect();
+        plan.sort_by(|a, b| (&a.1, scope_sort_key(&a.0)).cmp(&(&b.1, scope_sort_key(&b.0))));
+        Ok(plan)
+    }
+
+    /// Applies `rollback_plan` as ordinary changes, so shells pick the
+    /// restored values up through `export_since`. Returns the number of keys changed.
+    pub fn rollback(
+        &mut self,
+        to_gen: u64,
+        key: Option<&str>,
+        scope: Option<&Scope>,
+    ) -> Result<usize> {
+        let plan = self.rollback_plan(to_gen, key, scope)?;
+        let count = plan.len();
+        for (scope, key, target) in plan {
+            match target {
+                Some(value) => {
+                    self.set_stored(scope, key, value);
+                }
+                None => {
+                    self.unset(scope, key);
+                }
+            }
+        }
+        Ok(count)
+    }
+
     pub fn load(&mut self, scope: Scope, entries: Vec<(String, String)>) {
         for (k, v) in entries {
             self.set(scope.clone(), k, v);
         }
     }
 
-    pub fn effective_for_pwd(&self, pwd: &Path) -> HashMap<String, String> {
-        let mut map = self.globals.clone();
+    /// Like `load`, but seals every value. Nothing is stored if sealing fails.
+    pub fn load_secret(&mut self, scope: Scope, entries: Vec<(String, String)>) -> Result<()> {
+        let mut sealed = Vec::with_capacity(entries.len());
+        for (k, v) in entries {
+            sealed.push((k, self.vault.seal(&v)?));
+        }
+        for (k, v) in sealed {
+            self.set_stored(scope.clone(), k, StoredValue::Secret(v));
+        }
+        Ok(())
+    }
+
+    /// Loads the allowed autoload files of `pwd` and each of its ancestors
+    /// into their directory's scope, reloading files that changed. `guard`
+    /// sees the keys about to change in each directory first; if it fails,
+    /// that directory is left as it was. Returns notices for the user about
+    /// files that are not allowed, don't parse or were refused by `guard`.
+    pub fn autoload(&mut self, pwd: &Path, guard: &mut AutoloadGuard) -> Vec<String> {
+        let pwd = canon(pwd);
+        let mut notices = Vec::new();
+        for dir in pwd.ancestors() {
+            notices.extend(self.sync_autoload_dir(dir, guard));
+        }
+        notices
+    }
+
+    /// Reloads the autoload files of `dir` alone, like `autoload` does.
+    pub fn autoload_dir(&mut self, dir: &Path, guard: &mut AutoloadGuard) -> Vec<String> {
+        self.sync_autoload_dir(&canon(dir), guard)
+    }
+
+    /// Directories autoload files were found in.
+    pub fn autoload_dirs(&self) -> impl Iterator<Item = &PathBuf> {
+        self.autoloaded.keys()
+    }
+
+    /// Whether `dir`'s autoload files were edited, created or removed since
+    /// they were last loaded.
+    pub fn autoload_stale(&self, dir: &Path) -> bool {
+        let Some(loaded) = self.autoloaded.get(dir) else {
+            return false;
+        };
+        AUTOLOAD_FILES.iter().any(|name| {
+            let path = dir.join(name);
+            let previous = loaded.files.get(&path);
+            match FileSnapshot::read(&path, previous) {
+                Ok(Some(now)) => previous.map(|p| p.hash != now.hash).unwrap_or(true),
+                Ok(None) => previous.is_some(),
+                Err(_) => true,
+            }
+        })
+    }
+
+    // Bumps the generation without changing anything, so shell hooks export
+    // again and pick up whatever that export loads.
+    fn nudge(&mut self) {
+        self.generation += 1;
+    }
+
+    /// Allows or denies the autoload files at `path` (a directory, or one of
+    /// its files) as they are now, then reloads that directory's scope.
+    /// Returns the files decided on and any notices from the reload.
+    pub fn decide_autoload(
+        &mut self,
+        path: &Path,
+        allowed: bool,
+    ) -> Result<(Vec<PathBuf>, Vec<String>)> {
+        let (dir, files) = autoload::autoload_targets(path)?;
+        for file in &files {
+            let contents =
+                fs::read_to_string(file).with_context(|| format!("reading {}", file.display()))?;
+            self.trust
+                .decide(file.clone(), autoload::content_hash(&contents), allowed)?;
+        }
+        // Re-read the files rather than trust a cached copy with the same mtime
+        if let Some(loaded) = self.autoloaded.get_mut(&dir) {
+            loaded.files.clear();
+        }
+        let mut notices = self.sync_autoload_dir(&dir, &mut |_, _| Ok(()));
+        if let Some(linked) = self.linked.get(&dir) {
+            notices.push(format!(
+                "{} is linked to {}, which takes precedence until `envctl unlink`",
+                dir.display(),
+                linked.display()
+            ));
+        }
+        Ok((files, notices))
+    }
+
+    // Brings `dir`'s scope in line with its autoload files: keys whose value in
+    // the files changed are set, keys no longer loaded from them are unset.
+    // Keys the files never held, or that haven't changed there, are left
+    // alone, so manual `set`/`unset` in the scope stick until the file changes.
+    fn sync_autoload_dir(&mut self, dir: &Path, guard: &mut AutoloadGuard) -> Vec<String> {
+        // A linked file owns the whole scope
+        if self.linked.contains_key(dir) {
+            return Vec::new();
+        }
+        let previous = self.autoloaded.remove(dir);
+        if previous.is_none() && AUTOLOAD_FILES.iter().all(|f| !dir.join(f).exists()) {
+            return Vec::new();
+        }
+        let previous = previous.unwrap_or_default();
+        let mut notices = Vec::new();
+        let mut loaded = Autoloaded::default();
+        for name in AUTOLOAD_FILES {
+            let path = dir.join(name);
+            let snapshot = match FileSnapshot::read(&path, previous.files.get(&path)) {
+                Ok(Some(snapshot)) => snapshot,
+                Ok(None) => continue,
+                Err(e) => {
+                    notices.push(format!("{:#}", e));
+                    continue;
+                }
+            };
+            match self.trust.check(&path, &snapshot.hash) {
+                Trust::Allowed => match parse_autoload_file(&path, &snapshot.contents) {
+                    Ok(entries) => loaded.values.extend(entries),
+                    Err(e) => notices.push(format!("{}: {}", path.display(), e)),
+                },
+                Trust::Denied => {}
+                Trust::Unknown => notices.push(format!(
+                    "{} is not allowed; run `envctl allow {}` to load it",
+                    path.display(),
+                    dir.display()
+                )),
+            }
+            loaded.files.insert(path, snapshot);
+        }
+
+        let scope = Scope::Dir(dir.to_path_buf());
+        let unset: Vec<&String> = previous
+            .values
+            .keys()
+            .filter(|key| !loaded.values.contains_key(*key))
+            .collect();
+        let set: Vec<(&String, &String)> = loaded
+            .values
+            .iter()
+            .filter(|(key, value)| previous.values.get(*key) != Some(value))
+            .collect();
+        let targets: Vec<(Scope, String)> = unset
+            .iter()
+            .copied()
+            .chain(set.iter().map(|(key, _)| *key))
+            .map(|key| (scope.clone(), key.clone()))
+            .collect();
+        if !targets.is_empty() {
+            if let Err(e) = guard(self, targets) {
+                notices.push(format!("not loading {}: {:#}", dir.display(), e));
+                // Compare against the same values next time
+                self.autoloaded.insert(dir.to_path_buf(), previous);
+                return notices;
+            }
+        }
+        for key in unset {
+            self.unset(scope.clone(), key.clone());
+        }
+        for (key, value) in set {
+            self.set(scope.clone(), key.clone(), value.clone());
+        }
+        if !loaded.files.is_empty() {
+            self.autoloaded.insert(dir.to_path_buf(), loaded);
+        }
+        notices
+    }
+
+    /// Keys `envctl allow`/`deny` of `path` may change: those in its files and
+    /// those currently loaded from them. Empty if `path` has no such files.
+    fn autoload_keys(&self, path: &Path) -> Vec<(Scope, String)> {
+        let Ok((dir, files)) = autoload::autoload_targets(path) else {
+            return Vec::new();
+        };
+        let mut keys: HashSet<String> = self
+            .autoloaded
+            .get(&dir)
+            .map(|loaded| loaded.values.keys().cloned().collect())
+            .unwrap_or_default();
+        for file in files {
+            if let Ok(contents) = fs::read_to_string(&file) {
+                if let Ok(entries) = parse_autoload_file(&file, &contents) {
+                    keys.extend(entries.into_iter().map(|(k, _)| k));
+                }
+            }
+        }
+        keys.into_iter()
+            .map(|k| (Scope::Dir(dir.clone()), k))
+            .collect()
+    }
+
+    /// Makes `file` the source of truth for `dir`'s scope, replacing any file
+    /// linked there before, and applies it. Returns how many keys changed.
+    /// The directory's autoload files are ignored while it is linked.
+    pub fn link(&mut self, file: &Path, dir: &Path) -> Result<usize> {
+        let file = canon(file);
+        let dir = canon(dir);
+        let changes = self.sync_scope_to_file(&file, &dir)?;
+        // Forget what autoload set, so an unlink loads the files afresh
+        self.autoloaded.remove(&dir);
+        self.linked.insert(dir, file);
+        Ok(changes)
+    }
+
+    /// Forgets the file linked to `dir` and returns it. The scope keeps the
+    /// values last applied from it.
+    pub fn unlink(&mut self, dir: &Path) -> Result<PathBuf> {
+        self.linked
+            .remove(&canon(dir))
+            .ok_or_else(|| anyhow!("no file is linked to {}", dir.display()))
+    }
+
+    /// Re-applies the file linked to `dir`. If it can't be read or parsed the
+    /// scope is left as it was.
+    pub fn sync_linked(&mut self, dir: &Path) -> Result<usize> {
+        let dir = canon(dir);
+        let file = self
+            .linked
+            .get(&dir)
+            .cloned()
+            .ok_or_else(|| anyhow!("no file is linked to {}", dir.display()))?;
+        self.sync_scope_to_file(&file, &dir)
+    }
+
+    // Sets every key of `file` in `dir`'s scope and unsets every other key
+    // there, all as one generation so shells never see half an update.
+    fn sync_scope_to_file(&mut self, file: &Path, dir: &Path) -> Result<usize> {
+        let contents =
+            fs::read_to_string(file).with_context(|| format!("reading {}", file.display()))?;
+        let values: BTreeMap<String, String> = parse_dotenv_str(&contents)
+            .with_context(|| format!("parsing {}", file.display()))?
+            .into_iter()
+            .collect();
+        let mut changes: Vec<(String, Option<StoredValue>)> = Vec::new();
+        if let Some(current) = self.scoped.get(dir) {
+            let mut removed: Vec<&String> = current
+                .keys()
+                .filter(|k| !values.contains_key(*k))
+                .collect();
+            removed.sort();
+            changes.extend(removed.into_iter().map(|k| (k.clone(), None)));
+        }
+        for (key, value) in values {
+            let value = StoredValue::Plain(value);
+            if self.stored(&Scope::Dir(dir.to_path_buf()), &key) != Some(&value) {
+                changes.push((key, Some(value)));
+            }
+        }
+        Ok(self.apply_scope_changes(dir, changes))
+    }
+
+    // Like a series of `set_stored`/`unset` calls on one canonical directory
+    // scope, but bumping the generation once for all of them.
+    fn apply_scope_changes(
+        &mut self,
+        dir: &Path,
+        changes: Vec<(String, Option<StoredValue>)>,
+    ) -> usize {
+        if changes.is_empty() {
+            return 0;
+        }
+        self.generation += 1;
+        let timestamp = audit::now_secs();
+        let count = changes.len();
+        let map = self.scoped.entry(dir.to_path_buf()).or_default();
+        for (key, value) in changes {
+            match &value {
+                Some(v) => map.insert(key.clone(), v.clone()),
+                None => map.remove(&key),
+            };
+            self.history.push(ChangeEvent {
+                generation: self.generation,
+                key,
+                scope: Scope::Dir(dir.to_path_buf()),
+                value,
+                timestamp,
+            });
+        }
+        count
+    }
+
+    /// Keys linking `file` to `dir` may change: those in the file and those
+    /// already in the scope. Defaults to the file currently linked there.
+    fn link_keys(&self, file: Option<&Path>, dir: &Path) -> Vec<(Scope, String)> {
+        let dir = canon(dir);
+        let mut keys: HashSet<String> = self
+            .scoped
+            .get(&dir)
+            .map(|m| m.keys().cloned().collect())
+            .unwrap_or_default();
+        if let Some(file) = file.or_else(|| self.linked.get(&dir).map(PathBuf::as_path)) {
+            if let Ok(contents) = fs::read_to_string(file) {
+                if let Ok(entries) = parse_dotenv_str(&contents) {
+                    keys.extend(entries.into_iter().map(|(k, _)| k));
+                }
+            }
+        }
+        keys.into_iter()
+            .map(|k| (Scope::Dir(dir.clone()), k))
+            .collect()
+    }
+
+    pub fn secret_count(&self) -> usize {
+        self.globals
+            .values()
+            .chain(self.scoped.values().flat_map(|m| m.values()))
+            .filter(|v| v.is_secret())
+            .count()
+    }
+
+    // Opens secrets; references are left for `Revealed::resolve`.
+    fn reveal(&self, key: &str, value: &StoredValue) -> Result<Revealed> {
+        match value {
+            StoredValue::Plain(v) => Ok(Revealed::Value(v.clone())),
+            StoredValue::Secret(sealed) => self
+                .vault
+                .open(sealed)
+                .map(Revealed::Value)
+                .map_err(|e| anyhow!("cannot read secret {}: {}", key, e)),
+            StoredValue::Ref(r) => Ok(Revealed::Ref(r.clone())),
+        }
+    }
+
+    fn effective_for_pwd(&self, pwd: &Path) -> Result<HashMap<String, Revealed>> {
+        let mut stored: HashMap<&String, &StoredValue> = self.globals.iter().collect();
         if let Some((_, overlay)) = self.best_scope_for_pwd(pwd) {
             for (k, v) in overlay.iter() {
-                map.insert(k.clone(), v.clone());
+                stored.insert(k, v);
             }
         }
-        map
+        let mut map = HashMap::with_capacity(stored.len());
+        for (k, v) in stored {
+            map.insert(k.clone(), self.reveal(k, v)?);
+        }
+        Ok(map)
+    }
+
+    /// Effective keys at `pwd` whose value is stored encrypted, sorted.
+    pub fn effective_secret_keys(&self, pwd: &Path) -> Vec<String> {
+        self.effective_keys_where(pwd, StoredValue::is_secret)
+    }
+
+    /// Effective keys at `pwd` whose value comes from a provider, sorted.
+    pub fn effective_ref_keys(&self, pwd: &Path) -> Vec<String> {
+        self.effective_keys_where(pwd, StoredValue::is_ref)
+    }
+
+    fn effective_keys_where(&self, pwd: &Path, pred: fn(&StoredValue) -> bool) -> Vec<String> {
+        let mut stored: HashMap<&String, &StoredValue> = self.globals.iter().collect();
+        if let Some((_, overlay)) = self.best_scope_for_pwd(pwd) {
+            stored.extend(overlay.iter());
+        }
+        let mut keys: Vec<String> = stored
+            .into_iter()
+            .filter(|(_, v)| pred(v))
+            .map(|(k, _)| k.clone())
+            .collect();
+        keys.sort();
+        keys
+    }
+
+    fn get_effective(&self, key: &str, pwd: &Path) -> Result<Option<Revealed>> {
+        self.effective_stored(key, pwd)
+            .map(|v| self.reveal(key, v))
+            .transpose()
     }
 
-    pub fn get_effective(&self, key: &str, pwd: &Path) -> Option<String> {
+    fn effective_stored(&self, key: &str, pwd: &Path) -> Option<&StoredValue> {
         if let Some((_, overlay)) = self.best_scope_for_pwd(pwd) {
             if let Some(v) = overlay.get(key) {
-                return Some(v.clone());
+                return Some(v);
             }
         }
-        self.globals.get(key).cloned()
+        self.globals.get(key)
     }
 
     // Returns best matching directory scope (deepest ancestor) and its map
-    fn best_scope_for_pwd(&self, pwd: &Path) -> Option<(PathBuf, &HashMap<String, String>)> {
+    fn best_scope_for_pwd(&self, pwd: &Path) -> Option<(PathBuf, &HashMap<String, StoredValue>)> {
         let pwd = canon(pwd);
-        let mut best: Option<(PathBuf, &HashMap<String, String>)> = None;
+        let mut best: Option<(PathBuf, &HashMap<String, StoredValue>)> = None;
         for (dir, vars) in &self.scoped {
             if is_ancestor(dir, &pwd) {
                 match &best {
@@ -259,41 +973,157 @@ impl State {
         best
     }
 
-    pub fn export_since(&self, shell: ShellKind, since: u64, pwd: &Path) -> (String, u64) {
-        let new_gen = self.generation;
-        let mut changed_keys: HashSet<String> = HashSet::new();
+    // Keys changed after generation `since` that are visible from `pwd`, with
+    // their current effective value (None if now unset) and the generation
+    // they last changed at.
+    fn changes_since(&self, since: u64, pwd: &Path) -> Result<PendingExport> {
+        // Each changed key, with the generation it last changed at
+        let mut changed_keys: HashMap<String, u64> = HashMap::new();
         let pwd_c = canon(pwd);
         for ev in self.history.iter().filter(|e| e.generation > since) {
-            match &ev.scope {
-                Scope::Global => {
-                    changed_keys.insert(ev.key.clone());
-                }
-                Scope::Dir(dir) => {
-                    if is_ancestor(dir, &pwd_c) {
-                        changed_keys.insert(ev.key.clone());
-                    }
-                }
+            let visible = match &ev.scope {
+                Scope::Global => true,
+                Scope::Dir(dir) => is_ancestor(dir, &pwd_c),
+            };
+            if visible {
+                changed_keys.insert(ev.key.clone(), ev.generation);
             }
         }
+        // Locked secrets fail the whole export; references are resolved later
+        let mut changes = Vec::with_capacity(changed_keys.len());
+        for (key, changed_at) in changed_keys {
+            let value = match self.effective_stored(&key, &pwd_c) {
+                Some(v) => Some(self.reveal(&key, v)?),
+                None => None,
+            };
+            changes.push((key, value, changed_at));
+        }
+        changes.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(PendingExport {
+            changes,
+            generation: self.generation,
+        })
+    }
+}
+
+/// An effective value read under the state lock. References are resolved
+/// only after it is released, since a provider command can run for seconds.
+#[derive(Debug, Clone)]
+enum Revealed {
+    Value(String),
+    Ref(ProviderRef),
+}
+
+impl Revealed {
+    fn resolve(self, key: &str, providers: &ProviderCache) -> Result<String> {
+        match self {
+            Revealed::Value(v) => Ok(v),
+            Revealed::Ref(r) => providers
+                .resolve(&r)
+                .with_context(|| format!("cannot resolve {}", key)),
+        }
+    }
+}
+
+/// `changes_since` before its references are resolved.
+struct PendingExport {
+    changes: Vec<(String, Option<Revealed>, u64)>,
+    generation: u64,
+}
 
-        // For each changed key, compute current effective value for pwd
-        let mut actions: Vec<(String, Option<String>)> = Vec::new();
-        for key in changed_keys.into_iter() {
-            let val = self.get_effective(&key, &pwd_c);
-            actions.push((key, val));
+impl PendingExport {
+    // The changes to export, sorted by key, plus provider failures and
+    // autoload notices, and the generation the caller has caught up to. A
+    // failing provider only skips its key (reported in the script) and holds
+    // the generation back so the next export retries it.
+    fn resolve(self, providers: &ProviderCache, notices: Vec<String>) -> ExportChanges {
+        let mut actions: Vec<ExportAction> = Vec::with_capacity(self.changes.len());
+        let mut failures: Vec<String> = Vec::new();
+        let mut generation = self.generation;
+        for (key, value, changed_at) in self.changes {
+            match value.map(|v| v.resolve(&key, providers)).transpose() {
+                Ok(value) => actions.push((key, value)),
+                Err(e) => {
+                    failures.push(format!("{:#}", e));
+                    generation = generation.min(changed_at - 1);
+                }
+            }
         }
-        actions.sort_by(|a, b| a.0.cmp(&b.0));
-        let script = render_script(shell, &actions, new_gen);
+        failures.sort();
+        failures.extend(notices);
+        (actions, failures, generation)
+    }
+
+    /// Script applying the changes in `shell`'s syntax.
+    fn script(
+        self,
+        shell: ShellKind,
+        providers: &ProviderCache,
+        notices: Vec<String>,
+    ) -> (String, u64) {
+        let (actions, failures, new_gen) = self.resolve(providers, notices);
+        let script = render_script(shell, &actions, &failures, new_gen);
         (script, new_gen)
     }
+
+    /// The same changes as `script`, as data instead of a script.
+    fn diff(self, providers: &ProviderCache, notices: Vec<String>) -> ExportDiff {
+        let (actions, errors, generation) = self.resolve(providers, notices);
+        let mut diff = ExportDiff {
+            errors,
+            generation,
+            ..ExportDiff::default()
+        };
+        for (k, v) in actions.into_iter().filter(|(k, _)| is_valid_key(k)) {
+            match v {
+                Some(val) => {
+                    diff.set.insert(k, val);
+                }
+                None => diff.unset.push(k),
+            }
+        }
+        diff
+    }
 }
 
-fn is_ancestor(a: &Path, b: &Path) -> bool {
+/// Checks the keys autoload is about to change in one directory, seeing the
+/// state before the change; an error skips that directory.
+pub type AutoloadGuard<'a> = dyn FnMut(&State, Vec<(Scope, String)>) -> Result<()> + 'a;
+
+// A key and the value to export for it, or None to unset it
+type ExportAction = (String, Option<String>);
+type ExportChanges = (Vec<ExportAction>, Vec<String>, u64);
+
+pub(crate) fn is_ancestor(a: &Path, b: &Path) -> bool {
     let a = canon(a);
     let b = canon(b);
     b.starts_with(a)
 }
 
+fn canon_scope(scope: &Scope) -> Scope {
+    match scope {
+        Scope::Dir(p) => Scope::Dir(canon(p)),
+        Scope::Global => Scope::Global,
+    }
+}
+
+fn scope_sort_key(scope: &Scope) -> Option<&Path> {
+    match scope {
+        Scope::Global => None,
+        Scope::Dir(p) => Some(p),
+    }
+}
+
+// `.envrc` is a shell script, so only the subset that reads the same as
+// dotenv is accepted from it
+fn parse_autoload_file(path: &Path, contents: &str) -> Result<Vec<(String, String)>, DotenvError> {
+    if path.file_name() == Some(std::ffi::OsStr::new(".envrc")) {
+        parse_envrc_str(contents)
+    } else {
+        parse_dotenv_str(contents)
+    }
+}
+
 fn canon<P: AsRef<Path>>(p: P) -> PathBuf {
     let p = p.as_ref();
     match p.canonicalize() {
@@ -319,10 +1149,76 @@ fn sh_single_quote(val: &str) -> String {
     out
 }
 
-fn render_script(shell: ShellKind, actions: &[(String, Option<String>)], new_gen: u64) -> String {
+// csh/tcsh single quotes need more than `sh_single_quote`: `!` triggers
+// history expansion even inside them, so it is emitted outside the quotes as
+// `\!`, and a bare newline ends the command, so it is written as `\`+newline,
+// which csh turns into a literal newline inside quotes.
+fn csh_quote(val: &str) -> String {
+    let mut out = String::with_capacity(val.len() + 2);
+    out.push('\'');
+    for ch in val.chars() {
+        match ch {
+            '\'' => out.push_str("'\\''"),
+            '!' => out.push_str("'\\!'"),
+            '\n' => out.push_str("\\\n"),
+            _ => out.push(ch),
+        }
+    }
+    out.push('\'');
+    out
+}
+
+// Elvish single-quoted strings have one escape: `''` for a literal quote.
+// Newlines and everything else are taken verbatim.
+fn elvish_quote(val: &str) -> String {
+    format!("'{}'", val.replace('\'', "''"))
+}
+
+// Xonsh runs Python, so values become Python string literals. Control
+// characters are escaped so the generated line stays a single line.
+fn py_quote(val: &str) -> String {
+    let mut out = String::with_capacity(val.len() + 2);
+    out.push('\'');
+    for ch in val.chars() {
+        match ch {
+            '\\' => out.push_str("\\\\"),
+            '\'' => out.push_str("\\'"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            _ => out.push(ch),
+        }
+    }
+    out.push('\'');
+    out
+}
+
+// PowerShell single-quoted strings only escape quotes, by doubling them. The
+// typographic single quotes count as quote characters too, so double those.
+fn ps_single_quote(val: &str) -> String {
+    let mut out = String::with_capacity(val.len() + 2);
+    out.push('\'');
+    for ch in val.chars() {
+        if matches!(ch, '\'' | '\u{2018}' | '\u{2019}' | '\u{201A}' | '\u{201B}') {
+            out.push(ch);
+        }
+        out.push(ch);
+    }
+    out.push('\'');
+    out
+}
+
+fn render_script(
+    shell: ShellKind,
+    actions: &[(String, Option<String>)],
+    failures: &[String],
+    new_gen: u64,
+) -> String {
     let mut out = String::new();
     match shell {
         ShellKind::Bash | ShellKind::Zsh => {
+            push_echo_stderr(&mut out, failures);
             for (k, v) in actions {
                 if is_valid_key(k) {
                     match v {
@@ -337,7 +1233,25 @@ fn render_script(shell: ShellKind, actions: &[(String, Option<String>)], new_gen
             }
             out.push_str(&format!("export ENVCTL_GEN={}\n", new_gen));
         }
+        // Plain POSIX sh (dash, ash): no `unset -v`, nothing bash-specific
+        ShellKind::Posix => {
+            push_echo_stderr(&mut out, failures);
+            for (k, v) in actions {
+                if is_valid_key(k) {
+                    match v {
+                        Some(val) => {
+                            out.push_str(&format!("export {}={}\n", k, sh_single_quote(val)));
+                        }
+                        None => {
+                            out.push_str(&format!("unset {}\n", k));
+                        }
+                    }
+                }
+            }
+            out.push_str(&format!("export ENVCTL_GEN={}\n", new_gen));
+        }
         ShellKind::Fish => {
+            push_echo_stderr(&mut out, failures);
             for (k, v) in actions {
                 if is_valid_key(k) {
                     match v {
@@ -350,11 +1264,136 @@ fn render_script(shell: ShellKind, actions: &[(String, Option<String>)], new_gen
             }
             out.push_str(&format!("set -x ENVCTL_GEN {}\n", new_gen));
         }
+        ShellKind::Tcsh => {
+            // csh has no way to send just stdout to stderr. `>>!` still
+            // works with `set noclobber`, which refuses `>` to existing files
+            for msg in failures {
+                out.push_str(&format!(
+                    "echo {} >>! /dev/stderr\n",
+                    csh_quote(&format!("envctl: {}", msg))
+                ));
+            }
+            for (k, v) in actions {
+                if is_valid_key(k) {
+                    match v {
+                        Some(val) => out.push_str(&format!("setenv {} {}\n", k, csh_quote(val))),
+                        None => out.push_str(&format!("unsetenv {}\n", k)),
+                    }
+                }
+            }
+            out.push_str(&format!("setenv ENVCTL_GEN {}\n", new_gen));
+            // The hook can't see that ENVCTL_GEN stopped short of envd's
+            // generation, so tell it to export again at the next prompt
+            if !failures.is_empty() {
+                out.push_str("set __envctl_last = \"\"\n");
+            }
+        }
+        ShellKind::Nu => out.push_str(&render_nu(actions, failures, new_gen)),
+        ShellKind::PowerShell => {
+            for msg in failures {
+                out.push_str(&format!(
+                    "[Console]::Error.WriteLine({})\n",
+                    ps_single_quote(&format!("envctl: {}", msg))
+                ));
+            }
+            for (k, v) in actions {
+                if is_valid_key(k) {
+                    match v {
+                        Some(val) => {
+                            out.push_str(&format!("$env:{} = {}\n", k, ps_single_quote(val)))
+                        }
+                        None => out.push_str(&format!(
+                            "Remove-Item Env:{} -ErrorAction SilentlyContinue\n",
+                            k
+                        )),
+                    }
+                }
+            }
+            out.push_str(&format!("$env:ENVCTL_GEN = '{}'\n", new_gen));
+        }
+        ShellKind::Elvish => {
+            for msg in failures {
+                out.push_str(&format!(
+                    "echo {} >&2\n",
+                    elvish_quote(&format!("envctl: {}", msg))
+                ));
+            }
+            for (k, v) in actions {
+                if is_valid_key(k) {
+                    match v {
+                        Some(val) => {
+                            out.push_str(&format!("set-env {} {}\n", k, elvish_quote(val)))
+                        }
+                        None => out.push_str(&format!("unset-env {}\n", k)),
+                    }
+                }
+            }
+            out.push_str(&format!("set-env ENVCTL_GEN '{}'\n", new_gen));
+        }
+        ShellKind::Xonsh => {
+            for msg in failures {
+                out.push_str(&format!(
+                    "print({}, file=__import__('sys').stderr)\n",
+                    py_quote(&format!("envctl: {}", msg))
+                ));
+            }
+            for (k, v) in actions {
+                if is_valid_key(k) {
+                    match v {
+                        Some(val) => out.push_str(&format!("${} = {}\n", k, py_quote(val))),
+                        // `del` raises if the variable is already gone
+                        None => out.push_str(&format!("${{...}}.pop('{}', None)\n", k)),
+                    }
+                }
+            }
+            out.push_str(&format!("$ENVCTL_GEN = '{}'\n", new_gen));
+        }
     }
     out
 }
 
-fn is_valid_key(k: &str) -> bool {
+// `echo ... >&2` reads the same in sh, bash, zsh and fish
+fn push_echo_stderr(out: &mut String, failures: &[String]) {
+    for msg in failures {
+        out.push_str(&format!(
+            "echo {} >&2\n",
+            sh_single_quote(&format!("envctl: {}", msg))
+        ));
+    }
+}
+
+// Nushell cannot eval generated code, so its hook parses this JSON record
+// instead: `set` goes to `load-env`, `unset` to `hide-env`, `errors` to stderr.
+fn render_nu(actions: &[(String, Option<String>)], failures: &[String], new_gen: u64) -> String {
+    let mut set = serde_json::Map::new();
+    let mut unset = Vec::new();
+    for (k, v) in actions.iter().filter(|(k, _)| is_valid_key(k)) {
+        match v {
+            Some(val) => {
+                set.insert(k.clone(), serde_json::Value::String(val.clone()));
+            }
+            None => unset.push(serde_json::Value::String(k.clone())),
+        }
+    }
+    set.insert(
+        "ENVCTL_GEN".to_string(),
+        serde_json::Value::String(new_gen.to_string()),
+    );
+    let errors = failures
+        .iter()
+        .map(|msg| serde_json::Value::String(format!("envctl: {}", msg)))
+        .collect();
+    let record = serde_json::json!({
+        "set": set,
+        "unset": unset,
+        "errors": serde_json::Value::Array(errors),
+    });
+    format!("{}\n", record)
+}
+
+/// Whether `k` can be a variable name in every shell and file format envctl
+/// writes: ASCII letters, digits and `_`, not starting with a digit.
+pub fn is_valid_key(k: &str) -> bool {
     let first = k.chars().next();
     if !first
         .map(|c| c == '_' || c.is_ascii_alphabetic())
@@ -365,26 +1404,71 @@ fn is_valid_key(k: &str) -> bool {
     k.chars().all(|c| c == '_' || c.is_ascii_alphanumeric())
 }
 
+/// Fails with a message naming `k` unless it is a valid key.
+pub fn check_key(k: &str) -> Result<()> {
+    if is_valid_key(k) {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "invalid key {:?}: use letters, digits and _, not starting with a digit",
+            k
+        ))
+    }
+}
+
+// Keys a request would store. Checked by the daemon too, since not every
+// client goes through envctl's parsing.
+fn check_request_keys(req: &Request) -> Result<()> {
+    match req {
+        Request::Set { key, .. } | Request::SetRef { key, .. } => check_key(key),
+        Request::Load { entries, .. } => entries.iter().try_for_each(|(key, _)| check_key(key)),
+        _ => Ok(()),
+    }
+}
+
 // --------------- Server plumbing ---------------
 
 pub fn run_server() -> Result<()> {
+    run_server_with(Vault::default())
+}
+
+pub fn run_server_with(vault: Vault) -> Result<()> {
     let dir = ensure_socket_dir()?;
+    write_generation_file(0)?;
     let sock = socket_path();
     if sock.exists() {
         let _ = fs::remove_file(&sock);
     }
     let listener = UnixListener::bind(&sock).with_context(|| format!("bind {}", sock.display()))?;
+    let config = DaemonConfig::load()?;
     write_pid_file(&dir)?;
-    let state = Arc::new(Mutex::new(State::default()));
+    let daemon = Arc::new(Daemon {
+        state: Mutex::new(State {
+            trust: TrustStore::load(trust_path())?,
+            ..State::with_vault(vault)
+        }),
+        acl: Acl::new(
+            config.acl,
+            config.provider_writers.unwrap_or_else(|| {
+                // SAFETY: getuid has no preconditions and cannot fail.
+                vec![unsafe { libc::getuid() }]
+            }),
+        ),
+        audit: AuditLog::new(&config.audit),
+        watchers: Mutex::new(HashMap::new()),
+        autoload_watched: Mutex::new(HashMap::new()),
+    });
 
     loop {
         let (mut stream, _addr) = listener.accept()?;
-        let state = state.clone();
+        let daemon = daemon.clone();
         std::thread::spawn(move || {
+            let peer = PeerCred::from_stream(&stream);
             let resp = match read_json(&mut stream) {
-                Ok(req) => handle_request(req, &state),
+                Ok(req) => handle_request(req, &daemon, peer.as_ref()),
                 Err(e) => Response::Error {
                     message: format!("read error: {}", e),
+                    code: None,
                 },
             };
             let _ = write_json(&mut stream, &resp);
@@ -392,21 +1476,397 @@ pub fn run_server() -> Result<()> {
     }
 }
 
+struct Daemon {
+    state: Mutex<State>,
+    acl: Acl,
+    audit: AuditLog,
+    /// Stop flags of the threads watching linked files, by linked dir.
+    watchers: Mutex<HashMap<PathBuf, Arc<AtomicBool>>>,
+    /// Stop flags of the threads watching autoload files, by directory.
+    autoload_watched: Mutex<HashMap<PathBuf, Arc<AtomicBool>>>,
+}
+
+impl Daemon {
+    // Starts following `file`, replacing whatever watched `dir` before.
+    // Changes are applied with the permissions of `peer`, who linked it.
+    fn watch_linked(self: &Arc<Self>, file: PathBuf, dir: PathBuf, peer: Option<PeerCred>) {
+        let stop = Arc::new(AtomicBool::new(false));
+        self.unwatch_linked(&dir);
+        self.watchers.lock().insert(dir.clone(), stop.clone());
+        let daemon = self.clone();
+        thread::spawn(move || {
+            watch::watch_files(std::slice::from_ref(&file), &stop, || {
+                daemon.resync_linked(&dir, &stop, peer.as_ref())
+            })
+        });
+    }
+
+    fn unwatch_linked(&self, dir: &Path) {
+        if let Some(stop) = self.watchers.lock().remove(dir) {
+            stop.store(true, Ordering::Relaxed);
+        }
+    }
+
+    // Loads `pwd`'s autoload files as `peer`, who must be allowed to write
+    // the keys they change, and audits the changes like any other.
+    fn autoload(&self, st: &mut State, pwd: &Path, peer: Option<&PeerCred>) -> Vec<String> {
+        self.guarded_autoload(st, peer, |st, guard| st.autoload(pwd, guard))
+    }
+
+    fn guarded_autoload(
+        &self,
+        st: &mut State,
+        peer: Option<&PeerCred>,
+        load: impl FnOnce(&mut State, &mut AutoloadGuard) -> Vec<String>,
+    ) -> Vec<String> {
+        let mut audited = AuditedMutation {
+            op: "autoload",
+            before: Vec::new(),
+        };
+        let notices = load(st, &mut |st, targets| {
+            targets
+                .iter()
+                .try_for_each(|(scope, key)| self.acl.check_write(peer, key, scope))?;
+            let snapshot = AuditedMutation::snapshot("autoload", targets, st);
+            audited.before.extend(snapshot.before);
+            Ok(())
+        });
+        audited.record(&self.audit, st, peer);
+        notices
+    }
+
+    // Watches the autoload files of every directory they were loaded from,
+    // so an edit bumps the generation right away: shell hooks only export
+    // when the generation or $PWD changed. Watchers load nothing themselves;
+    // the export they prompt reloads the files with its caller's permissions.
+    // A watcher stops once its files are gone or its directory is no longer
+    // autoloaded (say, because it was linked).
+    fn watch_autoloaded(self: &Arc<Self>, st: &State) {
+        let mut watched = self.autoload_watched.lock();
+        watched.retain(|dir, stop| {
+            let stopped = stop.load(Ordering::Relaxed);
+            let keep = st.autoloaded.contains_key(dir)
+                && !(stopped && AUTOLOAD_FILES.iter().any(|f| dir.join(f).exists()));
+            if !keep {
+                stop.store(true, Ordering::Relaxed);
+            }
+            keep
+        });
+        for dir in st.autoload_dirs() {
+            if watched.contains_key(dir) {
+                continue;
+            }
+            let stop = Arc::new(AtomicBool::new(false));
+            watched.insert(dir.clone(), stop.clone());
+            let files: Vec<PathBuf> = AUTOLOAD_FILES.iter().map(|name| dir.join(name)).collect();
+            let dir = dir.clone();
+            let daemon = self.clone();
+            thread::spawn(move || {
+                watch::watch_files(&files, &stop, || {
+                    let mut st = daemon.state.lock();
+                    if stop.load(Ordering::Relaxed) || !st.autoload_stale(&dir) {
+                        return;
+                    }
+                    let before = st.generation;
+                    st.nudge();
+                    publish_generation(&st, before);
+                    // The next export for the directory forgets it
+                    if files.iter().all(|f| !f.exists()) {
+                        stop.store(true, Ordering::Relaxed);
+                    }
+                })
+            });
+        }
+    }
+
+    fn resync_linked(&self, dir: &Path, stop: &AtomicBool, peer: Option<&PeerCred>) {
+        let mut st = self.state.lock();
+        // Relinked or unlinked while this change was being noticed
+        if stop.load(Ordering::Relaxed) {
+            return;
+        }
+        let targets = st.link_keys(None, dir);
+        if let Err(e) = targets
+            .iter()
+            .try_for_each(|(scope, key)| self.acl.check_write(peer, key, scope))
+        {
+            eprintln!(
+                "envd: not applying change linked to {}: {:#}",
+                dir.display(),
+                e
+            );
+            return;
+        }
+        let audited = AuditedMutation::snapshot("link", targets, &st);
+        let before = st.generation;
+        if let Err(e) = st.sync_linked(dir) {
+            eprintln!("envd: {:#}", e);
+        }
+        audited.record(&self.audit, &st, peer);
+        publish_generation(&st, before);
+    }
+}
+
 fn resolve_pwd(pwd: Option<PathBuf>) -> PathBuf {
     pwd.unwrap_or_else(|| std::env::current_dir().unwrap_or_else(|_| PathBuf::from(".")))
 }
 
-fn handle_request(req: Request, state: &Arc<Mutex<State>>) -> Response {
-    let mut st = state.lock();
+fn handle_request(req: Request, daemon: &Arc<Daemon>, peer: Option<&PeerCred>) -> Response {
+    if let Err(e) = check_request_keys(&req) {
+        return error_response(e);
+    }
+    let mut st = daemon.state.lock();
+    let targets = mutation_targets(&req, &st);
+    if let Some((_, targets)) = &targets {
+        // Checked before anything is applied so a denied `Load` changes nothing.
+        let allowed = match &req {
+            Request::SetRef { key, .. } => daemon.acl.check_provider(peer, key),
+            _ => Ok(()),
+        };
+        if let Err(e) = allowed.and_then(|()| {
+            targets
+                .iter()
+                .try_for_each(|(scope, key)| daemon.acl.check_write(peer, key, scope))
+        }) {
+            return Response::Error {
+                message: format!("{:#}", e),
+                code: Some(ErrorCode::PermissionDenied),
+            };
+        }
+    }
+    let audited = targets.map(|(op, targets)| AuditedMutation::snapshot(op, targets, &st));
+    let before = st.generation;
+    // Whatever reads values for a directory sees its autoload files
+    let notices = match &req {
+        Request::Export { pwd, .. } | Request::ExportDiff { pwd, .. } => {
+            daemon.autoload(&mut st, pwd, peer)
+        }
+        Request::Get { pwd, .. } | Request::List { pwd } => {
+            daemon.autoload(&mut st, &resolve_pwd(pwd.clone()), peer)
+        }
+        _ => Vec::new(),
+    };
+    let reply = apply_request(req, &mut st, &daemon.audit);
+    if let Some(audited) = audited {
+        audited.record(&daemon.audit, &st, peer);
+    }
+    match &reply {
+        Reply::Done(Response::Linked { file, dir, .. }) => {
+            daemon.watch_linked(file.clone(), dir.clone(), peer.copied())
+        }
+        Reply::Done(Response::Unlinked { dir, .. }) => daemon.unwatch_linked(dir),
+        _ => {}
+    }
+    daemon.watch_autoloaded(&st);
+    publish_generation(&st, before);
+    match reply {
+        Reply::Done(resp) => resp,
+        Reply::Read(read) => {
+            // Provider commands may be slow; don't make other clients wait
+            let providers = st.providers.clone();
+            drop(st);
+            read.finish(&providers, notices)
+        }
+    }
+}
+
+// Still under the state lock, so concurrent mutations can't reorder writes
+fn publish_generation(st: &State, before: u64) {
+    if st.generation != before {
+        if let Err(e) = write_generation_file(st.generation) {
+            eprintln!("envd: failed to update generation file: {:#}", e);
+        }
+    }
+}
+
+/// What `apply_request` produced: a response, or values read from the state
+/// whose provider references still need resolving.
+enum Reply {
+    Done(Response),
+    Read(PendingRead),
+}
+
+/// Values a read request answers with, taken under the state lock and
+/// completed by `finish` once it is released.
+enum PendingRead {
+    Value {
+        key: String,
+        value: Option<Revealed>,
+    },
+    Map {
+        entries: HashMap<String, Revealed>,
+        secrets: Vec<String>,
+        refs: Vec<String>,
+    },
+    Export {
+        shell: ShellKind,
+        pending: PendingExport,
+    },
+    ExportDiff {
+        pending: PendingExport,
+    },
+}
+
+impl PendingRead {
+    /// Resolves the values; an export also reports `notices`.
+    fn finish(self, providers: &ProviderCache, notices: Vec<String>) -> Response {
+        match self {
+            PendingRead::Value { key, value } => {
+                match value.map(|v| v.resolve(&key, providers)).transpose() {
+                    Ok(value) => Response::Value { value },
+                    Err(e) => error_response(e),
+                }
+            }
+            PendingRead::Map {
+                entries,
+                secrets,
+                refs,
+            } => {
+                let mut map = HashMap::with_capacity(entries.len());
+                for (key, value) in entries {
+                    match value.resolve(&key, providers) {
+                        Ok(value) => map.insert(key, value),
+                        Err(e) => return error_response(e),
+                    };
+                }
+                Response::Map {
+                    entries: map,
+                    secrets,
+                    refs,
+                }
+            }
+            PendingRead::ExportDiff { pending } => Response::ExportDiff {
+                diff: pending.diff(providers, notices),
+            },
+            PendingRead::Export { shell, pending } => {
+                let (script, new_generation) = pending.script(shell, providers, notices);
+                Response::Export {
+                    script,
+                    new_generation,
+                }
+            }
+        }
+    }
+}
+
+/// The (scope, key) pairs a mutating request may change, with the operation
+/// name used in the audit log. `None` for read-only requests.
+fn mutation_targets(req: &Request, st: &State) -> Option<(&'static str, Vec<(Scope, String)>)> {
+    let single = |scope: &Scope, key: &String| vec![(canon_scope(scope), key.clone())];
     match req {
+        Request::Set { key, scope, .. } | Request::SetRef { key, scope, .. } => {
+            Some(("set", single(scope, key)))
+        }
+        Request::Unset { key, scope } => Some(("unset", single(scope, key))),
+        Request::Load { entries, scope, .. } => {
+            let scope = canon_scope(scope);
+            Some((
+                "load",
+                entries
+                    .iter()
+                    .map(|(k, _)| (scope.clone(), k.clone()))
+                    .collect(),
+            ))
+        }
+        Request::Allow { path } => Some(("allow", st.autoload_keys(path))),
+        Request::Deny { path } => Some(("deny", st.autoload_keys(path))),
+        Request::Link { file, dir } => Some(("link", st.link_keys(Some(file), dir))),
+        Request::Rollback { to_gen, key, scope } => {
+            // An invalid generation yields no targets; `apply_request` reports it.
+            let plan = st
+                .rollback_plan(*to_gen, key.as_deref(), scope.as_ref())
+                .unwrap_or_default();
+            Some((
+                "rollback",
+                plan.into_iter()
+                    .map(|(scope, key, _)| (scope, key))
+                    .collect(),
+            ))
+        }
+        _ => None,
+    }
+}
+
+/// Snapshot of the values a mutating request may touch, taken before it runs
+/// so that the audit entries can carry old and new value hashes.
+struct AuditedMutation {
+    op: &'static str,
+    before: Vec<(Scope, String, Option<StoredValue>)>,
+}
+
+impl AuditedMutation {
+    fn snapshot(op: &'static str, targets: Vec<(Scope, String)>, st: &State) -> Self {
+        let before = targets
+            .into_iter()
+            .map(|(scope, key)| {
+                let old = st.stored(&scope, &key).cloned();
+                (scope, key, old)
+            })
+            .collect();
+        AuditedMutation { op, before }
+    }
+
+    fn record(self, log: &AuditLog, st: &State, peer: Option<&PeerCred>) {
+        let mut caller: Option<Caller> = None;
+        for (scope, key, old) in self.before {
+            let new = st.stored(&scope, &key);
+            if old.as_ref() == new {
+                continue;
+            }
+            let caller = caller.get_or_insert_with(|| Caller::lookup(peer));
+            let entry = AuditEntry {
+                timestamp: audit::now_secs(),
+                generation: st.generation,
+                op: self.op.to_string(),
+                key,
+                scope,
+                pid: caller.pid,
+                uid: caller.uid,
+                exe: caller.exe.clone(),
+                parent_exe: caller.parent_exe.clone(),
+                cwd: caller.cwd.clone(),
+                old_hash: old.as_ref().map(audit::value_hash),
+                new_hash: new.map(audit::value_hash),
+            };
+            if let Err(e) = log.record(&entry) {
+                eprintln!("envd: failed to write audit log: {:#}", e);
+            }
+        }
+    }
+}
+
+fn apply_request(req: Request, st: &mut State, audit: &AuditLog) -> Reply {
+    Reply::Done(match req {
         Request::Ping => Response::Pong,
         Request::Status => Response::Status {
             generation: st.generation,
             globals: st.globals.len(),
             scopes: st.scoped.len(),
+            secrets: st.secret_count(),
+            locked: st.vault.is_locked(),
         },
-        Request::Set { key, value, scope } => {
-            st.set(scope, key, value);
+        Request::Set {
+            key,
+            value,
+            scope,
+            secret,
+        } => {
+            if secret {
+                match st.set_secret(scope, key, value) {
+                    Ok(_) => Response::Ok,
+                    Err(e) => error_response(e),
+                }
+            } else {
+                st.set(scope, key, value);
+                Response::Ok
+            }
+        }
+        Request::SetRef {
+            key,
+            provider,
+            scope,
+        } => {
+            st.set_ref(scope, key, provider);
             Response::Ok
         }
         Request::Unset { key, scope } => {
@@ -415,25 +1875,120 @@ fn handle_request(req: Request, state: &Arc<Mutex<State>>) -> Response {
         }
         Request::Get { key, pwd } => {
             let pwd = resolve_pwd(pwd);
-            let v = st.get_effective(&key, &pwd);
-            Response::Value { value: v }
+            match st.get_effective(&key, &pwd) {
+                Ok(value) => return Reply::Read(PendingRead::Value { key, value }),
+                Err(e) => error_response(e),
+            }
         }
         Request::List { pwd } => {
             let pwd = resolve_pwd(pwd);
-            let entries = st.effective_for_pwd(&pwd);
-            Response::Map { entries }
+            match st.effective_for_pwd(&pwd) {
+                Ok(entries) => {
+                    return Reply::Read(PendingRead::Map {
+                        entries,
+                        secrets: st.effective_secret_keys(&pwd),
+                        refs: st.effective_ref_keys(&pwd),
+                    })
+                }
+                Err(e) => error_response(e),
+            }
+        }
+        Request::Load {
+            entries,
+            scope,
+            secret,
+        } => {
+            if secret {
+                match st.load_secret(scope, entries) {
+                    Ok(()) => Response::Ok,
+                    Err(e) => error_response(e),
+                }
+            } else {
+                st.load(scope, entries);
+                Response::Ok
+            }
         }
-        Request::Load { entries, scope } => {
-            st.load(scope, entries);
+        Request::Export { shell, since, pwd } => match st.changes_since(since, &pwd) {
+            Ok(pending) => return Reply::Read(PendingRead::Export { shell, pending }),
+            Err(e) => error_response(e),
+        },
+        Request::ExportDiff { since, pwd } => match st.changes_since(since, &pwd) {
+            Ok(pending) => return Reply::Read(PendingRead::ExportDiff { pending }),
+            Err(e) => error_response(e),
+        },
+        Request::Lock => {
+            st.vault.lock();
             Response::Ok
         }
-        Request::Export { shell, since, pwd } => {
-            let (script, new_generation) = st.export_since(shell, since, &pwd);
-            Response::Export {
-                script,
-                new_generation,
+        Request::Unlock { passphrase } => match st.vault.unlock(passphrase.as_deref()) {
+            Ok(()) => Response::Ok,
+            Err(e) => error_response(e),
+        },
+        Request::History { key, scope } => Response::History {
+            events: st.key_history(&key, scope.as_ref()),
+        },
+        Request::Rollback { to_gen, key, scope } => {
+            match st.rollback(to_gen, key.as_deref(), scope.as_ref()) {
+                Ok(changes) => Response::RolledBack {
+                    changes,
+                    new_generation: st.generation,
+                },
+                Err(e) => error_response(e),
+            }
+        }
+        Request::Allow { path } => match st.decide_autoload(&path, true) {
+            Ok((files, notices)) => Response::Autoload { files, notices },
+            Err(e) => error_response(e),
+        },
+        Request::Deny { path } => match st.decide_autoload(&path, false) {
+            Ok((files, notices)) => Response::Autoload { files, notices },
+            Err(e) => error_response(e),
+        },
+        Request::Link { file, dir } => match st.link(&file, &dir) {
+            Ok(changes) => Response::Linked {
+                file: canon(&file),
+                dir: canon(&dir),
+                changes,
+                new_generation: st.generation,
+            },
+            Err(e) => error_response(e),
+        },
+        Request::Unlink { dir } => match st.unlink(&dir) {
+            Ok(file) => Response::Unlinked {
+                file,
+                dir: canon(&dir),
+            },
+            Err(e) => error_response(e),
+        },
+        Request::Log { key, limit } => match audit.read(key.as_deref()) {
+            Ok(mut entries) => {
+                if let Some(limit) = limit {
+                    entries.drain(..entries.len().saturating_sub(limit));
+                }
+                Response::AuditLog { entries }
             }
+            Err(e) => error_response(e),
+        },
+        Request::Scopes => {
+            let mut scopes: Vec<ScopeSummary> = st
+                .scoped
+                .iter()
+                .map(|(path, vars)| ScopeSummary {
+                    path: path.clone(),
+                    keys: vars.len(),
+                    secrets: vars.values().filter(|v| v.is_secret()).count(),
+                })
+                .collect();
+            scopes.sort_by(|a, b| a.path.cmp(&b.path));
+            Response::Scopes { scopes }
         }
+    })
+}
+
+fn error_response(e: anyhow::Error) -> Response {
+    Response::Error {
+        message: format!("{:#}", e),
+        code: None,
     }
 }
 
@@ -541,6 +2096,21 @@ fn envd_executable_path() -> Result<PathBuf> {
     }
 }
 
+/// Prompts on the terminal, or reads one line from stdin when it is not a tty.
+pub fn read_passphrase(prompt: &str) -> Result<String> {
+    let passphrase = if std::io::stdin().is_terminal() {
+        rpassword::prompt_password(prompt).context("read passphrase")?
+    } else {
+        let mut line = String::new();
+        std::io::stdin().lock().read_line(&mut line)?;
+        line.trim_end_matches(['\r', '\n']).to_string()
+    };
+    if passphrase.is_empty() {
+        return Err(anyhow!("empty passphrase"));
+    }
+    Ok(passphrase)
+}
+
 #[cfg(windows)]
 fn envd_binary_name() -> &'static str {
     "envd.exe"
@@ -551,33 +2121,13 @@ fn envd_binary_name() -> &'static str {
     "envd"
 }
 
-pub fn parse_dotenv<R: Read>(mut r: R) -> Result<Vec<(String, String)>> {
-    let mut s = String::new();
-    r.read_to_string(&mut s)?;
-    let mut out = Vec::new();
-    for (idx, line) in s.lines().enumerate() {
-        let line = line.trim();
-        if line.is_empty() || line.starts_with('#') {
-            continue;
-        }
-        let line = line.strip_prefix("export ").unwrap_or(line);
-        if let Some(eq) = line.find('=') {
-            let (k, v) = line.split_at(eq);
-            let k = k.trim().to_string();
-            let v = v[1..].trim().to_string();
-            let v = strip_quotes(&v);
-            if !is_valid_key(&k) {
-                return Err(anyhow!("invalid key at line {}: {}", idx + 1, k));
-            }
-            out.push((k, v));
-        } else {
-            return Err(anyhow!("invalid line {}: {}", idx + 1, line));
-        }
-    }
-    Ok(out)
+pub fn parse_dotenv_base64<S: AsRef<str>>(data: S) -> Result<Vec<(String, String)>> {
+    let text = decode_base64_payload(data)?;
+    parse_dotenv(Cursor::new(text))
 }
 
-pub fn parse_dotenv_base64<S: AsRef<str>>(data: S) -> Result<Vec<(String, String)>> {
+/// Decodes base64-encoded dotenv content, ignoring whitespace in the payload.
+pub fn decode_base64_payload<S: AsRef<str>>(data: S) -> Result<String> {
     let raw = data.as_ref();
     let sanitized: String = raw.chars().filter(|c| !c.is_whitespace()).collect();
     if sanitized.is_empty() {
@@ -586,13 +2136,147 @@ pub fn parse_dotenv_base64<S: AsRef<str>>(data: S) -> Result<Vec<(String, String
     let decoded = BASE64_STANDARD
         .decode(sanitized.as_bytes())
         .map_err(|e| anyhow!("invalid base64 payload: {}", e))?;
-    parse_dotenv(Cursor::new(decoded))
+    String::from_utf8(decoded).map_err(|_| anyhow!("base64 payload is not valid UTF-8"))
 }
 
-fn strip_quotes(s: &str) -> String {
-    if (s.starts_with('\"') && s.ends_with('\"')) || (s.starts_with('\'') && s.ends_with('\'')) {
-        s[1..s.len() - 1].to_string()
-    } else {
-        s.to_string()
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Values that each break at least one naive quoting scheme. Values read
+    // from non-UTF-8 files arrive lossily decoded, so U+FFFD stands in for them.
+    const TRICKY: &[&str] = &[
+        "",
+        "plain",
+        "it's",
+        "''",
+        "'",
+        "line\nbreak\r\n",
+        "\n",
+        "$HOME ${HOME} $(id) `id` %PATH% $env:PATH",
+        "{put}[0]",
+        "back\\slash\\",
+        "\\'",
+        "nul\0byte",
+        "!! !$ ^x^y",
+        "\u{2018}smart\u{2019} \u{201A}low\u{201B}",
+        "tab\tbell\u{7}esc\u{1b}[0m del\u{7f}",
+        "caf\u{e9} \u{2603} \u{1f600}",
+        "\u{fffd}\u{fffd}\\xff\\x80",
+    ];
+
+    // Reads one single-quoted string where `quote` is escaped by doubling it
+    fn undouble(quoted: &str, is_quote: impl Fn(char) -> bool) -> String {
+        let inner = quoted
+            .strip_prefix('\'')
+            .and_then(|s| s.strip_suffix('\''))
+            .expect("wrapped in single quotes");
+        let mut out = String::new();
+        let mut chars = inner.chars();
+        while let Some(ch) = chars.next() {
+            if is_quote(ch) {
+                assert_eq!(chars.next(), Some(ch), "lone quote in {:?}", quoted);
+            }
+            out.push(ch);
+        }
+        out
+    }
+
+    #[test]
+    fn elvish_quote_round_trips() {
+        for val in TRICKY {
+            let quoted = elvish_quote(val);
+            assert_eq!(undouble(&quoted, |c| c == '\''), *val, "{:?}", quoted);
+        }
+        assert_eq!(elvish_quote("it's\n"), "'it''s\n'");
+    }
+
+    #[test]
+    fn ps_single_quote_round_trips() {
+        let is_quote = |c| matches!(c, '\'' | '\u{2018}' | '\u{2019}' | '\u{201A}' | '\u{201B}');
+        for val in TRICKY {
+            let quoted = ps_single_quote(val);
+            assert_eq!(undouble(&quoted, is_quote), *val, "{:?}", quoted);
+        }
+        assert_eq!(ps_single_quote("a\u{2019}b'c"), "'a\u{2019}\u{2019}b''c'");
+    }
+
+    #[test]
+    fn py_quote_round_trips_on_one_line() {
+        fn unescape(quoted: &str) -> String {
+            let inner = &quoted[1..quoted.len() - 1];
+            let mut out = String::new();
+            let mut chars = inner.chars();
+            while let Some(ch) = chars.next() {
+                if ch == '\'' {
+                    panic!("unescaped quote in {:?}", quoted);
+                }
+                if ch != '\\' {
+                    out.push(ch);
+                    continue;
+                }
+                match chars.next() {
+                    Some('\\') => out.push('\\'),
+                    Some('\'') => out.push('\''),
+                    Some('n') => out.push('\n'),
+                    Some('r') => out.push('\r'),
+                    Some('t') => out.push('\t'),
+                    Some('u') => {
+                        let hex: String = chars.by_ref().take(4).collect();
+                        let code = u32::from_str_radix(&hex, 16).unwrap();
+                        out.push(char::from_u32(code).unwrap());
+                    }
+                    other => panic!("unknown escape {:?} in {:?}", other, quoted),
+                }
+            }
+            out
+        }
+        for val in TRICKY {
+            let quoted = py_quote(val);
+            assert!(quoted.starts_with('\'') && quoted.ends_with('\''));
+            assert!(!quoted.chars().any(|c| c.is_control()), "{:?}", quoted);
+            assert_eq!(unescape(&quoted), *val, "{:?}", quoted);
+        }
+        assert_eq!(py_quote("a\0b\\"), "'a\\u0000b\\\\'");
+    }
+
+    #[test]
+    fn csh_quote_round_trips_without_bare_bangs_or_newlines() {
+        // Quoted runs alternate with `\x` escapes outside the quotes; inside
+        // them `\` + newline stands for a newline.
+        fn unquote(quoted: &str) -> String {
+            let mut out = String::new();
+            let mut chars = quoted.chars();
+            let mut in_quotes = false;
+            while let Some(ch) = chars.next() {
+                match (in_quotes, ch) {
+                    (_, '\'') => in_quotes = !in_quotes,
+                    (_, '!') => panic!("bare ! in {:?}", quoted),
+                    (true, '\\') => match chars.next() {
+                        Some('\n') => out.push('\n'),
+                        Some(other) => {
+                            out.push('\\');
+                            if other == '\'' {
+                                in_quotes = false;
+                            } else {
+                                out.push(other);
+                            }
+                        }
+                        None => out.push('\\'),
+                    },
+                    (true, '\n') => panic!("bare newline in {:?}", quoted),
+                    (true, c) => out.push(c),
+                    (false, '\\') => out.push(chars.next().expect("escaped char")),
+                    (false, c) => panic!("unquoted {:?} in {:?}", c, quoted),
+                }
+            }
+            assert!(!in_quotes, "unterminated quote in {:?}", quoted);
+            out
+        }
+        for val in TRICKY {
+            let quoted = csh_quote(val);
+            assert_eq!(unquote(&quoted), *val, "{:?}", quoted);
+        }
+        assert_eq!(csh_quote("it's!\n"), "'it'\\''s'\\!'\\\n'");
     }
 }
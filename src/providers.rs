@@ -0,0 +1,197 @@
+use anyhow::{anyhow, Context, Result};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io::Read;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const DEFAULT_TTL: Duration = Duration::from_secs(30);
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A backend that can produce a variable's value on demand.
+///
+/// To add a backend, implement this trait and add a `ProviderRef` variant
+/// that constructs it in `ProviderRef::provider`.
+pub trait Provider {
+    /// Produces the current value, giving up after `timeout`.
+    fn resolve(&self, timeout: Duration) -> Result<String>;
+}
+
+/// Serializable description of where a reference value comes from.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(tag = "provider", rename_all = "lowercase")]
+pub enum ProviderRef {
+    File { path: PathBuf },
+    Cmd { command: String },
+}
+
+impl ProviderRef {
+    fn provider(&self) -> Box<dyn Provider + '_> {
+        match self {
+            ProviderRef::File { path } => Box::new(FileProvider { path }),
+            ProviderRef::Cmd { command } => Box::new(CommandProvider { command }),
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        match self {
+            ProviderRef::File { path } => format!("file {}", path.display()),
+            ProviderRef::Cmd { command } => format!("command `{}`", command),
+        }
+    }
+}
+
+pub struct FileProvider<'a> {
+    path: &'a Path,
+}
+
+impl Provider for FileProvider<'_> {
+    fn resolve(&self, _timeout: Duration) -> Result<String> {
+        let raw = fs::read_to_string(self.path)
+            .with_context(|| format!("reading {}", self.path.display()))?;
+        Ok(trim_trailing_newlines(raw))
+    }
+}
+
+/// Runs `sh -c COMMAND` and uses its stdout, like `$(...)` would.
+pub struct CommandProvider<'a> {
+    command: &'a str,
+}
+
+impl Provider for CommandProvider<'_> {
+    fn resolve(&self, timeout: Duration) -> Result<String> {
+        let deadline = Instant::now() + timeout;
+        // Its own process group, so a timeout also kills what it started
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(self.command)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .process_group(0)
+            .spawn()
+            .context("spawn sh")?;
+        let group = child.id() as libc::pid_t;
+
+        // Drain pipes on threads so a chatty command cannot block on a full
+        // pipe. A background process may keep them open after `sh` exits, so
+        // the results are waited for with the same deadline.
+        let out = read_on_thread(child.stdout.take().expect("piped stdout"));
+        let err = read_on_thread(child.stderr.take().expect("piped stderr"));
+
+        let timed_out = |child: &mut std::process::Child| {
+            kill_group(group);
+            let _ = child.wait();
+            anyhow!("timed out after {:?}", timeout)
+        };
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            if Instant::now() >= deadline {
+                return Err(timed_out(&mut child));
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+        let remaining = || deadline.saturating_duration_since(Instant::now());
+        let Ok(out) = out.recv_timeout(remaining()) else {
+            return Err(timed_out(&mut child));
+        };
+        let err = err.recv_timeout(remaining()).unwrap_or_default();
+
+        if !status.success() {
+            let err = String::from_utf8_lossy(&err);
+            let detail = err.lines().next().unwrap_or("").trim();
+            return Err(if detail.is_empty() {
+                anyhow!("exited with {}", status)
+            } else {
+                anyhow!("exited with {}: {}", status, detail)
+            });
+        }
+        let out = String::from_utf8(out).context("output is not valid utf-8")?;
+        Ok(trim_trailing_newlines(out))
+    }
+}
+
+fn read_on_thread(mut pipe: impl Read + Send + 'static) -> mpsc::Receiver<Vec<u8>> {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut buf = Vec::new();
+        let _ = pipe.read_to_end(&mut buf);
+        let _ = tx.send(buf);
+    });
+    rx
+}
+
+fn kill_group(group: libc::pid_t) {
+    // SAFETY: kill(2) with a negative pid signals that process group.
+    unsafe {
+        libc::kill(-group, libc::SIGKILL);
+    }
+}
+
+fn trim_trailing_newlines(mut s: String) -> String {
+    while s.ends_with('\n') || s.ends_with('\r') {
+        s.pop();
+    }
+    s
+}
+
+/// Resolves references with a short-lived cache so exports from many shells
+/// don't re-run the same command on every prompt. Failures are cached too, so
+/// a broken command isn't retried (and waited on) at every prompt either.
+#[derive(Debug)]
+pub struct ProviderCache {
+    ttl: Duration,
+    timeout: Duration,
+    entries: Mutex<HashMap<ProviderRef, (Instant, Resolved)>>,
+}
+
+/// A provider's value, or the message it failed with.
+type Resolved = Result<String, String>;
+
+impl Default for ProviderCache {
+    fn default() -> Self {
+        ProviderCache {
+            ttl: duration_from_env("ENVD_PROVIDER_TTL_SECS").unwrap_or(DEFAULT_TTL),
+            timeout: duration_from_env("ENVD_PROVIDER_TIMEOUT_SECS").unwrap_or(DEFAULT_TIMEOUT),
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl ProviderCache {
+    pub fn resolve(&self, r: &ProviderRef) -> Result<String> {
+        if let Some((at, value)) = self.entries.lock().get(r) {
+            if at.elapsed() < self.ttl {
+                return value.clone().map_err(|e| anyhow!(e));
+            }
+        }
+        let value = r
+            .provider()
+            .resolve(self.timeout)
+            .with_context(|| format!("provider {} failed", r.describe()))
+            .map_err(|e| format!("{:#}", e));
+        self.entries
+            .lock()
+            .insert(r.clone(), (Instant::now(), value.clone()));
+        value.map_err(|e| anyhow!(e))
+    }
+
+    pub fn invalidate(&self, r: &ProviderRef) {
+        self.entries.lock().remove(r);
+    }
+}
+
+fn duration_from_env(name: &str) -> Option<Duration> {
+    std::env::var(name)
+        .ok()
+        .and_then(|s| s.trim().parse::<u64>().ok())
+        .map(Duration::from_secs)
+}
@@ -0,0 +1,237 @@
+use anyhow::{anyhow, Context, Result};
+use argon2::Argon2;
+use base64::engine::general_purpose::STANDARD as BASE64_STANDARD;
+use base64::Engine;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use zeroize::Zeroizing;
+
+const KEY_LEN: usize = 32;
+const SALT_LEN: usize = 16;
+// Sealed with the active key on first unlock so later unlocks can detect a
+// wrong passphrase instead of silently producing garbage.
+const CHECK_PLAINTEXT: &str = "cmux-envd key check";
+
+/// Where the vault key comes from.
+#[derive(Debug, Clone)]
+pub enum KeySource {
+    /// Random key stored in a 0600 keyfile, created on first use.
+    Keyfile(PathBuf),
+    /// Key derived from a passphrase with Argon2; the salt lives in `salt`.
+    Passphrase { salt: PathBuf },
+}
+
+/// Ciphertext for a secret value. Only this form is ever serialized.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Sealed {
+    nonce: String,
+    data: String,
+}
+
+/// Holds the in-memory key used to seal and open secret values.
+#[derive(Debug)]
+pub struct Vault {
+    source: KeySource,
+    key: Option<Zeroizing<[u8; KEY_LEN]>>,
+    locked: bool,
+    check: Option<Sealed>,
+}
+
+impl Default for Vault {
+    fn default() -> Self {
+        Vault::new(KeySource::Keyfile(keyfile_path()))
+    }
+}
+
+impl Vault {
+    pub fn new(source: KeySource) -> Self {
+        Vault {
+            source,
+            key: None,
+            locked: false,
+            check: None,
+        }
+    }
+
+    /// Builds a passphrase-backed vault and unlocks it immediately.
+    pub fn with_passphrase(salt: PathBuf, passphrase: &str) -> Result<Self> {
+        let mut vault = Vault::new(KeySource::Passphrase { salt });
+        vault.unlock(Some(passphrase))?;
+        Ok(vault)
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked
+    }
+
+    pub fn needs_passphrase(&self) -> bool {
+        matches!(self.source, KeySource::Passphrase { .. })
+    }
+
+    /// Drops the in-memory key; secrets stay sealed until `unlock`.
+    pub fn lock(&mut self) {
+        self.key = None;
+        self.locked = true;
+    }
+
+    pub fn unlock(&mut self, passphrase: Option<&str>) -> Result<()> {
+        let key = match &self.source {
+            KeySource::Keyfile(path) => load_or_create_keyfile(path)?,
+            KeySource::Passphrase { salt } => {
+                let passphrase =
+                    passphrase.ok_or_else(|| anyhow!("a passphrase is required to unlock"))?;
+                let salt = load_or_create_salt(salt)?;
+                derive_key(passphrase, &salt)?
+            }
+        };
+        match &self.check {
+            Some(check) => {
+                let opened = open_with(&key, check).map_err(|_| anyhow!("wrong passphrase"))?;
+                if opened != CHECK_PLAINTEXT {
+                    return Err(anyhow!("wrong passphrase"));
+                }
+            }
+            None => self.check = Some(seal_with(&key, CHECK_PLAINTEXT)?),
+        }
+        self.key = Some(key);
+        self.locked = false;
+        Ok(())
+    }
+
+    pub fn seal(&mut self, plaintext: &str) -> Result<Sealed> {
+        let key = self.active_key()?;
+        seal_with(key, plaintext)
+    }
+
+    /// Opens a sealed value. Anything sealed implies the key was loaded, so a
+    /// missing key here always means the vault is locked.
+    pub fn open(&self, sealed: &Sealed) -> Result<String> {
+        match &self.key {
+            Some(key) if !self.locked => open_with(key, sealed),
+            _ => Err(anyhow!("secrets are locked; run `envctl unlock`")),
+        }
+    }
+
+    // Keyfile-backed vaults load their key lazily so the keyfile is only
+    // created once a secret is actually stored.
+    fn active_key(&mut self) -> Result<&[u8; KEY_LEN]> {
+        if self.locked {
+            return Err(anyhow!("secrets are locked; run `envctl unlock`"));
+        }
+        if self.key.is_none() {
+            if self.needs_passphrase() {
+                return Err(anyhow!("secrets are locked; run `envctl unlock`"));
+            }
+            self.unlock(None)?;
+        }
+        Ok(self.key.as_deref().expect("key loaded"))
+    }
+}
+
+pub fn keyfile_path() -> PathBuf {
+    if let Some(p) = std::env::var_os("ENVD_KEYFILE") {
+        if !p.is_empty() {
+            return PathBuf::from(p);
+        }
+    }
+    crate::config_dir().join("secret.key")
+}
+
+pub fn salt_path() -> PathBuf {
+    keyfile_path().with_extension("salt")
+}
+
+fn seal_with(key: &[u8; KEY_LEN], plaintext: &str) -> Result<Sealed> {
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(key));
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let data = cipher
+        .encrypt(&nonce, plaintext.as_bytes())
+        .map_err(|_| anyhow!("failed to encrypt secret"))?;
+    Ok(Sealed {
+        nonce: BASE64_STANDARD.encode(nonce),
+        data: BASE64_STANDARD.encode(data),
+    })
+}
+
+fn open_with(key: &[u8; KEY_LEN], sealed: &Sealed) -> Result<String> {
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(key));
+    let nonce = BASE64_STANDARD
+        .decode(&sealed.nonce)
+        .context("decode secret nonce")?;
+    if nonce.len() != 12 {
+        return Err(anyhow!("corrupt secret nonce"));
+    }
+    let data = BASE64_STANDARD
+        .decode(&sealed.data)
+        .context("decode secret data")?;
+    let plain = cipher
+        .decrypt(Nonce::from_slice(&nonce), data.as_slice())
+        .map_err(|_| anyhow!("failed to decrypt secret (wrong key?)"))?;
+    String::from_utf8(plain).context("secret is not valid utf-8")
+}
+
+fn derive_key(passphrase: &str, salt: &[u8]) -> Result<Zeroizing<[u8; KEY_LEN]>> {
+    let mut key = Zeroizing::new([0u8; KEY_LEN]);
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key[..])
+        .map_err(|e| anyhow!("derive key from passphrase: {}", e))?;
+    Ok(key)
+}
+
+fn load_or_create_keyfile(path: &Path) -> Result<Zeroizing<[u8; KEY_LEN]>> {
+    let bytes = read_or_create_private(path, KEY_LEN)?;
+    let mut key = Zeroizing::new([0u8; KEY_LEN]);
+    key.copy_from_slice(&bytes);
+    Ok(key)
+}
+
+fn load_or_create_salt(path: &Path) -> Result<Vec<u8>> {
+    read_or_create_private(path, SALT_LEN).map(|b| b.to_vec())
+}
+
+// Reads `len` random bytes (base64 on disk) from `path`, creating the file
+// with mode 0600 when missing. Refuses files readable by other users.
+fn read_or_create_private(path: &Path, len: usize) -> Result<Zeroizing<Vec<u8>>> {
+    if path.exists() {
+        let meta = fs::metadata(path).with_context(|| format!("stat {}", path.display()))?;
+        if meta.permissions().mode() & 0o077 != 0 {
+            return Err(anyhow!(
+                "{} is accessible by other users; chmod 600 it",
+                path.display()
+            ));
+        }
+        let text = Zeroizing::new(
+            fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?,
+        );
+        let bytes = Zeroizing::new(
+            BASE64_STANDARD
+                .decode(text.trim())
+                .with_context(|| format!("decoding {}", path.display()))?,
+        );
+        if bytes.len() != len {
+            return Err(anyhow!("{} has unexpected length", path.display()));
+        }
+        return Ok(bytes);
+    }
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).with_context(|| format!("creating dir {}", parent.display()))?;
+    }
+    let bytes =
+        Zeroizing::new(ChaCha20Poly1305::generate_key(&mut OsRng)[..len.min(KEY_LEN)].to_vec());
+    let mut f = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+        .with_context(|| format!("creating {}", path.display()))?;
+    let encoded = Zeroizing::new(BASE64_STANDARD.encode(bytes.as_slice()));
+    f.write_all(encoded.as_bytes())?;
+    f.write_all(b"\n")?;
+    Ok(bytes)
+}
@@ -0,0 +1,188 @@
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+// Editors often write a file in several steps; wait for them to settle
+const SETTLE: Duration = Duration::from_millis(50);
+
+/// Calls `on_change` whenever one of `files`, which must share a directory,
+/// may have been written, created, replaced or removed, until `stop` is set.
+/// It is also called once as soon as the files are being watched, so a change
+/// made before then is not missed.
+///
+/// Uses inotify on the files' directory, so files replaced by rename (as
+/// most editors save) keep being followed. Falls back to polling the files'
+/// metadata when inotify is unavailable, or always when
+/// `ENVD_LINK_POLL_MS` is set.
+pub fn watch_files(files: &[PathBuf], stop: &AtomicBool, mut on_change: impl FnMut()) {
+    if let Some(interval) = poll_interval_from_env() {
+        return poll(files, stop, interval, on_change);
+    }
+    #[cfg(target_os = "linux")]
+    if let Some(inotify) = inotify::Inotify::watch_dir_of(files) {
+        on_change();
+        if inotify.run(files, stop, &mut on_change) {
+            return;
+        }
+        // The directory went away; keep watching the paths the slow way
+    }
+    poll(files, stop, DEFAULT_POLL_INTERVAL, on_change)
+}
+
+fn poll_interval_from_env() -> Option<Duration> {
+    std::env::var("ENVD_LINK_POLL_MS")
+        .ok()
+        .and_then(|s| s.trim().parse::<u64>().ok())
+        .map(Duration::from_millis)
+}
+
+// What changes when a file is written or replaced
+fn stamp(file: &Path) -> Option<(Option<SystemTime>, u64, u64)> {
+    let meta = fs::metadata(file).ok()?;
+    Some((meta.modified().ok(), meta.len(), meta.ino()))
+}
+
+fn poll(files: &[PathBuf], stop: &AtomicBool, interval: Duration, mut on_change: impl FnMut()) {
+    let stamps = || files.iter().map(|f| stamp(f)).collect::<Vec<_>>();
+    let mut last = stamps();
+    on_change();
+    while !stop.load(Ordering::Relaxed) {
+        thread::sleep(interval);
+        let now = stamps();
+        if now != last {
+            last = now;
+            on_change();
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod inotify {
+    use super::SETTLE;
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+
+    // How often a blocked watcher wakes up to check whether it was stopped
+    const STOP_CHECK_MS: i32 = 250;
+
+    pub(super) struct Inotify {
+        fd: libc::c_int,
+    }
+
+    impl Inotify {
+        pub(super) fn watch_dir_of(files: &[PathBuf]) -> Option<Inotify> {
+            let dir = files.first()?.parent()?;
+            let dir = CString::new(dir.as_os_str().as_bytes()).ok()?;
+            let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC | libc::IN_NONBLOCK) };
+            if fd < 0 {
+                return None;
+            }
+            let inotify = Inotify { fd };
+            let mask = libc::IN_CLOSE_WRITE
+                | libc::IN_MODIFY
+                | libc::IN_CREATE
+                | libc::IN_DELETE
+                | libc::IN_MOVED_FROM
+                | libc::IN_MOVED_TO;
+            if unsafe { libc::inotify_add_watch(fd, dir.as_ptr(), mask) } < 0 {
+                return None;
+            }
+            Some(inotify)
+        }
+
+        // Returns false if the watch ended because the directory disappeared,
+        // true once stopped.
+        pub(super) fn run(
+            &self,
+            files: &[PathBuf],
+            stop: &AtomicBool,
+            on_change: &mut impl FnMut(),
+        ) -> bool {
+            let names: Vec<&[u8]> = files
+                .iter()
+                .filter_map(|f| f.file_name())
+                .map(|n| n.as_bytes())
+                .collect();
+            while !stop.load(Ordering::Relaxed) {
+                let mut pfd = libc::pollfd {
+                    fd: self.fd,
+                    events: libc::POLLIN,
+                    revents: 0,
+                };
+                if unsafe { libc::poll(&mut pfd, 1, STOP_CHECK_MS) } <= 0 {
+                    continue;
+                }
+                let (relevant, mut alive) = self.drain(&names);
+                if relevant {
+                    // Fold the rest of a multi-step save into this change
+                    thread::sleep(SETTLE);
+                    alive &= self.drain(&names).1;
+                    on_change();
+                }
+                if !alive {
+                    return false;
+                }
+            }
+            true
+        }
+
+        // Reads all queued events. Returns whether any concerned one of
+        // `names` (or events were lost), and whether the watch is still in place.
+        fn drain(&self, names: &[&[u8]]) -> (bool, bool) {
+            let mut relevant = false;
+            let mut alive = true;
+            // inotify_event is a u32-aligned header followed by a NUL-padded name
+            let mut buf = [0u32; 1024];
+            loop {
+                // SAFETY: `buf` is valid for writes of its full size.
+                let len = unsafe {
+                    libc::read(
+                        self.fd,
+                        buf.as_mut_ptr() as *mut libc::c_void,
+                        std::mem::size_of_val(&buf),
+                    )
+                };
+                if len <= 0 {
+                    break;
+                }
+                // SAFETY: the kernel initialized the first `len` bytes.
+                let bytes =
+                    unsafe { std::slice::from_raw_parts(buf.as_ptr() as *const u8, len as usize) };
+                let header = std::mem::size_of::<libc::inotify_event>();
+                let mut off = 0;
+                while off + header <= bytes.len() {
+                    // SAFETY: a whole header lies at `off`, as checked above.
+                    let event: libc::inotify_event =
+                        unsafe { std::ptr::read_unaligned(bytes[off..].as_ptr() as *const _) };
+                    let name_len = event.len as usize;
+                    let event_name =
+                        &bytes[off + header..(off + header + name_len).min(bytes.len())];
+                    let event_name = event_name.split(|b| *b == 0).next().unwrap_or_default();
+                    if event.mask & libc::IN_Q_OVERFLOW != 0 || names.contains(&event_name) {
+                        relevant = true;
+                    }
+                    if event.mask & libc::IN_IGNORED != 0 {
+                        alive = false;
+                    }
+                    off += header + name_len;
+                }
+            }
+            (relevant, alive)
+        }
+    }
+
+    impl Drop for Inotify {
+        fn drop(&mut self) {
+            unsafe {
+                libc::close(self.fd);
+            }
+        }
+    }
+}
@@ -4,6 +4,7 @@ use expectrl::{spawn, ControlCode};
 use predicates::prelude::*;
 use std::fs;
 use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
 use std::process::{Command, Stdio};
 use std::thread;
 use std::time::{Duration, Instant};
@@ -29,33 +30,184 @@ fn kill_envd_by_pid(tmp: &TempDir) {
 }
 
 fn start_envd_with_runtime(tmp: &TempDir) -> std::process::Child {
+    start_envd_with_env(tmp, &[])
+}
+
+fn start_envd_with_config(tmp: &TempDir, config: &str) -> std::process::Child {
+    let path = tmp.path().join("envd-config.json");
+    fs::write(&path, config).unwrap();
+    start_envd_with_env(tmp, &[("ENVD_CONFIG", path.to_str().unwrap())])
+}
+
+// A config path with no file behind it, so envd never reads the real
+// ~/.config/cmux-envd/config.json
+fn no_config(tmp: &TempDir) -> std::path::PathBuf {
+    tmp.path().join("no-envd-config.json")
+}
+
+fn start_envd_with_env(tmp: &TempDir, envs: &[(&str, &str)]) -> std::process::Child {
     let mut cmd = Command::cargo_bin("envd").expect("binary envd");
     cmd.env("XDG_RUNTIME_DIR", tmp.path());
+    cmd.env("ENVD_KEYFILE", tmp.path().join("secret.key"));
+    cmd.env("XDG_STATE_HOME", tmp.path());
+    // `envs` come later, so start_envd_with_config's ENVD_CONFIG wins
+    cmd.env("ENVD_CONFIG", no_config(tmp));
+    cmd.envs(envs.iter().copied());
     cmd.stdout(Stdio::null());
     cmd.stderr(Stdio::null());
     let mut child = cmd.spawn().expect("start envd");
-    // Wait for socket to show up
+    wait_for_envd(tmp, &mut child, Duration::from_secs(3));
+    child
+}
+
+// Waits until envd accepts connections. The socket file appears at bind(),
+// before listen(); a client connecting in between is refused and would
+// autostart a second envd without this one's environment.
+fn wait_for_envd(tmp: &TempDir, child: &mut std::process::Child, timeout: Duration) {
     let sock = tmp.path().join("cmux-envd/envd.sock");
     let start = Instant::now();
-    while !sock.exists() {
-        if start.elapsed() > Duration::from_secs(3) {
+    while std::os::unix::net::UnixStream::connect(&sock).is_err() {
+        if start.elapsed() > timeout {
             let _ = child.kill();
             panic!("envd socket did not appear: {}", sock.display());
         }
         thread::sleep(Duration::from_millis(50));
     }
+}
+
+fn start_envd_with_passphrase(tmp: &TempDir, passphrase: &str) -> std::process::Child {
+    let mut cmd = Command::cargo_bin("envd").expect("binary envd");
+    cmd.env("XDG_RUNTIME_DIR", tmp.path());
+    cmd.env("ENVD_KEYFILE", tmp.path().join("secret.key"));
+    cmd.env("XDG_STATE_HOME", tmp.path());
+    cmd.env("ENVD_CONFIG", no_config(tmp));
+    cmd.arg("--passphrase");
+    cmd.stdin(Stdio::piped());
+    cmd.stdout(Stdio::null());
+    cmd.stderr(Stdio::null());
+    let mut child = cmd.spawn().expect("start envd");
+    {
+        use std::io::Write;
+        let mut stdin = child.stdin.take().unwrap();
+        writeln!(stdin, "{}", passphrase).unwrap();
+    }
+    wait_for_envd(tmp, &mut child, Duration::from_secs(10));
     child
 }
 
 fn run_envctl(tmp: &TempDir, args: &[&str]) -> assert_cmd::assert::Assert {
     let mut cmd = Command::cargo_bin("envctl").unwrap();
     cmd.env("XDG_RUNTIME_DIR", tmp.path());
+    cmd.env("ENVD_KEYFILE", tmp.path().join("secret.key"));
+    cmd.env("XDG_STATE_HOME", tmp.path());
+    // For the envd it autostarts, if any
+    cmd.env("ENVD_CONFIG", no_config(tmp));
     for a in args {
         cmd.arg(a);
     }
     cmd.assert()
 }
 
+// Sends `req` straight to envd, as a client other than envctl would
+fn send_request(tmp: &TempDir, req: &cmux_env::Request) -> cmux_env::Response {
+    use std::io::{BufRead, BufReader, Write};
+    let mut stream =
+        std::os::unix::net::UnixStream::connect(tmp.path().join("cmux-envd/envd.sock")).unwrap();
+    writeln!(stream, "{}", serde_json::to_string(req).unwrap()).unwrap();
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line).unwrap();
+    serde_json::from_str(&line).unwrap()
+}
+
+// Answers every request on tmp's socket with `entries`, standing in for an
+// envd that hands out keys the real one refuses to store
+fn fake_envd(tmp: &TempDir, entries: &[(&str, &str)]) {
+    use std::io::{BufRead, BufReader, Write};
+    let dir = tmp.path().join("cmux-envd");
+    fs::create_dir_all(&dir).unwrap();
+    let listener = std::os::unix::net::UnixListener::bind(dir.join("envd.sock")).unwrap();
+    let reply = serde_json::to_string(&cmux_env::Response::Map {
+        entries: entries
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect(),
+        secrets: Vec::new(),
+        refs: Vec::new(),
+    })
+    .unwrap();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(mut stream) = stream else { return };
+            let mut line = String::new();
+            let _ = BufReader::new(&stream).read_line(&mut line);
+            let _ = writeln!(stream, "{}", reply);
+        }
+    });
+}
+
+#[test]
+fn invalid_keys_are_rejected_and_never_written_unquoted() {
+    use cmux_env::{ProviderRef, Request, Response, Scope};
+    let tmp = TempDir::new().unwrap();
+    let mut child = start_envd_with_runtime(&tmp);
+    let marker = tmp.path().join("pwned");
+    let evil = format!("X$(touch {})", marker.display());
+
+    run_envctl(&tmp, &["set", &format!("{}=1", evil)])
+        .failure()
+        .stderr(predicate::str::contains("invalid key"));
+    run_envctl(&tmp, &["set", "9LIVES", "--from-cmd", "echo x"])
+        .failure()
+        .stderr(predicate::str::contains("invalid key \"9LIVES\""));
+
+    // Clients that skip envctl's parsing are checked by envd itself
+    for req in [
+        Request::Set {
+            key: evil.clone(),
+            value: "1".into(),
+            scope: Scope::Global,
+            secret: false,
+        },
+        Request::SetRef {
+            key: "A\nB".into(),
+            provider: ProviderRef::Cmd {
+                command: "echo x".into(),
+            },
+            scope: Scope::Global,
+        },
+        Request::Load {
+            entries: vec![("OK".into(), "1".into()), ("BAD KEY".into(), "1".into())],
+            scope: Scope::Global,
+            secret: false,
+        },
+    ] {
+        match send_request(&tmp, &req) {
+            Response::Error { message, .. } => {
+                assert!(message.contains("invalid key"), "{}", message)
+            }
+            other => panic!("{:?} was accepted: {:?}", req, other),
+        }
+    }
+    run_envctl(&tmp, &["get", "OK"]).code(3);
+
+    let list = run_envctl(&tmp, &["list", "--format", "shell"])
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    Command::new("sh")
+        .arg("-c")
+        .arg("eval \"$1\"")
+        .arg("sh")
+        .arg(String::from_utf8(list).unwrap())
+        .assert()
+        .success();
+    assert!(!marker.exists());
+
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
 #[test]
 fn ping_and_status() {
     let tmp = TempDir::new().unwrap();
@@ -538,6 +690,45 @@ fn install_hook_multiple_shells_share_state() {
     let _ = child.wait();
 }
 
+fn have_shell(name: &str) -> bool {
+    Command::new("sh")
+        .arg("-c")
+        .arg(format!("command -v {} >/dev/null 2>&1", name))
+        .status()
+        .map(|s| s.success())
+        .unwrap_or(false)
+}
+
+// For the #[ignore]d tests that run a shell: they fail instead of passing
+// vacuously when it is missing. CI installs the shells and runs them.
+fn require_shell(name: &str) {
+    assert!(have_shell(name), "{} is not installed", name);
+}
+
+// $PATH with the envctl under test first, for shells running a hook
+fn path_with_envctl() -> String {
+    format!(
+        "{}:{}",
+        cargo_bin("envctl").parent().unwrap().display(),
+        std::env::var("PATH").unwrap()
+    )
+}
+
+// Sets TRICKY, sets and unsets GONE, and returns `envctl export SHELL` of it all
+fn export_tricky(tmp: &TempDir, shell: &str, tricky: &str) -> String {
+    run_envctl(tmp, &["set", &format!("TRICKY={}", tricky)]).success();
+    run_envctl(tmp, &["set", "GONE=1"]).success();
+    run_envctl(tmp, &["unset", "GONE"]).success();
+    let out = Command::cargo_bin("envctl")
+        .unwrap()
+        .env("XDG_RUNTIME_DIR", tmp.path())
+        .args(["export", shell, "--since", "0"])
+        .output()
+        .unwrap();
+    assert!(out.status.success());
+    String::from_utf8(out.stdout).unwrap()
+}
+
 fn shell_escape(s: &str) -> String {
     let mut out = String::with_capacity(s.len() + 2);
     out.push('\'');
@@ -687,3 +878,2888 @@ fn load_from_base64_invalid_payload_fails() {
     let _ = child.kill();
     let _ = child.wait();
 }
+
+#[test]
+fn secret_values_lock_and_unlock_with_keyfile() {
+    let tmp = TempDir::new().unwrap();
+    let mut child = start_envd_with_runtime(&tmp);
+
+    let keyfile = tmp.path().join("secret.key");
+    assert!(!keyfile.exists(), "keyfile should be created lazily");
+
+    run_envctl(&tmp, &["set", "TOKEN=s3cr3t", "--secret"]).success();
+    let mode = fs::metadata(&keyfile).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+
+    run_envctl(&tmp, &["export", "bash", "--since", "0"])
+        .success()
+        .stdout(predicate::str::contains("export TOKEN='s3cr3t'"));
+    run_envctl(&tmp, &["status"])
+        .success()
+        .stdout(predicate::str::contains("secrets: 1\n"));
+
+    run_envctl(&tmp, &["lock"]).success();
+    run_envctl(&tmp, &["status"])
+        .success()
+        .stdout(predicate::str::contains("secrets: 1 (locked)"));
+    run_envctl(&tmp, &["export", "bash", "--since", "0"])
+        .failure()
+        .stdout(predicate::str::is_empty())
+        .stderr(predicate::str::contains("cannot read secret TOKEN"));
+    run_envctl(&tmp, &["get", "TOKEN"])
+        .failure()
+        .stderr(predicate::str::contains("locked"));
+    run_envctl(&tmp, &["set", "OTHER=x", "--secret"])
+        .failure()
+        .stderr(predicate::str::contains("locked"));
+
+    run_envctl(&tmp, &["unlock"]).success();
+    run_envctl(&tmp, &["get", "TOKEN"])
+        .success()
+        .stdout("s3cr3t\n");
+
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[test]
+fn secret_values_unlock_with_passphrase() {
+    let tmp = TempDir::new().unwrap();
+    let mut child = start_envd_with_passphrase(&tmp, "correct horse");
+
+    run_envctl(&tmp, &["set", "DB_PASS=hunter2", "--secret"]).success();
+    run_envctl(&tmp, &["lock"]).success();
+    run_envctl(&tmp, &["unlock"])
+        .failure()
+        .stderr(predicate::str::contains("passphrase is required"));
+
+    let mut wrong = assert_cmd::Command::cargo_bin("envctl").unwrap();
+    wrong.env("XDG_RUNTIME_DIR", tmp.path());
+    wrong.args(["unlock", "--passphrase"]);
+    wrong.write_stdin("battery staple\n");
+    wrong
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("wrong passphrase"));
+
+    let mut right = assert_cmd::Command::cargo_bin("envctl").unwrap();
+    right.env("XDG_RUNTIME_DIR", tmp.path());
+    right.args(["unlock", "--passphrase"]);
+    right.write_stdin("correct horse\n");
+    right.assert().success();
+
+    run_envctl(&tmp, &["get", "DB_PASS"])
+        .success()
+        .stdout("hunter2\n");
+
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[test]
+fn provider_references_resolve_at_export() {
+    let tmp = TempDir::new().unwrap();
+    let mut child = start_envd_with_runtime(&tmp);
+
+    let secret_file = tmp.path().join("db-pass");
+    fs::write(&secret_file, "from-file\n").unwrap();
+
+    run_envctl(
+        &tmp,
+        &[
+            "set",
+            "DB_PASS",
+            "--from-file",
+            secret_file.to_str().unwrap(),
+        ],
+    )
+    .success();
+    run_envctl(&tmp, &["set", "TOKEN", "--from-cmd", "printf 'from-cmd'"]).success();
+    run_envctl(&tmp, &["set", "TOKEN=x", "--from-cmd", "true"])
+        .failure()
+        .stderr(predicate::str::contains("expected KEY without a value"));
+
+    run_envctl(&tmp, &["export", "bash", "--since", "0"])
+        .success()
+        .stdout(predicate::str::contains("export DB_PASS='from-file'"))
+        .stdout(predicate::str::contains("export TOKEN='from-cmd'"));
+    run_envctl(&tmp, &["get", "TOKEN"])
+        .success()
+        .stdout("from-cmd\n");
+
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[test]
+fn failing_provider_reports_error_in_export_script() {
+    let tmp = TempDir::new().unwrap();
+    let mut child = start_envd_with_runtime(&tmp);
+
+    run_envctl(&tmp, &["set", "GOOD=ok"]).success();
+    run_envctl(&tmp, &["set", "BAD", "--from-cmd", "echo boom >&2; exit 3"]).success();
+
+    let out = Command::cargo_bin("envctl")
+        .unwrap()
+        .env("XDG_RUNTIME_DIR", tmp.path())
+        .args(["export", "bash", "--since", "0"])
+        .output()
+        .unwrap();
+    assert!(out.status.success());
+    let script = String::from_utf8_lossy(&out.stdout).to_string();
+    assert!(script.contains("export GOOD='ok'"), "{}", script);
+    assert!(!script.contains("export BAD="), "{}", script);
+    assert!(script.contains("cannot resolve BAD"), "{}", script);
+
+    let mut bash = Command::new("bash");
+    bash.arg("-c")
+        .arg(format!("{}\nprintf '%s' \"$GOOD\"", script));
+    let ran = bash.output().unwrap();
+    assert!(ran.status.success());
+    assert_eq!(String::from_utf8_lossy(&ran.stdout), "ok");
+    let stderr = String::from_utf8_lossy(&ran.stderr);
+    assert!(stderr.contains("envctl: cannot resolve BAD"), "{}", stderr);
+    assert!(stderr.contains("boom"), "{}", stderr);
+
+    run_envctl(&tmp, &["get", "BAD"])
+        .failure()
+        .stderr(predicate::str::contains("cannot resolve BAD"));
+
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[test]
+fn failed_provider_is_retried_by_the_next_export() {
+    let tmp = TempDir::new().unwrap();
+    let mut child = start_envd_with_env(&tmp, &[("ENVD_PROVIDER_TTL_SECS", "1")]);
+    let ready = tmp.path().join("ready");
+    let cmd = format!(
+        "if [ -f '{}' ]; then printf ok; else exit 1; fi",
+        ready.display()
+    );
+
+    run_envctl(&tmp, &["set", "GOOD=1"]).success();
+    run_envctl(&tmp, &["set", "TOKEN", "--from-cmd", &cmd]).success();
+    run_envctl(&tmp, &["set", "LATER=2"]).success();
+
+    let export = |since: u64| -> serde_json::Value {
+        let since = since.to_string();
+        let out = run_envctl(&tmp, &["export", "json", "--since", &since])
+            .success()
+            .get_output()
+            .stdout
+            .clone();
+        serde_json::from_slice(&out).unwrap()
+    };
+
+    // The generation stops short of TOKEN's change so it is asked for again
+    let diff = export(0);
+    assert_eq!(
+        diff["set"],
+        serde_json::json!({ "GOOD": "1", "LATER": "2" })
+    );
+    assert!(diff["errors"].to_string().contains("cannot resolve TOKEN"));
+    assert_eq!(diff["generation"], serde_json::json!(1));
+
+    // Once the cached failure expires
+    fs::write(&ready, "").unwrap();
+    thread::sleep(Duration::from_millis(1200));
+    let diff = export(1);
+    assert_eq!(
+        diff["set"],
+        serde_json::json!({ "TOKEN": "ok", "LATER": "2" })
+    );
+    assert_eq!(diff["errors"], serde_json::json!([]));
+    assert_eq!(diff["generation"], serde_json::json!(3));
+
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[test]
+fn slow_provider_times_out_without_blocking_other_clients() {
+    let tmp = TempDir::new().unwrap();
+    let mut child = start_envd_with_env(&tmp, &[("ENVD_PROVIDER_TIMEOUT_SECS", "1")]);
+    let pidfile = tmp.path().join("grandchild.pid");
+    // `sh` exits at once, but a background process keeps stdout open
+    let cmd = format!(
+        "sh -c 'echo $$ > {}; sleep 30' & printf early",
+        pidfile.display()
+    );
+    run_envctl(&tmp, &["set", "SLOW", "--from-cmd", &cmd]).success();
+    run_envctl(&tmp, &["set", "FAST=1"]).success();
+
+    let envctl = cargo_bin("envctl");
+    let runtime = tmp.path().to_path_buf();
+    let slow = thread::spawn(move || {
+        let start = Instant::now();
+        let out = Command::new(envctl)
+            .env("XDG_RUNTIME_DIR", runtime)
+            .args(["get", "SLOW"])
+            .output()
+            .unwrap();
+        (start.elapsed(), out)
+    });
+    thread::sleep(Duration::from_millis(300));
+    let start = Instant::now();
+    run_envctl(&tmp, &["get", "FAST"]).success().stdout("1\n");
+    assert!(
+        start.elapsed() < Duration::from_millis(500),
+        "get waited for the provider: {:?}",
+        start.elapsed()
+    );
+
+    let (elapsed, out) = slow.join().unwrap();
+    assert!(!out.status.success());
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains("timed out after 1s"), "{}", stderr);
+    assert!(elapsed < Duration::from_secs(5), "{:?}", elapsed);
+
+    // The whole process group was killed, not just `sh`
+    let pid = fs::read_to_string(&pidfile).unwrap();
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid.trim())).unwrap_or_default();
+    let state = stat
+        .rsplit(')')
+        .next()
+        .unwrap_or("")
+        .split_whitespace()
+        .next();
+    assert!(matches!(state, None | Some("Z")), "{}", stat);
+
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[test]
+fn provider_results_are_cached_for_the_ttl() {
+    let tmp = TempDir::new().unwrap();
+    let mut child = start_envd_with_env(&tmp, &[("ENVD_PROVIDER_TTL_SECS", "1")]);
+    let counter = tmp.path().join("runs");
+    let cmd = format!(
+        "n=$(cat '{0}' 2>/dev/null || echo 0); n=$((n + 1)); echo $n > '{0}'; printf %s $n",
+        counter.display()
+    );
+    run_envctl(&tmp, &["set", "RUNS", "--from-cmd", &cmd]).success();
+
+    run_envctl(&tmp, &["get", "RUNS"]).success().stdout("1\n");
+    run_envctl(&tmp, &["get", "RUNS"]).success().stdout("1\n");
+    thread::sleep(Duration::from_millis(1200));
+    run_envctl(&tmp, &["get", "RUNS"]).success().stdout("2\n");
+
+    // Setting the reference again drops the cached value
+    run_envctl(&tmp, &["set", "RUNS", "--from-cmd", &cmd]).success();
+    run_envctl(&tmp, &["get", "RUNS"]).success().stdout("3\n");
+
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[test]
+fn provider_failures_are_cached_for_the_ttl() {
+    let tmp = TempDir::new().unwrap();
+    let mut child = start_envd_with_env(&tmp, &[("ENVD_PROVIDER_TTL_SECS", "1")]);
+    let counter = tmp.path().join("runs");
+    let cmd = format!("echo run >> '{}'; exit 1", counter.display());
+    run_envctl(&tmp, &["set", "BROKEN", "--from-cmd", &cmd]).success();
+    let runs = || {
+        fs::read_to_string(&counter)
+            .unwrap_or_default()
+            .lines()
+            .count()
+    };
+
+    for _ in 0..2 {
+        let out = run_envctl(&tmp, &["export", "json", "--since", "0"])
+            .success()
+            .get_output()
+            .stdout
+            .clone();
+        let diff: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert!(diff["errors"].to_string().contains("cannot resolve BROKEN"));
+    }
+    assert_eq!(runs(), 1);
+    run_envctl(&tmp, &["get", "BROKEN"])
+        .failure()
+        .stderr(predicate::str::contains("exited with"));
+    assert_eq!(runs(), 1);
+
+    thread::sleep(Duration::from_millis(1200));
+    run_envctl(&tmp, &["get", "BROKEN"]).failure();
+    assert_eq!(runs(), 2);
+
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[test]
+fn acl_rules_reject_writes_with_permission_exit_code() {
+    let tmp = TempDir::new().unwrap();
+    let uid = unsafe { libc::getuid() };
+    let locked_dir = tmp.path().join("locked");
+    fs::create_dir_all(&locked_dir).unwrap();
+    let config = format!(
+        r#"{{"acl": [
+            {{"keys": "PROD_*", "writers": [{other}]}},
+            {{"keys": "OWNED_*", "writers": [{uid}]}},
+            {{"dir": "{dir}", "writers": [{other}]}}
+        ]}}"#,
+        other = uid + 4242,
+        uid = uid,
+        dir = locked_dir.display()
+    );
+    let mut child = start_envd_with_config(&tmp, &config);
+
+    run_envctl(&tmp, &["set", "PROD_DB=x"])
+        .code(77)
+        .stderr(predicate::str::contains("permission denied"));
+    run_envctl(&tmp, &["unset", "PROD_DB"]).code(77);
+    run_envctl(&tmp, &["set", "OWNED_THING=1"]).success();
+    run_envctl(&tmp, &["set", "FREE=1"]).success();
+    run_envctl(
+        &tmp,
+        &["set", "FREE=2", "--dir", locked_dir.to_str().unwrap()],
+    )
+    .code(77);
+
+    // A load touching a protected key is rejected as a whole
+    let mut load = assert_cmd::Command::cargo_bin("envctl").unwrap();
+    load.env("XDG_RUNTIME_DIR", tmp.path());
+    load.args(["load", "-"]);
+    load.write_stdin("NEW_KEY=1\nPROD_API=2\n");
+    load.assert().code(77);
+    run_envctl(&tmp, &["get", "NEW_KEY"]).code(3).stdout("");
+
+    run_envctl(&tmp, &["get", "OWNED_THING"])
+        .success()
+        .stdout("1\n");
+
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[test]
+fn provider_refs_are_limited_to_provider_writers() {
+    let tmp = TempDir::new().unwrap();
+    let uid = unsafe { libc::getuid() };
+    let config = format!(r#"{{"provider_writers": [{}]}}"#, uid + 4242);
+    let mut child = start_envd_with_config(&tmp, &config);
+    let marker = tmp.path().join("ran");
+
+    run_envctl(
+        &tmp,
+        &[
+            "set",
+            "TOKEN",
+            "--from-cmd",
+            &format!("touch {}", marker.display()),
+        ],
+    )
+    .code(77)
+    .stderr(predicate::str::contains(format!(
+        "uid {} may not point TOKEN at a provider",
+        uid
+    )));
+    run_envctl(&tmp, &["set", "TOKEN", "--from-file", "/etc/hostname"]).code(77);
+    run_envctl(&tmp, &["get", "TOKEN"]).code(3);
+    assert!(!marker.exists());
+    // Plain values are unaffected
+    run_envctl(&tmp, &["set", "TOKEN=x"]).success();
+
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[test]
+fn audit_log_records_mutations_with_caller() {
+    let tmp = TempDir::new().unwrap();
+    let mut child = start_envd_with_runtime(&tmp);
+    let uid = unsafe { libc::getuid() };
+
+    run_envctl(&tmp, &["set", "FOO=1"]).success();
+    run_envctl(&tmp, &["set", "FOO=1"]).success(); // no change, not recorded
+    run_envctl(&tmp, &["set", "FOO=2"]).success();
+    run_envctl(&tmp, &["unset", "FOO"]).success();
+    let mut load = assert_cmd::Command::cargo_bin("envctl").unwrap();
+    load.env("XDG_RUNTIME_DIR", tmp.path());
+    load.args(["load", "-"]);
+    load.write_stdin("BAR=x\n");
+    load.assert().success();
+
+    assert!(tmp.path().join("cmux-envd/audit.log").exists());
+
+    let out = Command::cargo_bin("envctl")
+        .unwrap()
+        .env("XDG_RUNTIME_DIR", tmp.path())
+        .args(["log", "FOO"])
+        .output()
+        .unwrap();
+    assert!(out.status.success());
+    let text = String::from_utf8_lossy(&out.stdout).to_string();
+    let lines: Vec<&str> = text.lines().collect();
+    assert_eq!(lines.len(), 3, "{}", text);
+    assert!(lines[0].contains(" set FOO [global]"), "{}", text);
+    assert!(lines[0].contains("old=-"), "{}", text);
+    assert!(lines[2].contains(" unset FOO [global]"), "{}", text);
+    assert!(lines[2].ends_with("new=-"), "{}", text);
+    for line in &lines {
+        assert!(line.contains(&format!("uid={}", uid)), "{}", line);
+        assert!(line.contains("exe=") && line.contains("envctl"), "{}", line);
+    }
+
+    run_envctl(&tmp, &["log"])
+        .success()
+        .stdout(predicate::str::contains(" load BAR [global]"));
+
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[test]
+fn audit_log_rotates_by_size() {
+    let tmp = TempDir::new().unwrap();
+    let mut child = start_envd_with_config(&tmp, r#"{"audit": {"max_bytes": 300, "keep": 2}}"#);
+
+    for i in 0..8 {
+        run_envctl(&tmp, &["set", &format!("ROT={}", i)]).success();
+    }
+    let log = tmp.path().join("cmux-envd/audit.log");
+    assert!(log.with_extension("log.1").exists());
+    assert!(!log.with_extension("log.3").exists());
+
+    let out = Command::cargo_bin("envctl")
+        .unwrap()
+        .env("XDG_RUNTIME_DIR", tmp.path())
+        .args(["log", "ROT", "--limit", "2"])
+        .output()
+        .unwrap();
+    assert!(out.status.success());
+    let text = String::from_utf8_lossy(&out.stdout).to_string();
+    assert_eq!(text.lines().count(), 2, "{}", text);
+    assert!(text.lines().last().unwrap().contains("gen=8"), "{}", text);
+
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[test]
+fn history_and_rollback_restore_previous_values() {
+    let tmp = TempDir::new().unwrap();
+    let mut child = start_envd_with_runtime(&tmp);
+    let proj = tmp.path().join("proj");
+    fs::create_dir_all(&proj).unwrap();
+
+    run_envctl(&tmp, &["set", "A=1"]).success(); // gen 1
+    run_envctl(&tmp, &["set", "A=2"]).success(); // gen 2
+    run_envctl(&tmp, &["set", "B=x"]).success(); // gen 3
+    run_envctl(&tmp, &["unset", "A"]).success(); // gen 4
+    run_envctl(&tmp, &["set", "A=dir", "--dir", proj.to_str().unwrap()]).success(); // gen 5
+
+    let out = Command::cargo_bin("envctl")
+        .unwrap()
+        .env("XDG_RUNTIME_DIR", tmp.path())
+        .args(["history", "A"])
+        .output()
+        .unwrap();
+    assert!(out.status.success());
+    let text = String::from_utf8_lossy(&out.stdout).to_string();
+    let lines: Vec<&str> = text.lines().collect();
+    assert_eq!(lines.len(), 4, "{}", text);
+    assert!(lines[0].starts_with("gen=1 ") && lines[0].ends_with("[global] A=1"));
+    assert!(lines[1].ends_with("[global] A=2"));
+    assert!(lines[2].starts_with("gen=4 ") && lines[2].ends_with("[global] A (unset)"));
+    assert!(lines[3].ends_with("A=dir"));
+
+    run_envctl(&tmp, &["history", "A", "--dir", proj.to_str().unwrap()])
+        .success()
+        .stdout(predicate::str::contains("A=dir").and(predicate::str::contains("A=1").not()));
+
+    // Roll everything back to generation 2: A=2 again, B gone, dir overlay gone
+    run_envctl(&tmp, &["rollback", "--to-gen", "2"])
+        .success()
+        .stdout(predicate::str::contains(
+            "rolled back 3 key(s) to generation 2",
+        ));
+    run_envctl(&tmp, &["get", "A"]).success().stdout("2\n");
+    run_envctl(&tmp, &["get", "B"]).code(3).stdout("");
+
+    // Restored values propagate as new changes through export_since
+    run_envctl(&tmp, &["export", "bash", "--since", "5"])
+        .success()
+        .stdout(predicate::str::contains("export A='2'"))
+        .stdout(predicate::str::contains("unset -v B"));
+
+    // Per-key rollback leaves other keys alone
+    run_envctl(&tmp, &["rollback", "A", "--to-gen", "1"]).success();
+    run_envctl(&tmp, &["get", "A"]).success().stdout("1\n");
+    run_envctl(&tmp, &["rollback", "--to-gen", "999"])
+        .failure()
+        .stderr(predicate::str::contains("in the future"));
+
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[test]
+fn export_nu_emits_load_env_record() {
+    let tmp = TempDir::new().unwrap();
+    let mut child = start_envd_with_runtime(&tmp);
+
+    run_envctl(&tmp, &["set", "FOO=it's \"quoted\"\nline"]).success();
+    run_envctl(&tmp, &["set", "GONE=1"]).success();
+    run_envctl(&tmp, &["unset", "GONE"]).success();
+
+    let out = Command::cargo_bin("envctl")
+        .unwrap()
+        .env("XDG_RUNTIME_DIR", tmp.path())
+        .args(["export", "nu", "--since", "0"])
+        .output()
+        .unwrap();
+    assert!(out.status.success());
+    let record: serde_json::Value = serde_json::from_slice(&out.stdout).unwrap();
+    assert_eq!(record["set"]["FOO"], "it's \"quoted\"\nline");
+    assert_eq!(record["set"]["ENVCTL_GEN"], "3");
+    assert_eq!(record["unset"], serde_json::json!(["GONE"]));
+    assert_eq!(record["errors"], serde_json::json!([]));
+
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[test]
+fn install_hook_nu_targets_nushell_config() {
+    let tmp = TempDir::new().unwrap();
+    let home = tmp.path().join("home");
+    fs::create_dir_all(&home).unwrap();
+
+    let mut cmd = Command::cargo_bin("envctl").unwrap();
+    cmd.env("HOME", &home);
+    cmd.env_remove("XDG_CONFIG_HOME");
+    cmd.arg("install-hook").arg("nu");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("Installed envctl hook for nu"));
+
+    let contents = fs::read_to_string(home.join(".config/nushell/config.nu")).unwrap();
+    assert!(contents.contains("hooks.pre_execution"));
+    assert!(contents.contains("hooks.env_change.PWD"));
+    assert!(contents.contains("load-env"));
+    assert!(contents.contains("hide-env"));
+}
+
+#[test]
+#[ignore = "needs nu; CI installs it and runs ignored tests"]
+fn export_nu_round_trips_in_nu() {
+    require_shell("nu");
+    let tmp = TempDir::new().unwrap();
+    let mut child = start_envd_with_runtime(&tmp);
+    let tricky = "it's \"quoted\" $env.HOME (ls)\nline";
+    let record = export_tricky(&tmp, "nu", tricky);
+    let file = tmp.path().join("export.json");
+    fs::write(&file, record).unwrap();
+
+    let verify = format!(
+        "$env.GONE = 'still'; let diff = (open --raw '{}' | from json); $diff.set | load-env; hide-env --ignore-errors ...$diff.unset; print -n $\"($env.TRICKY)|($env.GONE? == null)\"",
+        file.display()
+    );
+    let ran = Command::new("nu")
+        .args(["--no-config-file", "-c"])
+        .arg(verify)
+        .output()
+        .unwrap();
+    assert!(ran.status.success(), "{:?}", ran);
+    assert_eq!(
+        String::from_utf8_lossy(&ran.stdout),
+        format!("{}|true", tricky)
+    );
+
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[test]
+#[ignore = "needs nu; CI installs it and runs ignored tests"]
+fn nu_hook_applies_values_at_start() {
+    require_shell("nu");
+    let tmp = TempDir::new().unwrap();
+    let home = tmp.path().join("home");
+    fs::create_dir_all(&home).unwrap();
+    let mut child = start_envd_with_runtime(&tmp);
+    run_envctl(&tmp, &["set", "FROM_HOOK=yes"]).success();
+    let hook = tmp.path().join("hook.nu");
+    let out = Command::cargo_bin("envctl")
+        .unwrap()
+        .args(["hook", "nu"])
+        .output()
+        .unwrap();
+    fs::write(&hook, out.stdout).unwrap();
+
+    let ran = Command::new("nu")
+        .env("HOME", &home)
+        .env("XDG_RUNTIME_DIR", tmp.path())
+        .env("PATH", path_with_envctl())
+        .args(["--no-config-file", "-c"])
+        .arg(format!(
+            "source '{}'; print -n $env.FROM_HOOK",
+            hook.display()
+        ))
+        .output()
+        .unwrap();
+    assert!(ran.status.success(), "{:?}", ran);
+    assert_eq!(String::from_utf8_lossy(&ran.stdout), "yes");
+
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[test]
+fn export_pwsh_quotes_values() {
+    let tmp = TempDir::new().unwrap();
+    let mut child = start_envd_with_runtime(&tmp);
+
+    let script = export_tricky(&tmp, "pwsh", PWSH_TRICKY);
+    assert!(script.contains(
+        "$env:TRICKY = 'it''s \u{2019}\u{2019}curly\u{2019}\u{2019} $HOME `tick`\nsecond'"
+    ));
+    assert!(script.contains("Remove-Item Env:GONE -ErrorAction SilentlyContinue"));
+    assert!(script.ends_with("$env:ENVCTL_GEN = '3'\n"));
+
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+const PWSH_TRICKY: &str = "it's \u{2019}curly\u{2019} $HOME `tick`\nsecond";
+
+#[test]
+#[ignore = "needs pwsh; CI installs it and runs ignored tests"]
+fn export_pwsh_round_trips_in_pwsh() {
+    require_shell("pwsh");
+    let tmp = TempDir::new().unwrap();
+    let mut child = start_envd_with_runtime(&tmp);
+
+    let script = export_tricky(&tmp, "pwsh", PWSH_TRICKY);
+    let verify = format!(
+        "$env:GONE = 'still'\n{}\n[Console]::Out.Write($env:TRICKY + '|' + ($null -eq $env:GONE))",
+        script
+    );
+    let ran = Command::new("pwsh")
+        .args(["-NoProfile", "-NonInteractive", "-Command", &verify])
+        .output()
+        .unwrap();
+    assert!(ran.status.success(), "{:?}", ran);
+    assert_eq!(
+        String::from_utf8_lossy(&ran.stdout),
+        format!("{}|True", PWSH_TRICKY)
+    );
+
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[test]
+fn install_hook_pwsh_wraps_prompt_in_profile() {
+    let tmp = TempDir::new().unwrap();
+    let home = tmp.path().join("home");
+    fs::create_dir_all(&home).unwrap();
+
+    let profile = install_pwsh_hook(&home);
+    let contents = fs::read_to_string(&profile).unwrap();
+    assert!(contents.contains("function global:prompt"));
+    assert!(contents.contains("envctl export pwsh"));
+}
+
+fn install_pwsh_hook(home: &Path) -> std::path::PathBuf {
+    let mut cmd = Command::cargo_bin("envctl").unwrap();
+    cmd.env("HOME", home);
+    cmd.env_remove("XDG_CONFIG_HOME");
+    cmd.arg("install-hook").arg("pwsh");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("Installed envctl hook for pwsh"));
+    home.join(".config/powershell/Microsoft.PowerShell_profile.ps1")
+}
+
+#[test]
+#[ignore = "needs pwsh; CI installs it and runs ignored tests"]
+fn pwsh_hook_applies_values_from_prompt() {
+    require_shell("pwsh");
+    let tmp = TempDir::new().unwrap();
+    let home = tmp.path().join("home");
+    fs::create_dir_all(&home).unwrap();
+    let profile = install_pwsh_hook(&home);
+
+    let mut envd = start_envd_with_runtime(&tmp);
+    run_envctl(&tmp, &["set", "FROM_HOOK=yes"]).success();
+    let ran = Command::new("pwsh")
+        .env("HOME", &home)
+        .env("XDG_RUNTIME_DIR", tmp.path())
+        .env("PATH", path_with_envctl())
+        .args([
+            "-NoProfile",
+            "-NonInteractive",
+            "-Command",
+            &format!(
+                ". '{}'; prompt | Out-Null; $env:FROM_HOOK",
+                profile.display()
+            ),
+        ])
+        .output()
+        .unwrap();
+    assert!(ran.status.success(), "{:?}", ran);
+    assert!(String::from_utf8_lossy(&ran.stdout).contains("yes"));
+
+    let _ = envd.kill();
+    let _ = envd.wait();
+}
+
+#[test]
+fn export_sh_runs_under_dash() {
+    let tmp = TempDir::new().unwrap();
+    let mut child = start_envd_with_runtime(&tmp);
+
+    let tricky = "it's \"quoted\" $HOME `tick`\nsecond";
+    run_envctl(&tmp, &["set", &format!("TRICKY={}", tricky)]).success();
+    run_envctl(&tmp, &["set", "GONE=1"]).success();
+    run_envctl(&tmp, &["unset", "GONE"]).success();
+
+    let out = Command::cargo_bin("envctl")
+        .unwrap()
+        .env("XDG_RUNTIME_DIR", tmp.path())
+        .args(["export", "sh", "--since", "0"])
+        .output()
+        .unwrap();
+    assert!(out.status.success());
+    let script = String::from_utf8_lossy(&out.stdout).to_string();
+    assert!(script.contains("unset GONE\n"));
+    assert!(!script.contains("unset -v"));
+
+    if !have_shell("dash") {
+        eprintln!("dash not installed; skipping dash evaluation");
+    } else {
+        let verify = format!(
+            "GONE=still\n{}\nprintf '%s|%s' \"$TRICKY\" \"${{GONE-unset}}\"",
+            script
+        );
+        let ran = Command::new("dash").arg("-c").arg(verify).output().unwrap();
+        assert!(ran.status.success());
+        assert_eq!(
+            String::from_utf8_lossy(&ran.stdout),
+            format!("{}|unset", tricky)
+        );
+    }
+
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[test]
+fn interactive_dash_picks_up_changes_via_env_hook() {
+    if !have_shell("dash") {
+        eprintln!("dash not installed; skipping");
+        return;
+    }
+    let tmp = TempDir::new().unwrap();
+    let mut child = start_envd_with_runtime(&tmp);
+
+    let home = tmp.path().join("home");
+    fs::create_dir_all(&home).unwrap();
+    let shrc = home.join(".shrc");
+    Command::cargo_bin("envctl")
+        .unwrap()
+        .env("HOME", &home)
+        .env("ENV", &shrc)
+        .args(["install-hook", "sh"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Add `export ENV").not());
+
+    let envctl_path = cargo_bin("envctl");
+    let envctl_dir = envctl_path.parent().expect("envctl dir");
+    let launcher = tmp.path().join("launch-dash.sh");
+    fs::write(
+        &launcher,
+        format!(
+            "#!/bin/sh\nexport HOME={home}\nexport ENV={shrc}\nexport XDG_RUNTIME_DIR={runtime}\nexport PATH={envctl_dir}:\"$PATH\"\nexport ENVCTL_GEN=0\nexec dash -i\n",
+            home = shell_escape(&home.to_string_lossy()),
+            shrc = shell_escape(&shrc.to_string_lossy()),
+            runtime = shell_escape(&tmp.path().to_string_lossy()),
+            envctl_dir = shell_escape(&envctl_dir.to_string_lossy()),
+        ),
+    )
+    .unwrap();
+    let mut perms = fs::metadata(&launcher).unwrap().permissions();
+    perms.set_mode(0o755);
+    fs::set_permissions(&launcher, perms).unwrap();
+
+    let mut p = spawn(launcher.to_string_lossy().as_ref()).unwrap();
+    p.send_line("printf '__READY__\\n'").unwrap();
+    p.expect("__READY__").unwrap();
+
+    run_envctl(&tmp, &["set", "DASHVAR=from_outside"]).success();
+
+    // The next prompt's PS1 expansion triggers the apply
+    p.send_line("").unwrap();
+    p.send_line("printf '__VAL__:%s\\n' \"${DASHVAR:-missing}\"")
+        .unwrap();
+    p.expect("__VAL__:from_outside").unwrap();
+    p.send_line("exit").unwrap();
+
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[test]
+fn export_tcsh_escapes_history_and_newlines() {
+    let tmp = TempDir::new().unwrap();
+    let mut child = start_envd_with_runtime(&tmp);
+
+    run_envctl(&tmp, &["set", "BROKEN", "--from-cmd", "exit 3"]).success();
+    let script = export_tricky(&tmp, "tcsh", TCSH_TRICKY);
+    assert!(script.contains("envctl: cannot resolve BROKEN"));
+    assert!(script.contains(" >>! /dev/stderr\n"), "{}", script);
+    assert!(script.contains("setenv TRICKY 'it'\\''s $HOME '\\!''\\!' `tick`\\\nsecond'\\!''\n"));
+    assert!(script.contains("unsetenv GONE\n"));
+    assert!(script.contains("setenv ENVCTL_GEN "));
+
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+const TCSH_TRICKY: &str = "it's $HOME !! `tick`\nsecond!";
+
+#[test]
+#[ignore = "needs tcsh; CI installs it and runs ignored tests"]
+fn export_tcsh_round_trips_in_tcsh() {
+    require_shell("tcsh");
+    let tmp = TempDir::new().unwrap();
+    let mut child = start_envd_with_runtime(&tmp);
+    run_envctl(&tmp, &["set", "BROKEN", "--from-cmd", "exit 3"]).success();
+
+    let script = export_tricky(&tmp, "tcsh", TCSH_TRICKY);
+    let file = tmp.path().join("export.tcsh");
+    fs::write(&file, &script).unwrap();
+    // noclobber must not stop the failure message from reaching stderr
+    let verify = format!(
+        "set noclobber; setenv GONE still; source {}; printf '%s|%s' \"$TRICKY\" \"$?GONE\"",
+        file.display()
+    );
+    let ran = Command::new("tcsh")
+        .args(["-f", "-c"])
+        .arg(verify)
+        .output()
+        .unwrap();
+    assert!(ran.status.success(), "{:?}", ran);
+    assert_eq!(
+        String::from_utf8_lossy(&ran.stdout),
+        format!("{}|0", TCSH_TRICKY)
+    );
+    let stderr = String::from_utf8_lossy(&ran.stderr);
+    assert!(
+        stderr.contains("envctl: cannot resolve BROKEN"),
+        "{}",
+        stderr
+    );
+
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[test]
+fn install_hook_tcsh_uses_precmd_alias() {
+    let tmp = TempDir::new().unwrap();
+    let home = tmp.path().join("home");
+    fs::create_dir_all(&home).unwrap();
+
+    let rc = fs::read_to_string(install_tcsh_hook(&home)).unwrap();
+    assert!(rc.contains("# >>> envctl hook >>>"));
+    assert!(rc.contains("alias precmd '__envctl_apply'"));
+    assert!(rc.contains("envctl export tcsh"));
+}
+
+fn install_tcsh_hook(home: &Path) -> std::path::PathBuf {
+    Command::cargo_bin("envctl")
+        .unwrap()
+        .env("HOME", home)
+        .args(["install-hook", "csh"])
+        .assert()
+        .success();
+    home.join(".tcshrc")
+}
+
+#[test]
+#[ignore = "needs tcsh; CI installs it and runs ignored tests"]
+fn tcsh_hook_chains_precmd_and_removes_temp_files() {
+    require_shell("tcsh");
+    let tmp = TempDir::new().unwrap();
+    let home = tmp.path().join("home");
+    fs::create_dir_all(&home).unwrap();
+    let rc = install_tcsh_hook(&home);
+
+    let mut envd = start_envd_with_runtime(&tmp);
+    run_envctl(&tmp, &["set", "FROM_HOOK=yes"]).success();
+    // An existing precmd alias with characters that break naive quoting
+    let script = tmp.path().join("start.tcsh");
+    fs::write(
+        &script,
+        format!(
+            "alias precmd 'echo \"prev ran!\"'\nsource {}\nprecmd\nprintf '%s\\n' \"$FROM_HOOK\"\n",
+            rc.display()
+        ),
+    )
+    .unwrap();
+    let ran = Command::new("tcsh")
+        .env("HOME", &home)
+        .env("XDG_RUNTIME_DIR", tmp.path())
+        .env("PATH", path_with_envctl())
+        .arg("-f")
+        .arg(&script)
+        .output()
+        .unwrap();
+    assert!(ran.status.success(), "{:?}", ran);
+    let stdout = String::from_utf8_lossy(&ran.stdout);
+    assert!(stdout.contains("prev ran!"), "{}", stdout);
+    assert!(stdout.ends_with("yes\n"), "{}", stdout);
+    // Every export's temp file is gone again
+    let leftovers: Vec<_> = fs::read_dir(tmp.path())
+        .unwrap()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_name().to_string_lossy().starts_with("envctl-tcsh."))
+        .collect();
+    assert!(leftovers.is_empty(), "{:?}", leftovers);
+
+    let _ = envd.kill();
+    let _ = envd.wait();
+}
+
+#[test]
+#[ignore = "needs tcsh; CI installs it and runs ignored tests"]
+fn tcsh_hook_skips_export_until_generation_or_cwd_changes() {
+    require_shell("tcsh");
+    let tmp = TempDir::new().unwrap();
+    let home = tmp.path().join("home");
+    fs::create_dir_all(home.join("sub")).unwrap();
+    let rc = install_tcsh_hook(&home);
+
+    // Logs every export the hook runs, then runs the real envctl
+    let shim = tmp.path().join("shim");
+    fs::create_dir_all(&shim).unwrap();
+    let log = tmp.path().join("exports");
+    fs::write(
+        shim.join("envctl"),
+        format!(
+            "#!/bin/sh\n[ \"$1\" = export ] && echo \"$*\" >> '{}'\nexec '{}' \"$@\"\n",
+            log.display(),
+            cargo_bin("envctl").display()
+        ),
+    )
+    .unwrap();
+    fs::set_permissions(shim.join("envctl"), fs::Permissions::from_mode(0o755)).unwrap();
+
+    let mut envd = start_envd_with_runtime(&tmp);
+    run_envctl(&tmp, &["set", "FROM_HOOK=one"]).success();
+    let script = tmp.path().join("start.tcsh");
+    fs::write(
+        &script,
+        format!(
+            "cd {home}\nsource {rc}\nprecmd\nprecmd\n{envctl} set FROM_HOOK=two\nprecmd\nprintf '%s\\n' \"$FROM_HOOK\"\ncd sub\nprecmd\nprecmd\n",
+            home = home.display(),
+            rc = rc.display(),
+            envctl = cargo_bin("envctl").display(),
+        ),
+    )
+    .unwrap();
+    let ran = Command::new("tcsh")
+        .env("HOME", &home)
+        .env("XDG_RUNTIME_DIR", tmp.path())
+        .env("PATH", format!("{}:{}", shim.display(), path_with_envctl()))
+        .arg("-f")
+        .arg(&script)
+        .output()
+        .unwrap();
+    assert!(ran.status.success(), "{:?}", ran);
+    assert_eq!(String::from_utf8_lossy(&ran.stdout), "two\n");
+    // At start, after the set, and after the cd; never for the idle prompts
+    let exports = fs::read_to_string(&log).unwrap();
+    assert_eq!(exports.lines().count(), 3, "{}", exports);
+
+    let _ = envd.kill();
+    let _ = envd.wait();
+}
+
+#[test]
+fn export_elvish_quotes_values() {
+    let tmp = TempDir::new().unwrap();
+    let mut child = start_envd_with_runtime(&tmp);
+
+    let script = export_tricky(&tmp, "elvish", ELVISH_TRICKY);
+    assert!(script.contains("set-env TRICKY 'it''s ''''$x'''' (put) \\\nnext'\n"));
+    assert!(script.contains("unset-env GONE\n"));
+    assert!(script.contains("set-env ENVCTL_GEN '"));
+
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+const ELVISH_TRICKY: &str = "it's ''$x'' (put) \\\nnext";
+
+#[test]
+#[ignore = "needs elvish; CI installs it and runs ignored tests"]
+fn export_elvish_round_trips_in_elvish() {
+    require_shell("elvish");
+    let tmp = TempDir::new().unwrap();
+    let mut child = start_envd_with_runtime(&tmp);
+
+    let script = export_tricky(&tmp, "elvish", ELVISH_TRICKY);
+    let file = tmp.path().join("export.elv");
+    fs::write(&file, &script).unwrap();
+    let verify = format!(
+        "set-env GONE still; eval (slurp < '{}'); print $E:TRICKY; if (has-env GONE) {{ print '|set' }} else {{ print '|unset' }}",
+        file.display().to_string().replace('\'', "''")
+    );
+    let ran = Command::new("elvish")
+        .args(["-norc", "-c"])
+        .arg(verify)
+        .output()
+        .unwrap();
+    assert!(ran.status.success(), "{:?}", ran);
+    assert_eq!(
+        String::from_utf8_lossy(&ran.stdout),
+        format!("{}|unset", ELVISH_TRICKY)
+    );
+
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[test]
+fn export_xonsh_emits_python_literals() {
+    let tmp = TempDir::new().unwrap();
+    let mut child = start_envd_with_runtime(&tmp);
+
+    let tricky = "it's \"q\" \\' $HOME @(x) \t\u{1b}[0m\nnext \u{e9}\\";
+    run_envctl(&tmp, &["set", &format!("TRICKY={}", tricky)]).success();
+    run_envctl(&tmp, &["set", "GONE=1"]).success();
+    run_envctl(&tmp, &["unset", "GONE"]).success();
+
+    let out = Command::cargo_bin("envctl")
+        .unwrap()
+        .env("XDG_RUNTIME_DIR", tmp.path())
+        .args(["export", "xonsh", "--since", "0"])
+        .output()
+        .unwrap();
+    assert!(out.status.success());
+    let script = String::from_utf8_lossy(&out.stdout).to_string();
+    assert!(script.contains("${...}.pop('GONE', None)\n"));
+    let line = script
+        .lines()
+        .find_map(|l| l.strip_prefix("$TRICKY = "))
+        .expect("TRICKY assignment on one line");
+    assert!(!line.contains('\u{1b}'));
+
+    if !have_shell("python3") {
+        eprintln!("python3 not installed; skipping literal check");
+    } else {
+        let ran = Command::new("python3")
+            .args([
+                "-c",
+                "import ast, sys; sys.stdout.write(ast.literal_eval(sys.argv[1]))",
+                line,
+            ])
+            .output()
+            .unwrap();
+        assert!(ran.status.success());
+        assert_eq!(String::from_utf8_lossy(&ran.stdout), tricky);
+    }
+
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+const XONSH_TRICKY: &str = "it's \"q\" \\' $HOME @(x) {y} \t\nnext \u{e9}\\";
+
+#[test]
+#[ignore = "needs xonsh; CI installs it and runs ignored tests"]
+fn export_xonsh_round_trips_in_xonsh() {
+    require_shell("xonsh");
+    let tmp = TempDir::new().unwrap();
+    let mut child = start_envd_with_runtime(&tmp);
+
+    let script = export_tricky(&tmp, "xonsh", XONSH_TRICKY);
+    let file = tmp.path().join("export.xsh");
+    fs::write(&file, &script).unwrap();
+    let verify = format!(
+        "$GONE = 'still'\nexecx(open({:?}).read())\nprint($TRICKY + '|' + str('GONE' in ${{...}}), end='')\n",
+        file.display().to_string()
+    );
+    let ran = Command::new("xonsh")
+        .args(["--no-rc", "-c"])
+        .arg(verify)
+        .output()
+        .unwrap();
+    assert!(ran.status.success(), "{:?}", ran);
+    assert_eq!(
+        String::from_utf8_lossy(&ran.stdout),
+        format!("{}|False", XONSH_TRICKY)
+    );
+
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[test]
+fn install_hook_elvish_and_xonsh_default_paths() {
+    let tmp = TempDir::new().unwrap();
+    let home = tmp.path().join("home");
+    fs::create_dir_all(&home).unwrap();
+
+    for shell in ["elvish", "xonsh"] {
+        let mut cmd = Command::cargo_bin("envctl").unwrap();
+        cmd.env("HOME", &home);
+        cmd.env_remove("XDG_CONFIG_HOME");
+        cmd.args(["install-hook", shell]).assert().success();
+    }
+
+    let rc = fs::read_to_string(home.join(".config/elvish/rc.elv")).unwrap();
+    assert!(rc.contains("edit:before-readline"));
+    assert!(rc.contains("envctl export elvish"));
+    let rc = fs::read_to_string(home.join(".xonshrc")).unwrap();
+    assert!(rc.contains("@events.on_pre_prompt"));
+    assert!(rc.contains("envctl export xonsh"));
+}
+
+#[test]
+fn generation_file_lets_bash_hook_skip_unchanged_exports() {
+    let tmp = TempDir::new().unwrap();
+    let mut child = start_envd_with_runtime(&tmp);
+
+    let gen_file = tmp.path().join("cmux-envd/generation");
+    assert_eq!(fs::read_to_string(&gen_file).unwrap(), "0\n");
+    run_envctl(&tmp, &["set", "FIRST=1"]).success();
+    assert_eq!(fs::read_to_string(&gen_file).unwrap(), "1\n");
+
+    // A shim envctl that logs every invocation before running the real one
+    let shim_dir = tmp.path().join("shim");
+    fs::create_dir_all(&shim_dir).unwrap();
+    let calls = tmp.path().join("calls.log");
+    let shim = shim_dir.join("envctl");
+    fs::write(
+        &shim,
+        format!(
+            "#!/bin/sh\necho \"$1\" >> {log}\nexec {real} \"$@\"\n",
+            log = shell_escape(&calls.to_string_lossy()),
+            real = shell_escape(&cargo_bin("envctl").to_string_lossy()),
+        ),
+    )
+    .unwrap();
+    let mut perms = fs::metadata(&shim).unwrap().permissions();
+    perms.set_mode(0o755);
+    fs::set_permissions(&shim, perms).unwrap();
+
+    let hook = Command::cargo_bin("envctl")
+        .unwrap()
+        .args(["hook", "bash"])
+        .output()
+        .unwrap();
+    let rc = tmp.path().join("bashrc");
+    fs::write(
+        &rc,
+        format!(
+            "export XDG_RUNTIME_DIR={runtime}\nexport PATH={shim_dir}:\"$PATH\"\n{hook}\n",
+            runtime = shell_escape(&tmp.path().to_string_lossy()),
+            shim_dir = shell_escape(&shim_dir.to_string_lossy()),
+            hook = String::from_utf8_lossy(&hook.stdout),
+        ),
+    )
+    .unwrap();
+
+    let exports = || {
+        fs::read_to_string(&calls)
+            .unwrap_or_default()
+            .lines()
+            .filter(|l| *l == "export")
+            .count()
+    };
+
+    let mut p = spawn(format!("bash --noprofile --rcfile {} -i", rc.display())).unwrap();
+    p.send_line("printf '__A__:%s\\n' \"$FIRST\"").unwrap();
+    p.expect("__A__:1").unwrap();
+    p.send_line("true; true; printf '__B__\\n'").unwrap();
+    p.expect("__B__").unwrap();
+    assert_eq!(exports(), 1, "unchanged generation should not export");
+
+    run_envctl(&tmp, &["set", "SECOND=2"]).success();
+    p.send_line("printf '__C__:%s\\n' \"$SECOND\"").unwrap();
+    p.expect("__C__:2").unwrap();
+    assert_eq!(exports(), 2);
+
+    p.send_line("cd / && printf '__D__\\n'").unwrap();
+    p.expect("__D__").unwrap();
+    p.send_line("printf '__E__\\n'").unwrap();
+    p.expect("__E__").unwrap();
+    assert_eq!(exports(), 3, "a changed $PWD should export once");
+    p.send_line("exit").unwrap();
+
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+// The parts of bash-preexec that matter for coexistence: a DEBUG trap that
+// fires preexec once per interactive command while skipping PROMPT_COMMAND
+// entries, and PROMPT_COMMAND wrapped so precmd sees the command's exit
+// status. Only a fallback for local runs: set BASH_PREEXEC to a real copy of
+// bash-preexec.sh to test against it, as CI does.
+const MINI_BASH_PREEXEC: &str = r#"__bp_interactive=
+__bp_precmd_invoke_cmd() {
+  __bp_last_ret_value=$?
+  local f
+  for f in "${precmd_functions[@]}"; do "$f"; done
+}
+__bp_interactive_mode() { __bp_interactive=on; }
+__bp_in_prompt_command() {
+  local cmd cmds IFS=$'\n;'
+  read -rd '' -a cmds <<< "${PROMPT_COMMAND[*]:-}"
+  for cmd in "${cmds[@]}"; do
+    [[ "$cmd" == "$1" ]] && return 0
+  done
+  return 1
+}
+__bp_preexec_invoke_exec() {
+  [[ -n "$__bp_interactive" ]] || return
+  __bp_interactive=
+  __bp_in_prompt_command "$BASH_COMMAND" && return
+  local f
+  for f in "${preexec_functions[@]}"; do "$f" "$BASH_COMMAND"; done
+}
+trap '__bp_preexec_invoke_exec "$_"' DEBUG
+PROMPT_COMMAND=$'__bp_precmd_invoke_cmd\n'"${PROMPT_COMMAND:-}"$'\n__bp_interactive_mode'
+"#;
+
+#[test]
+fn prompt_mode_bash_hook_coexists_with_bash_preexec() {
+    let tmp = TempDir::new().unwrap();
+    let mut child = start_envd_with_runtime(&tmp);
+
+    let preexec = match std::env::var("BASH_PREEXEC") {
+        Ok(path) if !path.is_empty() => fs::read_to_string(path).unwrap(),
+        _ => MINI_BASH_PREEXEC.to_string(),
+    };
+    let hook = Command::cargo_bin("envctl")
+        .unwrap()
+        .args(["hook", "bash", "--mode", "prompt"])
+        .output()
+        .unwrap();
+    assert!(hook.status.success());
+    let hook = String::from_utf8_lossy(&hook.stdout).to_string();
+    assert!(!hook.contains("trap '"));
+
+    let envctl_path = cargo_bin("envctl");
+    let envctl_dir = envctl_path.parent().expect("envctl parent dir");
+
+    // Both load orders: envctl after bash-preexec, and bash-preexec wrapping envctl
+    for (order, first, second) in [("after", &preexec, &hook), ("before", &hook, &preexec)] {
+        let log = tmp.path().join(format!("bp-{}.log", order));
+        let rc = tmp.path().join(format!("bashrc-{}", order));
+        fs::write(
+            &rc,
+            format!(
+                r#"export XDG_RUNTIME_DIR={runtime}
+export PATH={env_dir}:"$PATH"
+{first}
+{second}
+__log_preexec() {{ printf 'preexec:%s\n' "$1" >> {log}; }}
+__log_precmd() {{ printf 'precmd:%s\n' "$__bp_last_ret_value" >> {log}; }}
+preexec_functions+=(__log_preexec)
+precmd_functions+=(__log_precmd)
+"#,
+                runtime = shell_escape(&tmp.path().to_string_lossy()),
+                env_dir = shell_escape(&envctl_dir.to_string_lossy()),
+                log = shell_escape(&log.to_string_lossy()),
+            ),
+        )
+        .unwrap();
+
+        let mut p = spawn(format!("bash --noprofile --rcfile {} -i", rc.display())).unwrap();
+        p.send_line("printf '__READY__\\n'").unwrap();
+        p.expect("__READY__").unwrap();
+
+        run_envctl(&tmp, &["set", &format!("BP_{}=yes", order.to_uppercase())]).success();
+        // The prompt after this command applies the change
+        p.send_line("sh -c 'exit 3'").unwrap();
+        p.send_line(format!(
+            "printf '__VAL__:%s\\n' \"$BP_{}\"",
+            order.to_uppercase()
+        ))
+        .unwrap();
+        p.expect("__VAL__:yes").unwrap();
+        p.send_line("printf '__DONE__\\n'").unwrap();
+        p.expect("__DONE__").unwrap();
+        p.send_line("exit").unwrap();
+
+        let log = fs::read_to_string(&log).unwrap();
+        let preexecs: Vec<&str> = log.lines().filter(|l| l.starts_with("preexec:")).collect();
+        assert!(
+            preexecs.iter().all(|l| !l.contains("envctl")),
+            "preexec saw hook internals:\n{}",
+            log
+        );
+        assert_eq!(
+            preexecs
+                .iter()
+                .filter(|l| **l == "preexec:sh -c 'exit 3'")
+                .count(),
+            1,
+            "preexec should fire once per command:\n{}",
+            log
+        );
+        assert!(
+            log.contains("preexec:sh -c 'exit 3'\nprecmd:3\n"),
+            "precmd lost the exit status:\n{}",
+            log
+        );
+    }
+
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[test]
+fn prompt_mode_bash_hook_extends_prompt_command_array() {
+    let tmp = TempDir::new().unwrap();
+    let mut child = start_envd_with_runtime(&tmp);
+    run_envctl(&tmp, &["set", "ARRAYVAR=ok"]).success();
+
+    let hook = Command::cargo_bin("envctl")
+        .unwrap()
+        .args(["hook", "bash", "--mode", "prompt"])
+        .output()
+        .unwrap();
+    let envctl_path = cargo_bin("envctl");
+    let envctl_dir = envctl_path.parent().expect("envctl parent dir");
+    let rc = tmp.path().join("bashrc");
+    fs::write(
+        &rc,
+        format!(
+            "export XDG_RUNTIME_DIR={runtime}\nexport PATH={env_dir}:\"$PATH\"\nPROMPT_COMMAND=(__mine)\n__mine() {{ :; }}\n{hook}\n{hook}\n",
+            runtime = shell_escape(&tmp.path().to_string_lossy()),
+            env_dir = shell_escape(&envctl_dir.to_string_lossy()),
+            hook = String::from_utf8_lossy(&hook.stdout),
+        ),
+    )
+    .unwrap();
+
+    let mut p = spawn(format!("bash --noprofile --rcfile {} -i", rc.display())).unwrap();
+    p.send_line("printf '__PC__:%s\\n' \"${PROMPT_COMMAND[*]}|$ARRAYVAR\"")
+        .unwrap();
+    p.expect("__PC__:__envctl_apply __mine|ok").unwrap();
+    p.send_line("exit").unwrap();
+
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[test]
+fn prompt_mode_is_rejected_for_other_shells() {
+    Command::cargo_bin("envctl")
+        .unwrap()
+        .args(["hook", "zsh", "--mode", "prompt"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("only supported for bash"));
+}
+
+#[test]
+fn uninstall_hook_restores_rcfile_and_hook_status_tracks_it() {
+    let tmp = TempDir::new().unwrap();
+    let home = tmp.path().join("home");
+    fs::create_dir_all(&home).unwrap();
+    let bashrc = home.join(".bashrc");
+    let original = "alias ll='ls -l'\nexport EDITOR=vi\n";
+    fs::write(&bashrc, original).unwrap();
+
+    let envctl = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("envctl").unwrap();
+        cmd.env("HOME", &home);
+        cmd.env_remove("XDG_CONFIG_HOME");
+        cmd.env_remove("ENV");
+        cmd.args(args);
+        cmd.assert()
+    };
+
+    envctl(&["hook-status", "bash", "zsh"])
+        .success()
+        .stdout(predicate::str::is_match(r"(?m)^bash\s+missing\s").unwrap())
+        .stdout(predicate::str::is_match(r"(?m)^zsh\s+missing\s").unwrap());
+
+    envctl(&["install-hook", "bash", "--mode", "prompt"]).success();
+    envctl(&["install-hook", "zsh"]).success();
+    envctl(&["hook-status", "bash", "zsh"])
+        .success()
+        .stdout(predicate::str::is_match(r"(?m)^bash\s+installed\s").unwrap())
+        .stdout(predicate::str::is_match(r"(?m)^zsh\s+installed\s").unwrap());
+
+    // A block from an older envctl no longer matches the current hook
+    let zshrc = home.join(".zshrc");
+    let stale = fs::read_to_string(&zshrc)
+        .unwrap()
+        .replace("add-zsh-hook preexec", "add-zsh-hook precmd");
+    fs::write(&zshrc, stale).unwrap();
+    envctl(&["hook-status"])
+        .failure()
+        .stdout(predicate::str::is_match(r"(?m)^zsh\s+outdated\s").unwrap())
+        .stdout(predicate::str::is_match(r"(?m)^fish\s+missing\s").unwrap())
+        .stderr(predicate::str::contains("1 hook(s) need attention"));
+
+    envctl(&["uninstall-hook", "bash"])
+        .success()
+        .stdout(predicate::str::contains("Removed envctl hook for bash"));
+    assert_eq!(fs::read_to_string(&bashrc).unwrap(), original);
+    envctl(&["uninstall-hook", "bash"])
+        .success()
+        .stdout(predicate::str::contains("No envctl hook found"));
+
+    envctl(&["uninstall-hook", "zsh"]).success();
+    assert_eq!(fs::read_to_string(&zshrc).unwrap(), "");
+}
+
+#[test]
+fn uninstall_hook_refuses_block_without_end_marker() {
+    let tmp = TempDir::new().unwrap();
+    let rc = tmp.path().join("rc");
+    let broken = "before\n# >>> envctl hook >>>\nstuff\nafter\n";
+    fs::write(&rc, broken).unwrap();
+
+    Command::cargo_bin("envctl")
+        .unwrap()
+        .args(["uninstall-hook", "bash", "--rcfile"])
+        .arg(&rc)
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("without a matching"));
+    assert_eq!(fs::read_to_string(&rc).unwrap(), broken);
+
+    Command::cargo_bin("envctl")
+        .unwrap()
+        .args(["hook-status", "bash", "--rcfile"])
+        .arg(&rc)
+        .assert()
+        .failure()
+        .stdout(predicate::str::contains("malformed"));
+}
+
+#[test]
+fn install_hook_writes_through_symlinks_and_keeps_backup() {
+    let tmp = TempDir::new().unwrap();
+    let home = tmp.path().join("home");
+    let dotfiles = tmp.path().join("dotfiles");
+    fs::create_dir_all(&home).unwrap();
+    fs::create_dir_all(&dotfiles).unwrap();
+    let target = dotfiles.join("bashrc");
+    let original = "export EDITOR=vi\n";
+    fs::write(&target, original).unwrap();
+    fs::set_permissions(&target, fs::Permissions::from_mode(0o640)).unwrap();
+    std::os::unix::fs::symlink("../dotfiles/bashrc", home.join(".bashrc")).unwrap();
+
+    let install = || {
+        let mut cmd = Command::cargo_bin("envctl").unwrap();
+        cmd.env("HOME", &home);
+        cmd.args(["install-hook", "bash"]);
+        cmd.assert()
+    };
+    install()
+        .success()
+        .stdout(predicate::str::contains("Backed up"))
+        .stdout(predicate::str::contains("Installed envctl hook for bash"));
+
+    let link = fs::symlink_metadata(home.join(".bashrc")).unwrap();
+    assert!(link.file_type().is_symlink(), "symlink was replaced");
+    let contents = fs::read_to_string(&target).unwrap();
+    assert!(contents.starts_with(original));
+    assert!(contents.contains("# >>> envctl hook >>>"));
+    let mode = fs::metadata(&target).unwrap().permissions().mode() & 0o777;
+    assert_eq!(mode, 0o640);
+
+    let backups: Vec<_> = fs::read_dir(&dotfiles)
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .filter(|p| {
+            p.file_name()
+                .unwrap()
+                .to_string_lossy()
+                .starts_with("bashrc.envctl-backup-")
+        })
+        .collect();
+    assert_eq!(backups.len(), 1);
+    assert_eq!(fs::read_to_string(&backups[0]).unwrap(), original);
+    assert!(fs::read_dir(&dotfiles).unwrap().all(|e| !e
+        .unwrap()
+        .file_name()
+        .to_string_lossy()
+        .contains("envctl-tmp")));
+
+    // Nothing to change: no rewrite and no second backup
+    install()
+        .success()
+        .stdout(predicate::str::contains("already up to date"));
+    assert_eq!(fs::read_dir(&dotfiles).unwrap().count(), 2);
+}
+
+#[test]
+fn install_hook_dry_run_prints_diff_without_writing() {
+    let tmp = TempDir::new().unwrap();
+    let rc = tmp.path().join("rc");
+    fs::write(&rc, "export EDITOR=vi\n").unwrap();
+
+    Command::cargo_bin("envctl")
+        .unwrap()
+        .args(["install-hook", "bash", "--dry-run", "--rcfile"])
+        .arg(&rc)
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(format!("--- {}\n", rc.display())))
+        .stdout(predicate::str::contains("@@ -1 +1,"))
+        .stdout(predicate::str::contains(" export EDITOR=vi\n"))
+        .stdout(predicate::str::contains("+# >>> envctl hook >>>\n"))
+        .stdout(predicate::str::contains("+# <<< envctl hook <<<\n"));
+
+    assert_eq!(fs::read_to_string(&rc).unwrap(), "export EDITOR=vi\n");
+    assert_eq!(fs::read_dir(tmp.path()).unwrap().count(), 1);
+}
+
+#[test]
+fn install_hook_refuses_malformed_marker_block() {
+    let tmp = TempDir::new().unwrap();
+    let rc = tmp.path().join("rc");
+    let broken = "before\n# >>> envctl hook >>>\nold hook\nalias keep=me\n";
+    fs::write(&rc, broken).unwrap();
+
+    Command::cargo_bin("envctl")
+        .unwrap()
+        .args(["install-hook", "bash", "--rcfile"])
+        .arg(&rc)
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("without a matching"));
+    assert_eq!(fs::read_to_string(&rc).unwrap(), broken);
+}
+
+#[test]
+fn exec_runs_command_with_effective_environment() {
+    use std::os::unix::process::ExitStatusExt;
+
+    let tmp = TempDir::new().unwrap();
+    let mut child = start_envd_with_runtime(&tmp);
+    let proj = tmp.path().join("proj");
+    fs::create_dir_all(&proj).unwrap();
+
+    run_envctl(&tmp, &["set", "FOO=global"]).success();
+    run_envctl(
+        &tmp,
+        &["set", "BAR=line1\nline2", "--dir", proj.to_str().unwrap()],
+    )
+    .success();
+
+    let exec = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("envctl").unwrap();
+        cmd.env("XDG_RUNTIME_DIR", tmp.path());
+        cmd.env("KEEP", "kept");
+        cmd.env("XDG_CONFIG_HOME", tmp.path());
+        cmd.arg("exec").args(args);
+        cmd.output().unwrap()
+    };
+    let show = r#"printf '%s|%s|%s' "$FOO" "$BAR" "${KEEP-none}""#;
+
+    let out = exec(&["--pwd", proj.to_str().unwrap(), "--", "sh", "-c", show]);
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&out.stdout),
+        "global|line1\nline2|kept"
+    );
+
+    // Outside the project only the global value applies
+    let out = exec(&["--pwd", tmp.path().to_str().unwrap(), "sh", "-c", show]);
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "global||kept");
+
+    let out = exec(&[
+        "--clean",
+        "--pwd",
+        proj.to_str().unwrap(),
+        "--",
+        "/bin/sh",
+        "-c",
+        show,
+    ]);
+    assert_eq!(
+        String::from_utf8_lossy(&out.stdout),
+        "global|line1\nline2|none"
+    );
+
+    // A profile's values win over envd's
+    let profiles = tmp.path().join("cmux-envd/profiles");
+    fs::create_dir_all(&profiles).unwrap();
+    fs::write(profiles.join("ci.env"), "FOO=from-ci\nKEEP=ci\n").unwrap();
+    let out = exec(&[
+        "--profile",
+        "ci",
+        "--pwd",
+        proj.to_str().unwrap(),
+        "--",
+        "sh",
+        "-c",
+        show,
+    ]);
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&out.stdout),
+        "from-ci|line1\nline2|ci"
+    );
+    let out = exec(&["--profile", "nope", "--", "true"]);
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("reading profile"));
+    let out = exec(&["--profile", "../ci", "--", "true"]);
+    assert!(String::from_utf8_lossy(&out.stderr).contains("invalid profile name"));
+
+    let out = exec(&["--", "sh", "-c", "exit 7"]);
+    assert_eq!(out.status.code(), Some(7));
+
+    let out = exec(&["--", "sh", "-c", "kill -TERM $$"]);
+    assert_eq!(out.status.signal(), Some(libc::SIGTERM));
+
+    let out = exec(&["--", "envctl-test-no-such-command"]);
+    assert_eq!(out.status.code(), Some(127));
+    assert!(String::from_utf8_lossy(&out.stderr).contains("cannot run"));
+
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[test]
+fn list_get_status_and_scopes_output_formats() {
+    let tmp = TempDir::new().unwrap();
+    let mut child = start_envd_with_runtime(&tmp);
+    let proj = tmp.path().join("proj");
+    fs::create_dir_all(&proj).unwrap();
+    let proj_arg = proj.to_str().unwrap();
+
+    run_envctl(&tmp, &["set", "ZED=last"]).success();
+    run_envctl(&tmp, &["set", "ALPHA=first"]).success();
+    run_envctl(&tmp, &["set", "MULTI=it's \"two\"\nlines $HOME"]).success();
+    run_envctl(&tmp, &["set", "LOCAL=1", "--dir", proj_arg]).success();
+    let list = |args: &[&str]| {
+        let mut full = vec!["list", "--pwd", proj_arg];
+        full.extend_from_slice(args);
+        run_envctl(&tmp, &full)
+            .success()
+            .get_output()
+            .stdout
+            .clone()
+    };
+
+    assert_eq!(
+        String::from_utf8(list(&[])).unwrap(),
+        "ALPHA=first\nLOCAL=1\nMULTI=\"it's \\\"two\\\"\\nlines \\$HOME\"\nZED=last\n"
+    );
+    assert_eq!(
+        String::from_utf8(list(&["--format", "json"])).unwrap(),
+        "{\"ALPHA\":\"first\",\"LOCAL\":\"1\",\"MULTI\":\"it's \\\"two\\\"\\nlines $HOME\",\"ZED\":\"last\"}\n"
+    );
+    assert_eq!(
+        list(&["-0"]),
+        b"ALPHA=first\0LOCAL=1\0MULTI=it's \"two\"\nlines $HOME\0ZED=last\0".to_vec()
+    );
+    let table = String::from_utf8(list(&["--format", "table"])).unwrap();
+    assert!(table.starts_with("KEY    VALUE\nALPHA  first\n"));
+    assert!(table.contains("MULTI  it's \"two\"\\nlines $HOME\n"));
+
+    // Shell output evaluates back to the same values
+    let shell = String::from_utf8(list(&["--format", "shell"])).unwrap();
+    let ran = Command::new("sh")
+        .arg("-c")
+        .arg(format!("{}\nprintf '%s' \"$MULTI\"", shell))
+        .output()
+        .unwrap();
+    assert_eq!(
+        String::from_utf8_lossy(&ran.stdout),
+        "it's \"two\"\nlines $HOME"
+    );
+
+    run_envctl(&tmp, &["get", "ALPHA"])
+        .success()
+        .stdout("first\n");
+    run_envctl(&tmp, &["get", "ALPHA", "-0"])
+        .success()
+        .stdout("first\0");
+    run_envctl(&tmp, &["get", "ALPHA", "--format", "json"])
+        .success()
+        .stdout("\"first\"\n");
+    run_envctl(&tmp, &["get", "ALPHA", "--format", "shell"])
+        .success()
+        .stdout("export ALPHA='first'\n");
+
+    // Missing keys and daemon-side failures exit differently
+    run_envctl(&tmp, &["get", "NOPE"]).code(3).stdout("");
+    run_envctl(&tmp, &["set", "BROKEN", "--from-cmd", "exit 1"]).success();
+    run_envctl(&tmp, &["get", "BROKEN"]).code(1);
+
+    let status = run_envctl(&tmp, &["status", "--format", "json"])
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let status: serde_json::Value = serde_json::from_slice(&status).unwrap();
+    assert_eq!(status["globals"], 4);
+    assert_eq!(status["scopes"], 1);
+    assert_eq!(status["locked"], false);
+    run_envctl(&tmp, &["status", "--format", "dotenv"])
+        .success()
+        .stdout(predicate::str::contains("generation=5\nglobals=4\n"));
+
+    let canon_proj = proj.canonicalize().unwrap();
+    run_envctl(&tmp, &["scopes"]).success().stdout(format!(
+        "PATH{}  KEYS  SECRETS\n{}  1     0\n",
+        " ".repeat(canon_proj.to_str().unwrap().len() - 4),
+        canon_proj.display()
+    ));
+    run_envctl(&tmp, &["scopes", "--format", "json"])
+        .success()
+        .stdout(format!(
+            "[{{\"path\":\"{}\",\"keys\":1,\"secrets\":0}}]\n",
+            canon_proj.display()
+        ));
+    run_envctl(&tmp, &["scopes", "-0"])
+        .success()
+        .stdout(format!("{}\0", canon_proj.display()));
+    run_envctl(&tmp, &["scopes", "--format", "dotenv"])
+        .success()
+        .stdout(format!("path={} keys=1 secrets=0\n", canon_proj.display()));
+    let out = run_envctl(&tmp, &["scopes", "--format", "shell"])
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let read_back = Command::new("sh")
+        .arg("-c")
+        .arg(format!(
+            "{}\nprintf '%s|%s|%s' \"$path\" \"$keys\" \"$secrets\"",
+            String::from_utf8(out).unwrap()
+        ))
+        .output()
+        .unwrap();
+    assert_eq!(
+        String::from_utf8_lossy(&read_back.stdout),
+        format!("{}|1|0", canon_proj.display())
+    );
+
+    let _ = child.kill();
+    let _ = child.wait();
+
+    // Unknown formats are refused before envd is even started
+    let fresh = TempDir::new().unwrap();
+    run_envctl(&fresh, &["scopes", "--format", "yaml"])
+        .code(2)
+        .stderr(predicate::str::contains("invalid value 'yaml'"));
+    assert!(!fresh.path().join("cmux-envd/envd.sock").exists());
+}
+
+#[test]
+fn export_docker_env_and_compose_formats() {
+    let tmp = TempDir::new().unwrap();
+    let mut child = start_envd_with_runtime(&tmp);
+    let proj = tmp.path().join("proj");
+    fs::create_dir_all(&proj).unwrap();
+    let proj_arg = proj.to_str().unwrap();
+
+    run_envctl(&tmp, &["set", "PLAIN=a b \"c\" $d #e"]).success();
+    run_envctl(&tmp, &["set", "SCOPED=yes", "--dir", proj_arg]).success();
+    let export = |args: &[&str]| {
+        let mut full = vec!["export", "--pwd", proj_arg];
+        full.extend_from_slice(args);
+        run_envctl(&tmp, &full)
+    };
+
+    export(&["--format", "docker-env"])
+        .success()
+        .stdout("PLAIN=a b \"c\" $d #e\nSCOPED=yes\n");
+    export(&["--format", "compose"])
+        .success()
+        .stdout("environment:\n  \"PLAIN\": \"a b \\\"c\\\" $$d #e\"\n  \"SCOPED\": \"yes\"\n");
+
+    run_envctl(&tmp, &["set", "CERT=line1\nline2"]).success();
+    export(&["--format", "docker-env"])
+        .failure()
+        .stdout("")
+        .stderr(predicate::str::contains("CERT (contains a newline)"));
+    export(&["--format", "docker-env", "--skip-invalid"])
+        .success()
+        .stdout("PLAIN=a b \"c\" $d #e\nSCOPED=yes\n")
+        .stderr(predicate::str::contains("skipping CERT"));
+    export(&["--format", "compose"])
+        .success()
+        .stdout(predicate::str::contains("  \"CERT\": \"line1\\nline2\"\n"));
+
+    // The shell form still works, and one of SHELL/--format is required
+    export(&["bash"])
+        .success()
+        .stdout(predicate::str::contains("export SCOPED='yes'"));
+    export(&[]).failure();
+    export(&["bash", "--format", "compose"]).failure();
+
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[test]
+fn export_docker_env_and_compose_refuse_invalid_keys() {
+    let tmp = TempDir::new().unwrap();
+    fake_envd(&tmp, &[("BAD=KEY", "1"), ("OK", "2")]);
+
+    for format in ["docker-env", "compose"] {
+        run_envctl(&tmp, &["export", "--format", format])
+            .failure()
+            .stderr(predicate::str::contains(
+                "\"BAD=KEY\" (is not a valid variable name)",
+            ));
+    }
+    // Otherwise docker would read this as BAD with the value KEY=1
+    run_envctl(
+        &tmp,
+        &["export", "--format", "docker-env", "--skip-invalid"],
+    )
+    .success()
+    .stdout("OK=2\n");
+}
+
+#[test]
+fn export_systemd_format_and_sync_drop_in() {
+    let tmp = TempDir::new().unwrap();
+    let mut child = start_envd_with_runtime(&tmp);
+
+    run_envctl(&tmp, &["set", "GREETING=say \"hi\" to $USER `now`\\"]).success();
+    run_envctl(&tmp, &["set", "CERT=line1\nline2 100%"]).success();
+
+    run_envctl(&tmp, &["export", "--format", "systemd"])
+        .success()
+        .stdout(
+            "CERT=\"line1\nline2 100%\"\nGREETING=\"say \\\"hi\\\" to \\$USER \\`now\\`\\\\\"\n",
+        );
+
+    let config = tmp.path().join("config");
+    let mut cmd = Command::cargo_bin("envctl").unwrap();
+    cmd.env("XDG_RUNTIME_DIR", tmp.path());
+    cmd.env("XDG_CONFIG_HOME", &config);
+    cmd.args(["systemd", "sync", "api"]);
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains(
+            "Wrote 2 variable(s) for api.service",
+        ))
+        .stdout(predicate::str::contains("systemctl --user daemon-reload"));
+
+    let conf_path = config.join("systemd/user/api.service.d/envctl.conf");
+    let conf = fs::read_to_string(&conf_path).unwrap();
+    assert!(conf.contains("[Service]\n"));
+    let mode = fs::metadata(&conf_path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600, "drop-in may hold secrets: {:o}", mode);
+    assert!(conf.contains("Environment=\"CERT=line1\\nline2 100%%\"\n"));
+    assert!(conf.contains("Environment=\"GREETING=say \\\"hi\\\" to $USER `now`\\\\\"\n"));
+
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[test]
+fn export_systemd_and_sync_refuse_invalid_keys() {
+    let tmp = TempDir::new().unwrap();
+    fake_envd(&tmp, &[("A B", "1"), ("OK", "2")]);
+
+    run_envctl(&tmp, &["export", "--format", "systemd"])
+        .failure()
+        .stderr(predicate::str::contains(
+            "\"A B\" (is not a valid variable name)",
+        ));
+
+    let config = tmp.path().join("config");
+    let mut cmd = Command::cargo_bin("envctl").unwrap();
+    cmd.env("XDG_RUNTIME_DIR", tmp.path());
+    cmd.env("XDG_CONFIG_HOME", &config);
+    cmd.args(["systemd", "sync", "api"]);
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("invalid key \"A B\""));
+    assert!(!config.join("systemd/user/api.service.d").exists());
+}
+
+#[test]
+fn export_k8s_manifests_split_secrets_from_plain_values() {
+    let tmp = TempDir::new().unwrap();
+    let mut child = start_envd_with_runtime(&tmp);
+
+    run_envctl(&tmp, &["set", "LOG_LEVEL=debug"]).success();
+    run_envctl(&tmp, &["set", "MOTD=hello \"world\"\nbye"]).success();
+    run_envctl(&tmp, &["set", "DB_PASS=hunter2", "--secret"]).success();
+    // Provider values are usually credentials too
+    run_envctl(&tmp, &["set", "API_TOKEN", "--from-cmd", "printf tok"]).success();
+
+    run_envctl(
+        &tmp,
+        &["export", "--format", "k8s-configmap", "--name", "app-env"],
+    )
+    .success()
+    .stdout(
+        "apiVersion: v1\nkind: ConfigMap\nmetadata:\n  name: \"app-env\"\ndata:\n  \
+         \"LOG_LEVEL\": \"debug\"\n  \"MOTD\": \"hello \\\"world\\\"\\nbye\"\n",
+    )
+    .stderr(predicate::str::contains(
+        "leaving 2 secret or provider value(s) out",
+    ));
+
+    run_envctl(
+        &tmp,
+        &["export", "--format", "k8s-secret", "--name", "app-env"],
+    )
+    .success()
+    .stdout(
+        "apiVersion: v1\nkind: Secret\nmetadata:\n  name: \"app-env\"\ntype: Opaque\ndata:\n  \
+         \"API_TOKEN\": \"dG9r\"\n  \"DB_PASS\": \"aHVudGVyMg==\"\n",
+    );
+
+    run_envctl(&tmp, &["export", "--format", "k8s-secret"])
+        .failure()
+        .stderr(predicate::str::contains("--name is required"));
+    run_envctl(
+        &tmp,
+        &["export", "--format", "k8s-configmap", "--name", "App_Env"],
+    )
+    .failure()
+    .stderr(predicate::str::contains("invalid name"));
+
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[test]
+fn export_ci_formats_round_trip_multi_line_values() {
+    let tmp = TempDir::new().unwrap();
+    let mut child = start_envd_with_runtime(&tmp);
+
+    let multi_line_val = "hellowrold\nthisissecondline\nEOF\nyes 'yes' $yes";
+    run_envctl(
+        &tmp,
+        &["set", &format!("MULTI_LINE_THING={}", multi_line_val)],
+    )
+    .success();
+    run_envctl(&tmp, &["set", "PLAIN=a=b c"]).success();
+
+    // Parse $GITHUB_ENV the way the Actions runner does
+    let out = run_envctl(&tmp, &["export", "--format", "github"])
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let github = String::from_utf8(out).unwrap();
+    let mut parsed = Vec::new();
+    let mut lines = github.lines();
+    while let Some(line) = lines.next() {
+        let (key, delim) = line.split_once("<<").expect("heredoc header");
+        assert!(delim.starts_with("ghadelimiter_"), "{}", delim);
+        let mut value = Vec::new();
+        for line in lines.by_ref() {
+            if line == delim {
+                break;
+            }
+            value.push(line);
+        }
+        parsed.push((key.to_string(), value.join("\n")));
+    }
+    assert_eq!(
+        parsed,
+        vec![
+            ("MULTI_LINE_THING".to_string(), multi_line_val.to_string()),
+            ("PLAIN".to_string(), "a=b c".to_string()),
+        ]
+    );
+    // Delimiters differ between runs
+    let again = run_envctl(&tmp, &["export", "--format", "github"])
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    assert_ne!(String::from_utf8(again).unwrap(), github);
+
+    // The Buildkite hook is sourced by bash
+    let out = run_envctl(&tmp, &["export", "--format", "buildkite"])
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let hook = String::from_utf8(out).unwrap();
+    assert!(hook.starts_with("#!/bin/bash\n"));
+    let verify = Command::new("bash")
+        .arg("-c")
+        .arg(format!(
+            "{}\nprintf '__START__%s__END__%s' \"$MULTI_LINE_THING\" \"$PLAIN\"",
+            hook
+        ))
+        .output()
+        .unwrap();
+    assert!(verify.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&verify.stdout),
+        format!("__START__{}__END__a=b c", multi_line_val)
+    );
+
+    // GitLab dotenv reports can't hold multi-line values
+    run_envctl(&tmp, &["export", "--format", "gitlab"])
+        .failure()
+        .stderr(predicate::str::contains(
+            "MULTI_LINE_THING (contains a newline)",
+        ));
+    run_envctl(&tmp, &["export", "--format", "gitlab", "--skip-invalid"])
+        .success()
+        .stdout("PLAIN=a=b c\n")
+        .stderr(predicate::str::contains("skipping MULTI_LINE_THING"));
+
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[test]
+fn export_ci_formats_refuse_invalid_keys() {
+    let tmp = TempDir::new().unwrap();
+    let marker = tmp.path().join("pwned");
+    let evil = format!("X=$(touch {})", marker.display());
+    fake_envd(&tmp, &[(&evil, "1"), ("OK", "2")]);
+
+    for format in ["github", "gitlab", "buildkite"] {
+        run_envctl(&tmp, &["export", "--format", format])
+            .failure()
+            .stderr(predicate::str::contains("is not a valid variable name"));
+    }
+    let out = run_envctl(&tmp, &["export", "--format", "buildkite", "--skip-invalid"])
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let hook = String::from_utf8(out).unwrap();
+    assert!(!hook.contains("touch"), "{}", hook);
+    let status = Command::new("bash").arg("-c").arg(&hook).status().unwrap();
+    assert!(status.success());
+    assert!(!marker.exists());
+}
+
+#[test]
+fn export_json_and_nul_report_structured_diff() {
+    let tmp = TempDir::new().unwrap();
+    let mut child = start_envd_with_runtime(&tmp);
+
+    run_envctl(&tmp, &["set", "KEEP=1"]).success();
+    run_envctl(&tmp, &["set", "GONE=x"]).success();
+    run_envctl(&tmp, &["set", "MULTI=a\nb 'c'"]).success();
+
+    let out = run_envctl(&tmp, &["export", "json", "--since", "0"])
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let diff: serde_json::Value = serde_json::from_slice(&out).unwrap();
+    assert_eq!(
+        diff,
+        serde_json::json!({
+            "set": { "GONE": "x", "KEEP": "1", "MULTI": "a\nb 'c'" },
+            "unset": [],
+            "errors": [],
+            "generation": 3,
+        })
+    );
+
+    run_envctl(&tmp, &["unset", "GONE"]).success();
+    let out = run_envctl(&tmp, &["export", "json", "--since", "3"])
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let diff: serde_json::Value = serde_json::from_slice(&out).unwrap();
+    assert_eq!(diff["set"], serde_json::json!({}));
+    assert_eq!(diff["unset"], serde_json::json!(["GONE"]));
+    assert_eq!(diff["generation"], 4);
+
+    run_envctl(&tmp, &["export", "-0", "--since", "2"])
+        .success()
+        .stdout("MULTI=a\nb 'c'\0GONE\0ENVCTL_GEN=4\0");
+
+    run_envctl(&tmp, &["export", "bash", "-0"]).failure();
+
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+// Cases the common dotenv implementations (Ruby dotenv, node dotenv,
+// python-dotenv) agree on, paired with the values they load.
+const DOTENV_CORPUS: &str = "\u{feff}# leading comment\r
+BASIC=basic\r
+AFTER_LINE=after_line
+
+   INDENTED=indented
+EMPTY=
+EMPTY_SINGLE=''
+EMPTY_DOUBLE=\"\"
+SPACED = spaced value  
+SINGLE_QUOTES='single_quotes'
+SINGLE_QUOTES_SPACED='    single quotes    '
+DOUBLE_QUOTES=\"double_quotes\"
+BACKTICKS=`back'ticks\"`
+EXPORTED=1
+export EXPORT_PREFIX=exported
+export\tEXPORT_TAB=tab
+EQUAL_SIGNS=equals==
+RETAIN_INNER_QUOTES={\"foo\": \"bar\"}
+RETAIN_INNER_QUOTES_AS_STRING='{\"foo\": \"bar\"}'
+INLINE_COMMENT=unquoted # inline comment
+INLINE_COMMENT_SINGLE='single # not a comment' # a comment
+INLINE_COMMENT_DOUBLE=\"double # not a comment\"# a comment
+HASH_IN_VALUE=http://example.com/#anchor
+ONLY_COMMENT= # nothing here
+ESCAPES=\"tab\\there\\nnew \\\"quoted\\\" \\\\ \\$HOME \\q\"
+SINGLE_NO_ESCAPES='tab\\there\\n'
+UNQUOTED_BACKSLASH=a\\nb
+MULTI_DOUBLE=\"first
+  second
+
+last\"
+MULTI_SINGLE='one
+two' # trailing
+TRAILING_SPACE_DOUBLE=\"  keep  \"   
+";
+
+#[test]
+fn load_parses_dotenv_conformance_corpus() {
+    let tmp = TempDir::new().unwrap();
+    let mut child = start_envd_with_runtime(&tmp);
+
+    let env_file = tmp.path().join("corpus.env");
+    fs::write(&env_file, DOTENV_CORPUS).unwrap();
+    run_envctl(&tmp, &["load", env_file.to_str().unwrap()]).success();
+
+    let out = run_envctl(&tmp, &["list", "--format", "json"])
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let loaded: serde_json::Value = serde_json::from_slice(&out).unwrap();
+    let expected = serde_json::json!({
+        "BASIC": "basic",
+        "AFTER_LINE": "after_line",
+        "INDENTED": "indented",
+        "EMPTY": "",
+        "EMPTY_SINGLE": "",
+        "EMPTY_DOUBLE": "",
+        "SPACED": "spaced value",
+        "SINGLE_QUOTES": "single_quotes",
+        "SINGLE_QUOTES_SPACED": "    single quotes    ",
+        "DOUBLE_QUOTES": "double_quotes",
+        "BACKTICKS": "back'ticks\"",
+        "EXPORTED": "1",
+        "EXPORT_PREFIX": "exported",
+        "EXPORT_TAB": "tab",
+        "EQUAL_SIGNS": "equals==",
+        "RETAIN_INNER_QUOTES": "{\"foo\": \"bar\"}",
+        "RETAIN_INNER_QUOTES_AS_STRING": "{\"foo\": \"bar\"}",
+        "INLINE_COMMENT": "unquoted",
+        "INLINE_COMMENT_SINGLE": "single # not a comment",
+        "INLINE_COMMENT_DOUBLE": "double # not a comment",
+        "HASH_IN_VALUE": "http://example.com/#anchor",
+        "ONLY_COMMENT": "",
+        "ESCAPES": "tab\there\nnew \"quoted\" \\ $HOME \\q",
+        "SINGLE_NO_ESCAPES": "tab\\there\\n",
+        "UNQUOTED_BACKSLASH": "a\\nb",
+        "MULTI_DOUBLE": "first\n  second\n\nlast",
+        "MULTI_SINGLE": "one\ntwo",
+        "TRAILING_SPACE_DOUBLE": "  keep  ",
+    });
+    assert_eq!(loaded, expected);
+
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[test]
+fn load_reports_dotenv_errors_with_line_and_column() {
+    let tmp = TempDir::new().unwrap();
+    let mut child = start_envd_with_runtime(&tmp);
+
+    let cases = [
+        (
+            "OK=1\n  9LIVES=x\n",
+            "line 2, column 3: invalid key \"9LIVES\"",
+        ),
+        (
+            "OK=1\nNO_EQUALS\n",
+            "line 2, column 10: expected '=' after NO_EQUALS",
+        ),
+        (
+            "A=1\nB=\"never\nclosed\n",
+            "line 2, column 3: unterminated \" quote",
+        ),
+        (
+            "A='x' y\n",
+            "line 1, column 7: unexpected 'y' after closing quote",
+        ),
+        ("export A\n", "line 1, column 9: expected '=' after A"),
+    ];
+    for (input, message) in cases {
+        let mut load = assert_cmd::Command::cargo_bin("envctl").unwrap();
+        load.env("XDG_RUNTIME_DIR", tmp.path());
+        load.args(["load", "-"]);
+        load.write_stdin(input);
+        load.assert()
+            .failure()
+            .stderr(predicate::str::contains(message));
+    }
+    // Nothing from a file that failed to parse was stored
+    run_envctl(&tmp, &["get", "OK"]).code(3);
+
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[test]
+fn load_expand_resolves_references_in_order() {
+    let tmp = TempDir::new().unwrap();
+    let mut child = start_envd_with_runtime(&tmp);
+
+    run_envctl(&tmp, &["set", "DB_HOST=db.internal"]).success();
+
+    let input = r#"
+DB_PORT=5432
+DB_URL=postgres://${DB_HOST}:$DB_PORT/app
+DB_HOST=localhost
+LOCAL_URL="http://${DB_HOST}/\$literal \\$DB_PORT"
+RAW='${DB_HOST} stays'
+WITH_DEFAULT=${MISSING:-fallback-$DB_PORT}
+EMPTY=
+EMPTY_DEFAULT=${EMPTY:-was empty}
+EMPTY_DASH=${EMPTY-kept empty}
+FROM_ENV=${ENVCTL_TEST_FROM_ENV}
+"#;
+    let mut load = assert_cmd::Command::cargo_bin("envctl").unwrap();
+    load.env("XDG_RUNTIME_DIR", tmp.path());
+    load.env("ENVCTL_TEST_FROM_ENV", "from-process");
+    load.args(["load", "-", "--expand", "--expand-env"]);
+    load.write_stdin(input);
+    load.assert().success();
+
+    for (key, value) in [
+        // envd's value until the file defines its own
+        ("DB_URL", "postgres://db.internal:5432/app"),
+        ("LOCAL_URL", "http://localhost/$literal \\5432"),
+        ("RAW", "${DB_HOST} stays"),
+        ("WITH_DEFAULT", "fallback-5432"),
+        ("EMPTY_DEFAULT", "was empty"),
+        ("EMPTY_DASH", ""),
+        ("FROM_ENV", "from-process"),
+    ] {
+        run_envctl(&tmp, &["get", key])
+            .success()
+            .stdout(format!("{}\n", value));
+    }
+
+    // Without --expand-env the process environment is not consulted, and
+    // every unresolved reference is reported at once
+    let mut load = assert_cmd::Command::cargo_bin("envctl").unwrap();
+    load.env("XDG_RUNTIME_DIR", tmp.path());
+    load.env("ENVCTL_TEST_FROM_ENV", "from-process");
+    load.args(["load", "-", "--expand"]);
+    load.write_stdin("A=$ENVCTL_TEST_FROM_ENV\nB=${NOPE}\nC=${REQUIRED:?set REQUIRED first}\n");
+    load.assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "line 3: REQUIRED: set REQUIRED first",
+        ))
+        .stderr(predicate::str::contains(
+            "unresolved references: ENVCTL_TEST_FROM_ENV (line 1), NOPE (line 2)",
+        ));
+    run_envctl(&tmp, &["get", "A"]).code(3);
+
+    // Without --expand, references are stored as written
+    let mut load = assert_cmd::Command::cargo_bin("envctl").unwrap();
+    load.env("XDG_RUNTIME_DIR", tmp.path());
+    load.args(["load", "-"]);
+    load.write_stdin("LITERAL=${DB_HOST}\n");
+    load.assert().success();
+    run_envctl(&tmp, &["get", "LITERAL"])
+        .success()
+        .stdout("${DB_HOST}\n");
+
+    // A secret only expands into a load that is stored encrypted too
+    run_envctl(&tmp, &["set", "DB_PASS=hunter2", "--secret"]).success();
+    let load_dsn = |extra: &[&str]| {
+        let mut load = assert_cmd::Command::cargo_bin("envctl").unwrap();
+        load.env("XDG_RUNTIME_DIR", tmp.path());
+        load.args(["load", "-", "--expand"]).args(extra);
+        load.write_stdin("DSN=app:${DB_PASS}@db\n");
+        load.assert()
+    };
+    load_dsn(&[]).failure().stderr(predicate::str::contains(
+        "DB_PASS is a secret; pass --secret to store values expanding it",
+    ));
+    run_envctl(&tmp, &["get", "DSN"]).code(3);
+    load_dsn(&["--secret"]).success();
+    run_envctl(&tmp, &["get", "DSN"])
+        .success()
+        .stdout("app:hunter2@db\n");
+    run_envctl(&tmp, &["status"])
+        .success()
+        .stdout(predicate::str::contains("secrets: 2"));
+
+    // So does a provider's output
+    run_envctl(&tmp, &["set", "DB_PASS", "--from-cmd", "printf fromcmd"]).success();
+    load_dsn(&[]).failure().stderr(predicate::str::contains(
+        "DB_PASS comes from a provider; pass --secret to store values expanding it",
+    ));
+    load_dsn(&["--secret"]).success();
+    run_envctl(&tmp, &["get", "DSN"])
+        .success()
+        .stdout("app:fromcmd@db\n");
+
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[test]
+fn autoload_env_files_require_allow_and_follow_changes() {
+    let tmp = TempDir::new().unwrap();
+    let mut child = start_envd_with_runtime(&tmp);
+    let proj = tmp.path().join("proj");
+    fs::create_dir_all(proj.join("sub")).unwrap();
+    let proj = proj.canonicalize().unwrap();
+    let sub = proj.join("sub");
+    let proj_s = proj.to_str().unwrap();
+    let sub_s = sub.to_str().unwrap();
+    fs::write(proj.join(".env"), "FOO=from-env\nBAR=overridden\n").unwrap();
+    fs::write(proj.join(".envrc"), "export BAR=from-envrc\n").unwrap();
+
+    let export = |pwd: &str| -> serde_json::Value {
+        let out = run_envctl(&tmp, &["export", "json", "--pwd", pwd, "--since", "0"])
+            .success()
+            .get_output()
+            .stdout
+            .clone();
+        serde_json::from_slice(&out).unwrap()
+    };
+
+    // Found from a subdirectory, but not loaded until allowed
+    let diff = export(sub_s);
+    assert_eq!(diff["set"], serde_json::json!({}));
+    let errors = diff["errors"].to_string();
+    assert!(
+        errors.contains(".env is not allowed; run `envctl allow"),
+        "{}",
+        errors
+    );
+    assert!(errors.contains(".envrc is not allowed"), "{}", errors);
+
+    run_envctl(&tmp, &["allow", proj_s])
+        .success()
+        .stdout(predicate::str::contains(format!(
+            "Allowed {}/.env\n",
+            proj_s
+        )))
+        .stdout(predicate::str::contains(format!(
+            "Allowed {}/.envrc\n",
+            proj_s
+        )));
+    let diff = export(sub_s);
+    assert_eq!(
+        diff["set"],
+        serde_json::json!({ "FOO": "from-env", "BAR": "from-envrc" })
+    );
+    assert_eq!(diff["errors"], serde_json::json!([]));
+    run_envctl(&tmp, &["get", "FOO", "--pwd", tmp.path().to_str().unwrap()]).code(3);
+
+    // An edit needs approval again; until then the file's keys are unloaded
+    let allowed_env = "FOO=from-env\nBAR=overridden\n";
+    fs::write(proj.join(".env"), "FOO=edited\nNEW=1\n").unwrap();
+    let diff = export(proj_s);
+    assert!(diff["errors"].to_string().contains(".env is not allowed"));
+    assert_eq!(diff["unset"], serde_json::json!(["FOO"]));
+    run_envctl(&tmp, &["get", "BAR", "--pwd", proj_s])
+        .success()
+        .stdout("from-envrc\n");
+
+    run_envctl(&tmp, &["allow", proj.join(".env").to_str().unwrap()])
+        .success()
+        .stdout(format!("Allowed {}/.env\n", proj_s));
+    run_envctl(&tmp, &["get", "FOO", "--pwd", proj_s])
+        .success()
+        .stdout("edited\n");
+    run_envctl(&tmp, &["get", "NEW", "--pwd", proj_s])
+        .success()
+        .stdout("1\n");
+
+    // Going back to a version allowed before reloads without asking
+    fs::write(proj.join(".env"), allowed_env).unwrap();
+    let diff = export(proj_s);
+    assert_eq!(diff["errors"], serde_json::json!([]));
+    assert_eq!(diff["unset"], serde_json::json!(["NEW"]));
+    run_envctl(&tmp, &["get", "FOO", "--pwd", proj_s])
+        .success()
+        .stdout("from-env\n");
+
+    // Denied files are unloaded and skipped quietly
+    run_envctl(&tmp, &["deny", proj_s])
+        .success()
+        .stdout(predicate::str::contains("Denied"));
+    run_envctl(&tmp, &["get", "FOO", "--pwd", proj_s]).code(3);
+    let diff = export(proj_s);
+    assert_eq!(diff["set"], serde_json::json!({}));
+    assert_eq!(diff["errors"], serde_json::json!([]));
+
+    // Decisions are kept across daemon restarts
+    let trust = fs::read_to_string(tmp.path().join("cmux-envd/trust.json")).unwrap();
+    assert!(trust.contains(&format!("{}/.envrc", proj_s)), "{}", trust);
+    let _ = child.kill();
+    let _ = child.wait();
+    let _ = fs::remove_file(tmp.path().join("cmux-envd/envd.sock"));
+    child = start_envd_with_runtime(&tmp);
+    let diff = export(proj_s);
+    assert_eq!(diff["set"], serde_json::json!({}));
+    assert_eq!(diff["errors"], serde_json::json!([]));
+
+    run_envctl(&tmp, &["allow", tmp.path().to_str().unwrap()])
+        .failure()
+        .stderr(predicate::str::contains("no .env or .envrc in"));
+
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+fn check_link_follows_file(envs: &[(&str, &str)]) {
+    let tmp = TempDir::new().unwrap();
+    let mut child = start_envd_with_env(&tmp, envs);
+    let proj = tmp.path().join("proj");
+    fs::create_dir_all(&proj).unwrap();
+    let proj = proj.canonicalize().unwrap();
+    let proj_s = proj.to_str().unwrap();
+    let file = proj.join("app.env");
+    let file_s = file.to_str().unwrap();
+    fs::write(&file, "A=1\nB=2\n").unwrap();
+    run_envctl(&tmp, &["set", "STALE=x", "--dir", proj_s]).success();
+
+    let generation = || -> u64 {
+        let out = run_envctl(&tmp, &["export", "json", "--pwd", proj_s])
+            .success()
+            .get_output()
+            .stdout
+            .clone();
+        let diff: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        diff["generation"].as_u64().unwrap()
+    };
+    let get = |key: &str| {
+        let out = run_envctl(&tmp, &["get", key, "--pwd", proj_s])
+            .get_output()
+            .stdout
+            .clone();
+        String::from_utf8(out).unwrap()
+    };
+    let wait_for = |key: &str, value: &str| {
+        let start = Instant::now();
+        while get(key) != format!("{}\n", value) {
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "{} never became {}",
+                key,
+                value
+            );
+            thread::sleep(Duration::from_millis(50));
+        }
+    };
+
+    // Linking replaces the scope's contents with the file's
+    run_envctl(&tmp, &["link", file_s, "--dir", proj_s])
+        .success()
+        .stdout(predicate::str::starts_with(format!(
+            "Linked {} to {} (3 change(s)",
+            file_s, proj_s
+        )));
+    assert_eq!(get("A"), "1\n");
+    assert_eq!(get("B"), "2\n");
+    run_envctl(&tmp, &["get", "STALE", "--pwd", proj_s]).code(3);
+
+    // An edit saved by rename arrives as one generation
+    let before = generation();
+    let staged = proj.join(".app.env.tmp");
+    fs::write(&staged, "A=10\nC=3\n").unwrap();
+    fs::rename(&staged, &file).unwrap();
+    wait_for("A", "10");
+    let since = before.to_string();
+    let out = run_envctl(
+        &tmp,
+        &["export", "json", "--pwd", proj_s, "--since", &since],
+    )
+    .success()
+    .get_output()
+    .stdout
+    .clone();
+    let diff: serde_json::Value = serde_json::from_slice(&out).unwrap();
+    assert_eq!(diff["set"], serde_json::json!({ "A": "10", "C": "3" }));
+    assert_eq!(diff["unset"], serde_json::json!(["B"]));
+    assert_eq!(diff["generation"], serde_json::json!(before + 1));
+    // Audited as the client that linked the file
+    let uid = unsafe { libc::getuid() };
+    run_envctl(&tmp, &["log", "C"])
+        .success()
+        .stdout(predicate::str::contains(" link C ["))
+        .stdout(predicate::str::contains(format!("uid={} ", uid)));
+
+    // A file that doesn't parse leaves the scope alone
+    fs::write(&file, "A=\"unterminated\n").unwrap();
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(generation(), before + 1);
+    fs::write(&file, "A=11\nC=3\n").unwrap();
+    wait_for("A", "11");
+
+    run_envctl(&tmp, &["unlink", "--dir", proj_s])
+        .success()
+        .stdout(format!("Unlinked {} from {}\n", file_s, proj_s));
+    fs::write(&file, "A=99\n").unwrap();
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(get("A"), "11\n");
+    assert_eq!(get("C"), "3\n");
+    run_envctl(&tmp, &["unlink", "--dir", proj_s])
+        .failure()
+        .stderr(predicate::str::contains("no file is linked to"));
+
+    run_envctl(
+        &tmp,
+        &[
+            "link",
+            proj.join("missing.env").to_str().unwrap(),
+            "--dir",
+            proj_s,
+        ],
+    )
+    .failure()
+    .stderr(predicate::str::contains("missing.env"));
+
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[test]
+fn link_applies_file_edits_as_single_generation() {
+    check_link_follows_file(&[]);
+}
+
+#[test]
+fn link_falls_back_to_polling() {
+    check_link_follows_file(&[("ENVD_LINK_POLL_MS", "50")]);
+}
+
+#[test]
+fn envrc_files_only_load_plain_export_lines() {
+    let tmp = TempDir::new().unwrap();
+    let mut child = start_envd_with_runtime(&tmp);
+    let proj = tmp.path().join("proj");
+    fs::create_dir_all(&proj).unwrap();
+    let proj = proj.canonicalize().unwrap();
+    let proj_s = proj.to_str().unwrap();
+    let envrc = proj.join(".envrc");
+    let export = || -> serde_json::Value {
+        let out = run_envctl(&tmp, &["export", "json", "--pwd", proj_s, "--since", "0"])
+            .success()
+            .get_output()
+            .stdout
+            .clone();
+        serde_json::from_slice(&out).unwrap()
+    };
+
+    fs::write(
+        &envrc,
+        "# comment\nexport A='x $y'\nexport B=plain/path:1\nexport C=\"two words\"\n",
+    )
+    .unwrap();
+    run_envctl(&tmp, &["allow", proj_s]).success();
+    let diff = export();
+    assert_eq!(diff["errors"], serde_json::json!([]));
+    assert_eq!(
+        diff["set"],
+        serde_json::json!({ "A": "x $y", "B": "plain/path:1", "C": "two words" })
+    );
+
+    // Lines direnv would run or expand differently are refused
+    for (contents, error) in [
+        ("use nix\n", "only `export KEY=VALUE` lines are supported"),
+        ("A=1\n", "only `export KEY=VALUE` lines are supported"),
+        ("export A=\"$HOME\"\n", "are not supported in .envrc values"),
+        ("export A=\"a\\nb\"\n", "are not supported in .envrc values"),
+        ("export A=$(id)\n", "quote values containing spaces"),
+        ("export A=`id`\n", "command substitution is not supported"),
+        ("export A= b\n", "no spaces allowed around '='"),
+    ] {
+        fs::write(&envrc, contents).unwrap();
+        run_envctl(&tmp, &["allow", proj_s]).success();
+        let diff = export();
+        let errors = diff["errors"].to_string();
+        assert!(errors.contains(error), "{}: {}", contents, errors);
+        assert_eq!(diff["set"], serde_json::json!({}), "{}", contents);
+    }
+
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[test]
+fn linked_directory_ignores_its_autoload_files() {
+    let tmp = TempDir::new().unwrap();
+    let mut child = start_envd_with_runtime(&tmp);
+    let proj = tmp.path().join("proj");
+    fs::create_dir_all(&proj).unwrap();
+    let proj = proj.canonicalize().unwrap();
+    let proj_s = proj.to_str().unwrap();
+    let linked = tmp.path().join("linked.env");
+    fs::write(proj.join(".env"), "A=env\nFROM_ENV=1\n").unwrap();
+    fs::write(&linked, "A=link\n").unwrap();
+    let get = |key: &str| {
+        let out = run_envctl(&tmp, &["get", key, "--pwd", proj_s])
+            .get_output()
+            .stdout
+            .clone();
+        String::from_utf8(out).unwrap()
+    };
+
+    run_envctl(&tmp, &["allow", proj_s]).success();
+    assert_eq!(get("A"), "env\n");
+    run_envctl(&tmp, &["link", linked.to_str().unwrap(), "--dir", proj_s]).success();
+    assert_eq!(get("A"), "link\n");
+    run_envctl(&tmp, &["get", "FROM_ENV", "--pwd", proj_s]).code(3);
+
+    // Neither reads nor edits to the autoload file take the scope back
+    fs::write(proj.join(".env"), "A=edited\n").unwrap();
+    thread::sleep(Duration::from_millis(500));
+    run_envctl(&tmp, &["export", "json", "--pwd", proj_s]).success();
+    assert_eq!(get("A"), "link\n");
+
+    // Once unlinked, the (re-allowed) autoload file applies again
+    run_envctl(&tmp, &["unlink", "--dir", proj_s]).success();
+    run_envctl(&tmp, &["allow", proj_s]).success();
+    assert_eq!(get("A"), "edited\n");
+
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[test]
+fn autoload_changes_are_checked_against_acl_and_audited() {
+    let tmp = TempDir::new().unwrap();
+    let free = tmp.path().join("free");
+    let prod = tmp.path().join("prod");
+    fs::create_dir_all(&free).unwrap();
+    fs::create_dir_all(&prod).unwrap();
+    let free = free.canonicalize().unwrap();
+    let prod = prod.canonicalize().unwrap();
+    fs::write(free.join(".env"), "FREE_X=1\n").unwrap();
+    fs::write(prod.join(".env"), "PROD_X=1\nOTHER=1\n").unwrap();
+
+    // Allowed before any ACL protects the keys
+    let mut child = start_envd_with_runtime(&tmp);
+    run_envctl(&tmp, &["allow", free.to_str().unwrap()]).success();
+    run_envctl(&tmp, &["allow", prod.to_str().unwrap()]).success();
+    let _ = child.kill();
+    let _ = child.wait();
+    let _ = fs::remove_file(tmp.path().join("cmux-envd/envd.sock"));
+
+    let uid = unsafe { libc::getuid() };
+    let config = format!(
+        r#"{{"acl": [{{"keys": "PROD_*", "writers": [{}]}}]}}"#,
+        uid + 4242
+    );
+    child = start_envd_with_config(&tmp, &config);
+    let export = |pwd: &Path| -> serde_json::Value {
+        let out = run_envctl(&tmp, &["export", "json", "--pwd", pwd.to_str().unwrap()])
+            .success()
+            .get_output()
+            .stdout
+            .clone();
+        serde_json::from_slice(&out).unwrap()
+    };
+
+    let diff = export(&free);
+    assert_eq!(diff["set"], serde_json::json!({ "FREE_X": "1" }));
+    run_envctl(&tmp, &["log", "FREE_X"])
+        .success()
+        .stdout(predicate::str::contains(" autoload FREE_X ["))
+        .stdout(predicate::str::contains(format!("uid={}", uid)));
+
+    // A file touching a protected key is not loaded at all
+    let diff = export(&prod);
+    assert_eq!(diff["set"], serde_json::json!({}));
+    let errors = diff["errors"].to_string();
+    assert!(
+        errors.contains(&format!(
+            "not loading {}: permission denied",
+            prod.display()
+        )),
+        "{}",
+        errors
+    );
+    run_envctl(&tmp, &["get", "OTHER", "--pwd", prod.to_str().unwrap()]).code(3);
+
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[test]
+fn autoload_applies_to_every_command_reading_a_directory() {
+    let tmp = TempDir::new().unwrap();
+    let names = ["get", "list", "exec", "format"];
+    let dirs: Vec<std::path::PathBuf> = names
+        .iter()
+        .map(|name| {
+            let dir = tmp.path().join(name);
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join(".env"), format!("FROM_ENV={}\n", name)).unwrap();
+            dir.canonicalize().unwrap()
+        })
+        .collect();
+
+    // Allowed, then forgotten by a restart so nothing is loaded yet
+    let mut child = start_envd_with_runtime(&tmp);
+    for dir in &dirs {
+        run_envctl(&tmp, &["allow", dir.to_str().unwrap()]).success();
+    }
+    let _ = child.kill();
+    let _ = child.wait();
+    let _ = fs::remove_file(tmp.path().join("cmux-envd/envd.sock"));
+    child = start_envd_with_runtime(&tmp);
+
+    let envctl_in = |dir: &Path, args: &[&str]| {
+        let mut cmd = Command::cargo_bin("envctl").unwrap();
+        cmd.env("XDG_RUNTIME_DIR", tmp.path())
+            .env("XDG_STATE_HOME", tmp.path())
+            .current_dir(dir)
+            .args(args);
+        cmd.assert()
+    };
+    envctl_in(&dirs[0], &["get", "FROM_ENV"])
+        .success()
+        .stdout("get\n");
+    envctl_in(&dirs[1], &["list"])
+        .success()
+        .stdout(predicate::str::contains("FROM_ENV=list\n"));
+    envctl_in(
+        &dirs[2],
+        &["exec", "--", "sh", "-c", "printf %s \"$FROM_ENV\""],
+    )
+    .success()
+    .stdout("exec");
+    envctl_in(&dirs[3], &["export", "--format", "docker-env"])
+        .success()
+        .stdout(predicate::str::contains("FROM_ENV=format\n"));
+
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[test]
+fn autoload_watchers_stop_once_the_files_are_gone() {
+    let tmp = TempDir::new().unwrap();
+    let mut child = start_envd_with_runtime(&tmp);
+    let proj = tmp.path().join("proj");
+    fs::create_dir_all(&proj).unwrap();
+    let proj = proj.canonicalize().unwrap();
+    fs::write(proj.join(".env"), "FOO=one\n").unwrap();
+
+    let threads = || {
+        fs::read_dir(format!("/proc/{}/task", child.id()))
+            .unwrap()
+            .count()
+    };
+    let wait_for = |what: &str, done: &dyn Fn() -> bool| {
+        let start = Instant::now();
+        while !done() {
+            assert!(start.elapsed() < Duration::from_secs(5), "{}", what);
+            thread::sleep(Duration::from_millis(50));
+        }
+    };
+    let idle = threads();
+    run_envctl(&tmp, &["allow", proj.to_str().unwrap()]).success();
+    run_envctl(&tmp, &["get", "FOO", "--pwd", proj.to_str().unwrap()])
+        .success()
+        .stdout("one\n");
+    wait_for("no watcher started", &|| threads() == idle + 1);
+
+    // Edits only bump the generation; the next read loads them
+    let generation = || fs::read_to_string(tmp.path().join("cmux-envd/generation")).unwrap();
+    let before = generation();
+    fs::remove_file(proj.join(".env")).unwrap();
+    wait_for("generation never moved", &|| generation() != before);
+    wait_for("watcher kept running", &|| threads() == idle);
+    run_envctl(&tmp, &["get", "FOO", "--pwd", proj.to_str().unwrap()]).code(3);
+
+    // A new file is found by the next read and watched again
+    fs::write(proj.join(".env"), "FOO=one\n").unwrap();
+    run_envctl(&tmp, &["get", "FOO", "--pwd", proj.to_str().unwrap()])
+        .success()
+        .stdout("one\n");
+    wait_for("no watcher restarted", &|| threads() == idle + 1);
+
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[test]
+fn bash_hook_reloads_edited_autoload_files_without_cd() {
+    let tmp = TempDir::new().unwrap();
+    let mut child = start_envd_with_runtime(&tmp);
+    let proj = tmp.path().join("proj");
+    fs::create_dir_all(&proj).unwrap();
+    let proj = proj.canonicalize().unwrap();
+    fs::write(proj.join(".env"), "FOO=one\n").unwrap();
+    run_envctl(&tmp, &["allow", proj.to_str().unwrap()]).success();
+
+    let hook = Command::cargo_bin("envctl")
+        .unwrap()
+        .args(["hook", "bash"])
+        .output()
+        .unwrap();
+    let rc = tmp.path().join("bashrc");
+    fs::write(
+        &rc,
+        format!(
+            "export XDG_RUNTIME_DIR={runtime}\nexport XDG_STATE_HOME={runtime}\nexport PATH={bin}:\"$PATH\"\ncd {proj}\n{hook}\n",
+            runtime = shell_escape(&tmp.path().to_string_lossy()),
+            bin = shell_escape(&cargo_bin("envctl").parent().unwrap().to_string_lossy()),
+            proj = shell_escape(&proj.to_string_lossy()),
+            hook = String::from_utf8_lossy(&hook.stdout),
+        ),
+    )
+    .unwrap();
+
+    let mut p = spawn(format!("bash --noprofile --rcfile {} -i", rc.display())).unwrap();
+    p.send_line("printf '__A__:%s\\n' \"$FOO\"").unwrap();
+    p.expect("__A__:one").unwrap();
+
+    // Same directory, so only the generation can tell the hook to export
+    let generation = || fs::read_to_string(tmp.path().join("cmux-envd/generation")).unwrap();
+    let before = generation();
+    fs::write(proj.join(".env"), "FOO=two\n").unwrap();
+    let start = Instant::now();
+    while generation() == before {
+        assert!(
+            start.elapsed() < Duration::from_secs(5),
+            "generation never moved"
+        );
+        thread::sleep(Duration::from_millis(50));
+    }
+    p.send_line("printf '__B__:%s\\n' \"${FOO-unset}\"")
+        .unwrap();
+    p.expect(".env is not allowed").unwrap();
+    p.expect("__B__:unset").unwrap();
+
+    run_envctl(&tmp, &["allow", proj.to_str().unwrap()]).success();
+    p.send_line("printf '__C__:%s\\n' \"$FOO\"").unwrap();
+    p.expect("__C__:two").unwrap();
+    p.send_line("exit").unwrap();
+
+    let _ = child.kill();
+    let _ = child.wait();
+}